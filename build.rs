@@ -1,4 +1,3 @@
-use tonic_build;
 
 fn main() {
     tonic_build::configure()
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use ipnet::IpNet;
+use rand::RngCore;
+use tonic::{Request, Response, Status};
+use wireguard_control::Key;
+use crate::api::proto;
+use crate::api::proto::{AdoptReply, AdoptRequest, ApplyReply, ApplyRequest, ApproveRouteReply, ApproveRouteRequest, CreateInviteReply, CreateInviteRequest, GetMemberRequest, GetPolicyReply, GetPolicyRequest, HistoryReply, HistoryRequest, ListInvitesReply, ListInvitesRequest, ListMembersReply, ListMembersRequest, ListRoutesReply, ListRoutesRequest, RemoveMemberReply, RemoveMemberRequest, RenameMemberReply, RenameMemberRequest, RevokeInviteReply, RevokeInviteRequest, RollbackReply, RollbackRequest, SetMemberDisabledReply, SetMemberDisabledRequest, SetPolicyReply, SetPolicyRequest, TestPolicyReply, TestPolicyRequest, UpdateMemberReply, UpdateMemberRequest};
+use crate::config::invite::InviteConfig;
+use crate::config::manifest::NetworkManifest;
+use crate::config::policy::PolicyConfig;
+use crate::config::wg::{Endpoint, HostEnum, InterfaceConfig};
+use crate::policy;
+use crate::state::{Invite, Member};
+use crate::store::Store;
+use crate::utils::token_eq;
+
+/// Name of the admin calling, put into the request extensions by `AdminGuard`.
+#[derive(Clone)]
+pub struct AdminName(pub String);
+
+/// Checks admin tokens before any admin request reaches `AdminServer`.
+/// Admins are configured on the server and are not members of the network.
+#[derive(Clone)]
+pub struct AdminGuard {
+    // name: token
+    admins: Arc<HashMap<String, String>>,
+}
+
+impl AdminGuard {
+    pub fn new(admins: HashMap<String, String>) -> Self {
+        AdminGuard { admins: Arc::new(admins) }
+    }
+}
+
+impl tonic::service::Interceptor for AdminGuard {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        let token = req.metadata().get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("missing admin token"))?;
+        // every token is compared, a match doesn't end the search early
+        let name = self.admins.iter()
+            .fold(None, |found, (name, t)| if token_eq(token, t) { Some(name.clone()) } else { found })
+            .ok_or_else(|| Status::permission_denied("invalid admin token"))?;
+        req.extensions_mut().insert(AdminName(name));
+        Ok(req)
+    }
+}
+
+pub struct AdminServer {
+    // server iface, invites join its network
+    pub iface_config: InterfaceConfig,
+    pub listen: SocketAddr,
+    // rpc sockets of the other servers of the network
+    pub servers: Vec<Endpoint>,
+    // pem certificate of the rpc service, handed to invited members
+    pub tls_cert: String,
+    pub store: Arc<Store>,
+}
+
+/// Invites are valid for a day unless asked otherwise.
+const DEFAULT_INVITE_TTL: u64 = 24 * 60 * 60;
+
+fn admin<T>(req: &Request<T>) -> Result<String, Status> {
+    req.extensions().get::<AdminName>()
+        .map(|a| a.0.clone())
+        .ok_or_else(|| Status::unauthenticated("admin service is not guarded"))
+}
+
+fn member_not_found(name: &str) -> Status {
+    Status::not_found(format!("no member {}", name))
+}
+
+fn parse_nets(nets: &[String]) -> Result<Vec<IpNet>, Status> {
+    nets.iter()
+        .map(|n| IpNet::from_str(n).map_err(|e| Status::invalid_argument(e.to_string())))
+        .collect()
+}
+
+impl AdminServer {
+    /// Where invited members reach the rpc service: the server's public address on the listen port.
+    fn server_socket(&self) -> Endpoint {
+        let host = match (&self.iface_config.external_endpoint, self.iface_config.internal_endpoint) {
+            (Some(e), _) => e.host.clone(),
+            (None, Some(e)) => HostEnum::Ip(e.ip()),
+            (None, None) => HostEnum::Ip(self.listen.ip()),
+        };
+        Endpoint { host, port: self.listen.port() }
+    }
+
+    /// All servers of the network, this one first.
+    pub fn servers(&self) -> Vec<Endpoint> {
+        let own = self.server_socket();
+        let mut servers = vec![own.clone()];
+        servers.extend(self.servers.iter().filter(|s| **s != own).cloned());
+        servers
+    }
+}
+
+#[tonic::async_trait]
+impl proto::admin_server::Admin for AdminServer {
+    async fn apply(&self, req: Request<ApplyRequest>) -> Result<Response<ApplyReply>, Status> {
+        let admin = admin(&req)?;
+        let req = req.into_inner();
+        let manifest = NetworkManifest::from_yaml_str(&req.manifest)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let server_addrs: Vec<IpAddr> = self.iface_config.addrs.iter().map(|a| a.addr()).collect();
+        let (plan, revision) = self.store.commit_revision(&admin, "apply manifest", |state| -> Result<_, Status> {
+            if let Some(revision) = req.revision {
+                if revision != state.revision {
+                    return Err(Status::failed_precondition(format!(
+                        "state changed since revision {}, now at {}", revision, state.revision)));
+                }
+            }
+            manifest.check_addrs(state, &server_addrs)?;
+            let plan = manifest.plan(state);
+            if !req.dry_run && !plan.is_empty() {
+                manifest.apply(state);
+                log::info!("Manifest applied by {admin}, {} changes", plan.len());
+            }
+            Ok(plan)
+        }).await?;
+        Ok(Response::new(ApplyReply { plan, revision }))
+    }
+
+    async fn history(&self, req: Request<HistoryRequest>) -> Result<Response<HistoryReply>, Status> {
+        admin(&req)?;
+        let revisions = self.store.history()?.into_iter()
+            .map(|r| proto::Revision {
+                revision: r.revision,
+                author: r.author,
+                timestamp: r.timestamp,
+                message: r.message,
+            })
+            .collect();
+        Ok(Response::new(HistoryReply { revisions }))
+    }
+
+    async fn rollback(&self, req: Request<RollbackRequest>) -> Result<Response<RollbackReply>, Status> {
+        let admin = admin(&req)?;
+        let revision = self.store.rollback(&admin, req.into_inner().revision).await?;
+        // the change reaches the members through their peer watches
+        Ok(Response::new(RollbackReply { revision }))
+    }
+
+    async fn list_members(&self, req: Request<ListMembersRequest>) -> Result<Response<ListMembersReply>, Status> {
+        admin(&req)?;
+        let state = self.store.read().await;
+        let members = state.members.iter().map(|(name, m)| m.to_proto_member(name)).collect();
+        Ok(Response::new(ListMembersReply { members }))
+    }
+
+    async fn get_member(&self, req: Request<GetMemberRequest>) -> Result<Response<proto::Member>, Status> {
+        admin(&req)?;
+        let name = req.into_inner().name;
+        let state = self.store.read().await;
+        let member = state.members.get(&name).ok_or_else(|| member_not_found(&name))?;
+        Ok(Response::new(member.to_proto_member(&name)))
+    }
+
+    async fn remove_member(&self, req: Request<RemoveMemberRequest>) -> Result<Response<RemoveMemberReply>, Status> {
+        let admin = admin(&req)?;
+        let name = req.into_inner().name;
+        self.store.commit(&admin, &format!("remove member {}", name), |state| -> Result<(), Status> {
+            state.remove_member(&name).ok_or_else(|| member_not_found(&name))?;
+            // rules naming the member stay and match nobody, dropping the last one
+            // would let everyone in; `wgnet admin policy set` cleans them up
+            Ok(())
+        }).await?;
+        Ok(Response::new(RemoveMemberReply {}))
+    }
+
+    async fn set_member_disabled(&self, req: Request<SetMemberDisabledRequest>) -> Result<Response<SetMemberDisabledReply>, Status> {
+        let admin = admin(&req)?;
+        let req = req.into_inner();
+        let message = format!("{} member {}", if req.disabled { "disable" } else { "enable" }, req.name);
+        self.store.commit(&admin, &message, |state| -> Result<(), Status> {
+            let member = state.members.get_mut(&req.name).ok_or_else(|| member_not_found(&req.name))?;
+            member.disabled = req.disabled;
+            Ok(())
+        }).await?;
+        Ok(Response::new(SetMemberDisabledReply {}))
+    }
+
+    async fn rename_member(&self, req: Request<RenameMemberRequest>) -> Result<Response<RenameMemberReply>, Status> {
+        let admin = admin(&req)?;
+        let req = req.into_inner();
+        let message = format!("rename member {} to {}", req.name, req.new_name);
+        self.store.commit(&admin, &message, |state| -> Result<(), Status> {
+            if req.new_name.is_empty() {
+                return Err(Status::invalid_argument("empty member name"));
+            }
+            if state.members.contains_key(&req.new_name) {
+                return Err(Status::already_exists(format!("member {} already exists", req.new_name)));
+            }
+            state.rename_member(&req.name, &req.new_name).ok_or_else(|| member_not_found(&req.name))?;
+            Ok(())
+        }).await?;
+        Ok(Response::new(RenameMemberReply {}))
+    }
+
+    async fn update_member(&self, req: Request<UpdateMemberRequest>) -> Result<Response<UpdateMemberReply>, Status> {
+        let admin = admin(&req)?;
+        let req = req.into_inner();
+        let allowed_ips = req.allowed_ips
+            .map(|ips| parse_nets(&ips.ips))
+            .transpose()?;
+        let keepalive = req.persistent_keepalive
+            .map(|k| u16::try_from(k).map_err(|_| Status::invalid_argument("persistent keepalive above 65535")))
+            .transpose()?;
+        let public_key = req.public_key.as_ref()
+            .map(|k| Key::from_base64(k).map(|k| k.to_base64()).map_err(|_| Status::invalid_argument("invalid public key")))
+            .transpose()?;
+        self.store.commit(&admin, &format!("update member {}", req.name), |state| -> Result<(), Status> {
+            let member = state.members.get_mut(&req.name).ok_or_else(|| member_not_found(&req.name))?;
+            if let Some(allowed_ips) = allowed_ips {
+                member.allowed_ips = allowed_ips;
+            }
+            match keepalive {
+                Some(0) => member.persistent_keepalive = None,
+                Some(keepalive) => member.persistent_keepalive = Some(keepalive),
+                None => {}
+            }
+            if let Some(public_key) = public_key {
+                if let Some(other) = state.find_by_public_key(&public_key).filter(|n| **n != req.name) {
+                    return Err(Status::already_exists(format!("key already used by member {other}")));
+                }
+                let member = state.members.get_mut(&req.name).unwrap();
+                // the lost key and the gossip signing key registered with it stop working
+                member.public_key = public_key;
+                member.key_created_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+                member.retired_key = None;
+                member.signing_key = None;
+            }
+            Ok(())
+        }).await?;
+        Ok(Response::new(UpdateMemberReply {}))
+    }
+
+    async fn list_invites(&self, req: Request<ListInvitesRequest>) -> Result<Response<ListInvitesReply>, Status> {
+        admin(&req)?;
+        let state = self.store.read().await;
+        let invites = state.invites.iter().map(|(id, i)| i.to_proto_invite(id)).collect();
+        Ok(Response::new(ListInvitesReply { invites }))
+    }
+
+    async fn create_invite(&self, req: Request<CreateInviteRequest>) -> Result<Response<CreateInviteReply>, Status> {
+        let admin = admin(&req)?;
+        let req = req.into_inner();
+        if req.name.is_empty() {
+            return Err(Status::invalid_argument("empty member name"));
+        }
+        let addrs = parse_nets(&req.addrs)?;
+        let key = Key::generate_private();
+        let mut id = [0u8; 4];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id = id.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let ttl = if req.ttl == 0 { DEFAULT_INVITE_TTL } else { req.ttl };
+        let server_addrs: Vec<IpAddr> = self.iface_config.addrs.iter().map(|a| a.addr()).collect();
+
+        let message = format!("create invite {} for member {}", id, req.name);
+        let (invite, peers) = self.store.commit(&admin, &message, |state| -> Result<_, Status> {
+            if state.members.contains_key(&req.name) || state.invites.values().any(|i| i.name == req.name) {
+                return Err(Status::already_exists(format!("member {} already exists or is invited", req.name)));
+            }
+            let addrs = if addrs.is_empty() {
+                state.allocate(&self.iface_config.addrs, &server_addrs)
+                    .ok_or_else(|| Status::resource_exhausted("no free address left in the network"))?
+            } else {
+                if let Some(other) = state.addr_in_use(&addrs, &server_addrs) {
+                    return Err(Status::already_exists(format!("address already used by {other}")));
+                }
+                addrs
+            };
+            let invite = Invite {
+                name: req.name.clone(),
+                public_key: key.generate_public().to_base64(),
+                addrs,
+                created_at: now,
+                expires_at: now + ttl,
+                created_by: admin.clone(),
+            };
+            state.invites.insert(id.clone(), invite.clone());
+            Ok((invite, state.peers_of(&req.name)))
+        }).await?;
+
+        let code = InviteConfig {
+            iface_config: InterfaceConfig {
+                name: self.iface_config.name.clone(),
+                private_key: key.to_base64(),
+                addrs: invite.addrs.clone(),
+                listen_port: None,
+                mtu: self.iface_config.mtu,
+                internal_endpoint: None,
+                external_endpoint: None,
+                peers,
+                dns: self.iface_config.dns.clone(),
+                search: self.iface_config.search.clone(),
+            },
+            server_socket: self.server_socket(),
+            key: key.to_base64(),
+            servers: self.servers(),
+            tls_ca: self.tls_cert.clone(),
+        }.to_base64_json();
+        log::info!("Invite {id} for member {} created by {admin}", req.name);
+        Ok(Response::new(CreateInviteReply {
+            invite: Some(invite.to_proto_invite(&id)),
+            code,
+        }))
+    }
+
+    async fn revoke_invite(&self, req: Request<RevokeInviteRequest>) -> Result<Response<RevokeInviteReply>, Status> {
+        let admin = admin(&req)?;
+        let id = req.into_inner().id;
+        self.store.commit(&admin, &format!("revoke invite {}", id), |state| -> Result<(), Status> {
+            state.invites.remove(&id)
+                .map(|_| ())
+                .ok_or_else(|| Status::not_found(format!("no invite {}", id)))
+        }).await?;
+        Ok(Response::new(RevokeInviteReply {}))
+    }
+
+    async fn list_routes(&self, req: Request<ListRoutesRequest>) -> Result<Response<ListRoutesReply>, Status> {
+        admin(&req)?;
+        let state = self.store.read().await;
+        let mut routes = vec![];
+        for (name, m) in state.members.iter() {
+            for (prefixes, approved) in [(&m.allowed_ips, true), (&m.advertised_routes, false)] {
+                routes.extend(prefixes.iter().map(|p| proto::Route {
+                    member: name.clone(),
+                    prefix: p.to_string(),
+                    approved,
+                }));
+            }
+        }
+        Ok(Response::new(ListRoutesReply { routes }))
+    }
+
+    async fn approve_route(&self, req: Request<ApproveRouteRequest>) -> Result<Response<ApproveRouteReply>, Status> {
+        let admin = admin(&req)?;
+        let req = req.into_inner();
+        let prefix = IpNet::from_str(&req.prefix).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let message = format!("approve route {} of member {}", prefix, req.member);
+        self.store.commit(&admin, &message, |state| -> Result<(), Status> {
+            let member = state.members.get_mut(&req.member).ok_or_else(|| member_not_found(&req.member))?;
+            let i = member.advertised_routes.iter().position(|p| *p == prefix)
+                .ok_or_else(|| Status::not_found(format!("member {} does not advertise {}", req.member, prefix)))?;
+            member.advertised_routes.remove(i);
+            if !member.allowed_ips.contains(&prefix) {
+                member.allowed_ips.push(prefix);
+            }
+            Ok(())
+        }).await?;
+        Ok(Response::new(ApproveRouteReply {}))
+    }
+
+    async fn get_policy(&self, req: Request<GetPolicyRequest>) -> Result<Response<GetPolicyReply>, Status> {
+        admin(&req)?;
+        let policy = PolicyConfig { acls: self.store.read().await.acls.clone() };
+        Ok(Response::new(GetPolicyReply { policy: policy.to_yaml_string() }))
+    }
+
+    async fn set_policy(&self, req: Request<SetPolicyRequest>) -> Result<Response<SetPolicyReply>, Status> {
+        let admin = admin(&req)?;
+        let policy = PolicyConfig::from_yaml_str(&req.into_inner().policy)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let ((), revision) = self.store.commit_revision(&admin, "set policy", |state| -> Result<_, Status> {
+            state.acls = policy.acls;
+            Ok(())
+        }).await?;
+        Ok(Response::new(SetPolicyReply { revision }))
+    }
+
+    async fn test_policy(&self, req: Request<TestPolicyRequest>) -> Result<Response<TestPolicyReply>, Status> {
+        admin(&req)?;
+        let req = req.into_inner();
+        let state = self.store.read().await;
+        let (allowed, explanation) = policy::explain(&state, &req.from, &req.to)
+            .map_err(Status::not_found)?;
+        Ok(Response::new(TestPolicyReply { allowed, explanation }))
+    }
+
+    async fn adopt(&self, req: Request<AdoptRequest>) -> Result<Response<AdoptReply>, Status> {
+        let admin = admin(&req)?;
+        let req = req.into_inner();
+        let iface_config = req.iface_config
+            .ok_or_else(|| Status::invalid_argument("missing iface_config"))?;
+        let iface_config = InterfaceConfig::from_proto_config(&iface_config)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let public_key = Key::from_base64(&req.key)
+            .map_err(|_| Status::invalid_argument("invalid key"))?
+            .generate_public()
+            .to_base64();
+        let routes = parse_nets(&req.routes)?;
+        let server_addrs: Vec<IpAddr> = self.iface_config.addrs.iter().map(|a| a.addr()).collect();
+        let message = format!("adopt member {}", req.name);
+        let peers = self.store.commit(&admin, &message, |state| -> Result<_, Status> {
+            if state.members.contains_key(&req.name) {
+                return Err(Status::already_exists(format!("member {} already exists", req.name)));
+            }
+            if let Some(other) = state.find_by_public_key(&public_key) {
+                return Err(Status::already_exists(format!("key already used by member {other}")));
+            }
+            if let Some(other) = state.addr_in_use(&iface_config.addrs, &server_addrs) {
+                return Err(Status::already_exists(format!("address already used by {other}")));
+            }
+            // endpoints the adopted device already knows for existing members
+            for peer in iface_config.peers.values() {
+                let known = state.find_by_public_key(&peer.public_key).cloned();
+                if let (Some(known), Some(endpoint)) = (known, peer.endpoint.clone()) {
+                    let member = state.members.get_mut(&known).unwrap();
+                    if member.external_endpoint.is_none() {
+                        member.external_endpoint = Some(endpoint);
+                    }
+                }
+            }
+            state.members.insert(req.name.clone(), Member {
+                public_key,
+                key_created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                retired_key: None,
+                addrs: iface_config.addrs.clone(),
+                routes,
+                allowed_ips: vec![],
+                advertised_routes: vec![],
+                groups: vec![],
+                tags: vec![],
+                listen_port: iface_config.listen_port,
+                internal_endpoint: iface_config.internal_endpoint,
+                external_endpoint: iface_config.external_endpoint,
+                persistent_keepalive: None,
+                disabled: false,
+                exit: None,
+                services: vec![],
+                signing_key: None,
+            });
+            log::info!("Member {} adopted from interface {} by {admin}", req.name, iface_config.name);
+            Ok(state.peers_of(&req.name).iter()
+                .map(|(k, v)| (k.clone(), v.to_proto_peer().unwrap()))
+                .collect())
+        }).await?;
+        Ok(Response::new(AdoptReply {
+            peers,
+            servers: self.servers().iter().map(|s| s.to_string()).collect(),
+        }))
+    }
+}
@@ -1,4 +1,3 @@
-use tonic;
 
 pub mod proto {
     tonic::include_proto!("api");
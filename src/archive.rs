@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use chacha20poly1305::aead::{Aead, NewAead};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::config::server::ServerConfig;
+use crate::config::wg::InterfaceConfig;
+use crate::state::PairKey;
+use crate::store::{migrate, Store, SCHEMA_VERSION};
+use crate::utils::write_atomic;
+
+/// Version of the archive layout itself, the state inside follows `store::SCHEMA_VERSION`.
+pub const ARCHIVE_VERSION: u64 = 1;
+
+/// Everything needed to rebuild a server: state, settings and secrets.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Archive {
+    pub archive_version: u64,
+    pub schema_version: u64,
+    pub created_at: u64,
+    pub state: Value,
+    // admin and replication tokens are moved to `secrets`
+    pub settings: ServerConfig,
+    // private key is moved to `secrets`
+    pub iface: InterfaceConfig,
+    pub secrets: SecretsBox,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Secrets {
+    // name: token
+    pub admins: HashMap<String, String>,
+    pub iface_private_key: String,
+    // preshared keys of the member pairs, moved out of the state
+    #[serde(default)]
+    pub psks: BTreeMap<String, PairKey>,
+    #[serde(default)]
+    pub replication_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SecretsBox {
+    Plain { secrets: Secrets },
+    /// argon2id derived key, chacha20-poly1305 sealed json, all base64
+    Encrypted { salt: String, nonce: String, ciphertext: String },
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], io::Error> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(key)
+}
+
+impl SecretsBox {
+    pub fn seal(secrets: Secrets, passphrase: Option<&str>) -> Result<Self, io::Error> {
+        let passphrase = match passphrase {
+            Some(p) => p,
+            None => return Ok(SecretsBox::Plain { secrets }),
+        };
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt)?.into());
+        let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), serde_json::to_vec(&secrets)?.as_slice())
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(SecretsBox::Encrypted {
+            salt: base64::encode(salt),
+            nonce: base64::encode(nonce),
+            ciphertext: base64::encode(ciphertext),
+        })
+    }
+
+    pub fn open(&self, passphrase: Option<&str>) -> Result<Secrets, io::Error> {
+        match self {
+            SecretsBox::Plain { secrets } => Ok(secrets.clone()),
+            SecretsBox::Encrypted { salt, nonce, ciphertext } => {
+                let passphrase = passphrase
+                    .ok_or_else(|| invalid("archive secrets are encrypted, a passphrase is needed".to_string()))?;
+                let decode = |s: &String| base64::decode(s).map_err(|e| invalid(e.to_string()));
+                let nonce = decode(nonce)?;
+                if nonce.len() != 12 {
+                    return Err(invalid("invalid nonce".to_string()));
+                }
+                let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &decode(salt)?)?.into());
+                let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), decode(ciphertext)?.as_slice())
+                    .map_err(|_| invalid("wrong passphrase or corrupted archive".to_string()))?;
+                Ok(serde_json::from_slice(&plaintext)?)
+            }
+        }
+    }
+}
+
+/// Dump the server state and settings into a single archive file at `output`.
+pub async fn export(config: &ServerConfig, data: &Path, output: &Path, passphrase: Option<&str>) -> Result<(), io::Error> {
+    let store = Store::open(&config.store, data)?;
+    let mut state = store.read().await.clone();
+    let mut settings = config.clone();
+    let mut iface = InterfaceConfig::from_wg_quick_file(Path::new(&config.iface_config_path))?;
+    let secrets = Secrets {
+        admins: std::mem::take(&mut settings.admins),
+        iface_private_key: std::mem::take(&mut iface.private_key),
+        psks: std::mem::take(&mut state.psks),
+        replication_token: settings.replication_token.take(),
+    };
+    let state = serde_json::to_value(&state)?;
+    if passphrase.is_none() {
+        eprintln!("Warning: secrets are stored unencrypted in {}", output.display());
+    }
+    let archive = Archive {
+        archive_version: ARCHIVE_VERSION,
+        schema_version: SCHEMA_VERSION,
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        state,
+        settings,
+        iface,
+        secrets: SecretsBox::seal(secrets, passphrase)?,
+    };
+    write_atomic(output, serde_json::to_string_pretty(&archive)?.as_bytes(), 0o600)
+}
+
+/// Validate the archive at `input` and replace the server state with it.
+/// With `restore_settings`, the server config at `config_path` and its iface config are restored too.
+/// The server must not be running.
+pub async fn import(config_path: &Path, data: &Path, input: &Path, passphrase: Option<&str>, restore_settings: bool) -> Result<(), io::Error> {
+    let archive: Archive = serde_json::from_str(&fs::read_to_string(input)?)?;
+    if archive.archive_version != ARCHIVE_VERSION {
+        return Err(invalid(format!("unsupported archive version {}", archive.archive_version)));
+    }
+    // everything is checked before anything is written
+    let secrets = archive.secrets.open(passphrase)?;
+    let mut state = migrate(archive.schema_version, archive.state)?;
+    state.psks = secrets.psks;
+    state.validate()?;
+    let mut settings = archive.settings;
+    settings.admins = secrets.admins;
+    // archives made before it moved to the secrets keep it in the settings
+    settings.replication_token = secrets.replication_token.or(settings.replication_token.take());
+    let mut iface = archive.iface;
+    iface.private_key = secrets.iface_private_key;
+    wireguard_control::Key::from_base64(&iface.private_key)
+        .map_err(|_| invalid("invalid iface private key".to_string()))?;
+
+    let config = if restore_settings {
+        settings.clone()
+    } else {
+        ServerConfig::from_yaml_file(config_path)?
+    };
+    let store = Store::open(&config.store, data)?;
+    let members = state.members.len();
+    let message = format!("import {}", input.display());
+    store.commit("import", &message, move |current| -> Result<(), io::Error> {
+        let psks = state.psks.clone();
+        current.restore_config(state);
+        // unlike a rollback, the archive brings the keys of the pairs
+        current.psks = psks;
+        Ok(())
+    }).await?;
+    if restore_settings {
+        write_atomic(Path::new(&settings.iface_config_path), iface.to_wg_quick().as_bytes(), 0o600)?;
+        settings.to_yaml_file(config_path)?;
+    }
+    println!("Imported {} members from {}", members, input.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_secrets_box() {
+        let secrets = Secrets {
+            admins: HashMap::from([("monsoon".to_string(), "token".to_string())]),
+            iface_private_key: wireguard_control::Key::generate_private().to_base64(),
+            psks: BTreeMap::new(),
+            replication_token: Some("replication".to_string()),
+        };
+        let sealed = SecretsBox::seal(secrets, Some("passphrase")).unwrap();
+        let opened = sealed.open(Some("passphrase")).unwrap();
+        assert_eq!(opened.admins["monsoon"], "token");
+        assert_eq!(opened.replication_token.as_deref(), Some("replication"));
+        assert!(sealed.open(Some("wrong")).is_err());
+        assert!(sealed.open(None).is_err());
+    }
+}
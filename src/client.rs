@@ -1,144 +1,1114 @@
 use std::collections::HashMap;
+use std::fs;
 use std::io;
-use std::io::Error;
-use std::iter::Map;
-use std::ops::Mul;
-use std::path::PathBuf;
-use std::time::Duration;
-use log::log;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ipnet::IpNet;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio::time;
-use wireguard_control::Backend;
+use tokio::time::Instant;
+use tonic::transport::Channel;
+use wireguard_control::Key;
 use crate::wg::Interface;
 use crate::config::client::ClientConfig;
 use crate::config::invite::InviteConfig;
 use crate::api::proto;
-use crate::config::wg::InterfaceConfig;
+use crate::config::joined;
+use crate::config::joined::{JoinedNetwork, NetworkStatus};
+use crate::config::wg::{Endpoint, InterfaceConfig, PeerConfig};
+use crate::ctl::status_to_io;
+use crate::dns;
+use crate::gossip;
+use crate::gossip::{Gossip, GossipMessage};
+use crate::dns::Zone;
+use crate::resolver;
+use crate::hosts;
+use crate::state::Service;
+use crate::firewall::FirewallRule;
+use crate::tls;
+use crate::utils::{enable_ip_forwarding, format_timestamp, parse_backend};
 
 pub struct Client {
     config: ClientConfig,
     // name: iface
     ifaces: HashMap<String, Interface>,
-    rpc_client: proto::rpc_client::RpcClient<tonic::transport::Channel>,
+    // iface: servers of its network, the one in use first
+    servers: HashMap<String, Vec<Endpoint>>,
+    rpc_clients: HashMap<String, RpcClient>,
+    // iface: reachability of its server
+    health: HashMap<String, Health>,
     exiting: bool,
+    // iface: member names of its network, served by the DNS listeners
+    dns_zones: Arc<RwLock<HashMap<String, Zone>>>,
+    // iface: how the host resolver was set up for it
+    resolvers: HashMap<String, resolver::Method>,
+    // iface: endpoint records of its network
+    gossip: HashMap<String, Gossip>,
+    // iface: address its external endpoint resolved to when last posted
+    posted: HashMap<String, Option<SocketAddr>>,
+    // iface: pem certificate the servers of its network present
+    tls_cas: HashMap<String, String>,
+}
+
+pub type RpcClient = proto::rpc_client::RpcClient<Channel>;
+
+// longest wait between two tries to reach a server that is down
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// a server not connecting or answering within these counts as down
+const RPC_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RPC_TIMEOUT: Duration = Duration::from_secs(15);
+
+struct Health {
+    // failed tries in a row
+    failures: u32,
+    // when to ask the server for the peers next
+    next_poll: Instant,
+    status: NetworkStatus,
+}
+
+/// Wait before the next try after `failures` failed ones, doubling from 1s up to `MAX_BACKOFF`.
+/// Half of it is random, so the clients of a server coming back do not all retry at once.
+fn backoff(failures: u32) -> Duration {
+    let delay = Duration::from_secs(1 << failures.clamp(1, 16).saturating_sub(1)).min(MAX_BACKOFF);
+    delay / 2 + (delay / 2).mul_f64(rand::random::<f64>())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Client of the rpc service at `server`, connecting on first use. The timeout covers a
+/// request until its reply starts, streams go on for as long as the server sends.
+pub fn connect_lazy(server: &Endpoint, tls_ca: &str) -> Result<RpcClient, io::Error> {
+    Ok(RpcClient::new(connect_channel(server, tls_ca)?))
+}
+
+/// Channel to `server` presenting the certificate `tls_ca`, connected on first use.
+pub fn connect_channel(server: &Endpoint, tls_ca: &str) -> Result<Channel, io::Error> {
+    Ok(tonic::transport::Endpoint::from_shared(format!("https://{}", server))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .tls_config(tls::client_config(tls_ca))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .connect_timeout(RPC_CONNECT_TIMEOUT)
+        .timeout(RPC_TIMEOUT)
+        .connect_lazy())
 }
 
 impl Client {
+    /// Client resuming the networks saved in the iface config dir.
+    pub async fn new(config: ClientConfig) -> Result<Self, io::Error> {
+        let dir = PathBuf::from(&config.iface_config_dir);
+        fs::create_dir_all(&dir)?;
+        let mut client = Client {
+            config,
+            ifaces: HashMap::new(),
+            servers: HashMap::new(),
+            rpc_clients: HashMap::new(),
+            health: HashMap::new(),
+            exiting: false,
+            dns_zones: Arc::new(RwLock::new(HashMap::new())),
+            resolvers: HashMap::new(),
+            gossip: HashMap::new(),
+            posted: HashMap::new(),
+            tls_cas: HashMap::new(),
+        };
+        for network in JoinedNetwork::scan_dir(&dir)? {
+            log::info!("Resuming interface {} of server {}", network.iface_config.name, network.server_socket);
+            client.add_network(network).await?;
+        }
+        Ok(client)
+    }
+
+    async fn add_network(&mut self, network: JoinedNetwork) -> Result<(), io::Error> {
+        let name = network.iface_config.name.clone();
+        let mut iface = Interface::new(&network.iface_config, parse_backend(&self.config.backend));
+        iface.kill_switch = self.config.kill_switch;
+        if iface.kill_switch {
+            iface.host_resolvers = resolver::host_resolvers();
+        }
+        iface.adopted = network.adopted;
+        let mut servers = network.servers();
+        servers.retain(|s| *s != network.server_socket);
+        servers.insert(0, network.server_socket.clone());
+        iface.servers = resolve_all(&servers).await;
+        let tls_ca = network.tls_ca()?.to_string();
+        self.rpc_clients.insert(name.clone(), connect_lazy(&network.server_socket, &tls_ca)?);
+        self.tls_cas.insert(name.clone(), tls_ca);
+        self.servers.insert(name.clone(), servers);
+        self.ifaces.insert(name.clone(), iface);
+        let dir = Path::new(&self.config.iface_config_dir);
+        let status = NetworkStatus::from_json_file(&NetworkStatus::path(dir, &name)).unwrap_or(NetworkStatus {
+            degraded: false,
+            since: unix_now(),
+            last_sync: None,
+            last_error: None,
+        });
+        self.health.insert(name.clone(), Health {
+            failures: 0,
+            next_poll: Instant::now(),
+            status,
+        });
+        // networks joined before gossip get their key now, kept for the next start
+        let secret = network.signing_key.clone().unwrap_or_else(gossip::generate_signing_key);
+        // the name comes with the first peers of networks joined before it was saved
+        self.gossip.insert(name.clone(), Gossip::new(network.member.as_deref().unwrap_or_default(), &secret));
+        if network.signing_key.is_none() {
+            self.save_iface(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Client of the server of interface `name`.
+    fn rpc(&self, name: &str) -> RpcClient {
+        self.rpc_clients[name].clone()
+    }
+
+    /// Move interface `name` on to the next server of its network.
+    fn fail_over(&mut self, name: &str) -> Result<(), io::Error> {
+        let servers = self.servers.get_mut(name).unwrap();
+        servers.rotate_left(1);
+        log::info!("Interface {name}: failing over to server {}", servers[0]);
+        self.rpc_clients.insert(name.to_string(), connect_lazy(&servers[0], &self.tls_cas[name])?);
+        Ok(())
+    }
+
+    /// Take the server list sent for interface `name`, keeping the server in use first.
+    async fn set_servers(&mut self, name: &str, servers: &[String]) -> Result<(), io::Error> {
+        let mut servers = servers.iter()
+            .map(|s| s.parse::<Endpoint>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let current = &self.servers[name];
+        if servers.is_empty() || (servers.len() == current.len() && servers.iter().all(|s| current.contains(s))) {
+            return Ok(());
+        }
+        if let Some(i) = servers.iter().position(|s| *s == current[0]) {
+            servers[..=i].rotate_right(1);
+        }
+        log::info!("Interface {name}: servers {:?}", servers.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+        let resolved = resolve_all(&servers).await;
+        self.ifaces.get_mut(name).unwrap().set_servers(resolved)?;
+        if servers[0] != current[0] {
+            self.rpc_clients.insert(name.to_string(), connect_lazy(&servers[0], &self.tls_cas[name])?);
+        }
+        self.servers.insert(name.to_string(), servers);
+        self.save_iface(name)
+    }
+
     pub async fn run(&mut self) {
         self.exiting = false;
+        let mut shutdown = ShutdownSignal::new();
         for (name, iface) in self.ifaces.iter_mut() {
             log::info!("Interface {} upping ...", name);
-            match iface.up() {
+            match iface.up().await {
                 Ok(_) => log::info!("Interface {name} upped successfully"),
                 Err(e) => log::error!("Interface {name} upped failed: {e}"),
             }
         }
-        // update config and peers periodically
+        // the tunnels come up from the cached peers, the server may be down
+        let names: Vec<String> = self.ifaces.keys().cloned().collect();
+        for name in names.iter() {
+            match joined::load_peers(Path::new(&self.config.iface_config_dir), name) {
+                Ok(Some(reply)) => {
+                    log::info!("Interface {name}: {} cached peers", reply.peers.len());
+                    if let Err(e) = self.apply_peers(name, reply).await {
+                        log::error!("Interface {name} failed to apply the cached peers: {e}");
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("Interface {name} failed to read the cached peers: {e}"),
+            }
+        }
+        for name in names {
+            if let Err(e) = self.post_endpoint(&name).await {
+                log::error!("Interface {name} failed to post its endpoint: {e}");
+            }
+            if let Err(e) = self.advertise_routes(&name).await {
+                log::error!("Interface {name} failed to advertise routes: {e}");
+            }
+        }
+        let names: Vec<String> = self.ifaces.keys().cloned().collect();
+        for name in names {
+            if let Err(e) = self.publish_services(&name).await {
+                log::error!("Interface {name} failed to publish services: {e}");
+            }
+            if let Err(e) = self.register_signing_key(&name).await {
+                log::error!("Interface {name} failed to register its signing key: {e}");
+            }
+        }
+        let dns_servers = self.serve_dns();
+        let names: Vec<String> = self.ifaces.keys().cloned().collect();
+        for name in names {
+            if let Err(e) = self.configure_resolver(&name) {
+                log::error!("Interface {name} failed to configure the host resolver: {e}");
+            }
+        }
+        let (gossip_tx, mut gossip_rx) = mpsc::channel(64);
+        let (gossip_sockets, gossip_listeners) = self.start_gossip(gossip_tx).await;
+        let mut gossip_tick = time::interval(gossip::GOSSIP_INTERVAL);
+        // peers pushed by the server, polled periodically in case a watch broke
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut watches: HashMap<String, JoinHandle<()>> = HashMap::new();
         loop {
             if self.exiting {
                 log::info!("Exiting the client ...");
-                for (name, mut iface) in self.ifaces.iter() {
+                for (name, iface) in self.ifaces.iter_mut() {
                     log::debug!("Interface {name} downing ...");
                     match iface.down() {
                         Ok(_) => log::info!("Interface {name} is down"),
-                        Err(e) => log::error!("Interface {name} down failed"),
+                        Err(e) => log::error!("Interface {name} down failed: {e}"),
+                    }
+                }
+                for server in dns_servers.iter().chain(gossip_listeners.iter()) {
+                    server.abort();
+                }
+                if let Some(path) = &self.config.hosts_file {
+                    for name in self.ifaces.keys() {
+                        if let Err(e) = hosts::remove(Path::new(path), name) {
+                            log::error!("Interface {name} failed to clean {path}: {e}");
+                        }
+                    }
+                }
+                for (name, method) in self.resolvers.drain() {
+                    if let Err(e) = resolver::restore(&name, &method) {
+                        log::error!("Interface {name} failed to restore the host resolver: {e}");
                     }
                 }
                 return;
             }
             let names: Vec<String> = self.ifaces.keys().cloned().collect();
-            for name in names {
-                log::debug!("Interface {name} updating ...");
-                match self.update_peers(&name).await {
-                    Ok(_) => log::debug!("Interface {name} updated successfully"),
-                    Err(e) => log::error!("Interface {name} updated failed: {e}"),
-                };
+            for name in names.iter() {
+                // no watch while degraded, the polls back off instead
+                if !self.health[name].status.degraded && watches.get(name).is_none_or(|w| w.is_finished()) {
+                    watches.insert(name.clone(), self.watch_peers(name, tx.clone()));
+                }
+            }
+            let next_poll = self.health.values().map(|h| h.next_poll).min()
+                .unwrap_or_else(|| Instant::now() + Duration::from_secs(self.config.update_interval));
+            // announced preshared keys are switched to on time, without waiting for the server
+            let next_switch = self.ifaces.values().filter_map(|i| i.next_psk_switch()).min()
+                .map(|at| Instant::now() + Duration::from_secs(at.saturating_sub(unix_now())));
+            let mut rotate = vec![];
+            let mut recovered = vec![];
+            tokio::select! {
+                _ = shutdown.recv() => {
+                    self.exiting = true;
+                }
+                Some((name, reply)) = rx.recv() => {
+                    match self.sync_peers(&name, reply).await {
+                        Ok((due, back)) => {
+                            if due {
+                                rotate.push(name.clone());
+                            }
+                            if back {
+                                recovered.push(name);
+                            }
+                        }
+                        Err(e) => log::error!("Interface {name} updated failed: {e}"),
+                    }
+                }
+                Some((name, from, message)) = gossip_rx.recv() => {
+                    if let Err(e) = self.take_gossip(&name, from, message) {
+                        log::warn!("Interface {name} failed to take the gossip from {from}: {e}");
+                    }
+                }
+                _ = gossip_tick.tick() => {
+                    self.gossip_round(&gossip_sockets).await;
+                }
+                _ = time::sleep_until(next_switch.unwrap_or(next_poll)), if next_switch.is_some() => {
+                    let now = unix_now();
+                    for iface in self.ifaces.values_mut() {
+                        if let Err(e) = iface.switch_psks(now) {
+                            log::error!("Interface {} failed to switch preshared keys: {e}", iface.config.name);
+                        }
+                    }
+                }
+                _ = time::sleep_until(next_poll) => {
+                    for iface in self.ifaces.values_mut() {
+                        if let Err(e) = iface.refresh_endpoints().await {
+                            log::error!("Interface {} failed to refresh endpoints: {e}", iface.config.name);
+                        }
+                    }
+                    let now = Instant::now();
+                    for name in names {
+                        if self.health.get(&name).is_none_or(|h| h.next_poll > now) {
+                            continue;
+                        }
+                        log::debug!("Interface {name} updating ...");
+                        match self.update_peers(&name).await {
+                            Ok((due, back)) => {
+                                log::debug!("Interface {name} updated successfully");
+                                // also catches the external endpoint resolving to a new address
+                                if let Err(e) = self.post_endpoint(&name).await {
+                                    log::error!("Interface {name} failed to post its endpoint: {e}");
+                                }
+                                if due {
+                                    rotate.push(name.clone());
+                                }
+                                if back {
+                                    recovered.push(name);
+                                }
+                            }
+                            Err(e) => self.poll_failed(&name, &e),
+                        };
+                    }
+                }
             }
-            tokio::time::sleep(Duration::from_secs(self.config.update_interval)).await;
+            // what the server missed while it was down
+            for name in recovered {
+                if let Err(e) = self.advertise_routes(&name).await {
+                    log::error!("Interface {name} failed to advertise routes: {e}");
+                }
+                if let Err(e) = self.publish_services(&name).await {
+                    log::error!("Interface {name} failed to publish services: {e}");
+                }
+                if let Err(e) = self.register_signing_key(&name).await {
+                    log::error!("Interface {name} failed to register its signing key: {e}");
+                }
+            }
+            for name in rotate {
+                match self.rotate_key(&name).await {
+                    // the watch was opened with the old key
+                    Ok(_) => if let Some(w) = watches.remove(&name) {
+                        w.abort();
+                    },
+                    Err(e) => log::error!("Interface {name} key rotation failed: {e}"),
+                }
+            }
+            watches.retain(|name, w| {
+                if !self.ifaces.contains_key(name) {
+                    w.abort();
+                }
+                self.ifaces.contains_key(name)
+            });
         }
     }
 
+    /// Join the network of `invite`, saved so that later runs resume it.
     pub async fn redeem_invite(&mut self, invite: &InviteConfig) -> Result<(), io::Error> {
+        if invite.tls_ca.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the invite predates tls on the servers, ask for a new one"));
+        }
         // init iface
-        #[cfg(target_os = "linux")]
-            let backend = match self.config.backend.to_lowercase().as_str() {
-            "kernel" => Backend::Kernel,
-            "userspace" => Backend::Userspace,
-            _ => {
-                log::error!("Unknown backend \"{}\", use \"kernel\" by default", self.config.backend);
-                Backend::Kernel
-            }
-        };
-        #[cfg(not(target_os = "linux"))]
-            let backend = match self.config.backend.to_lowercase().as_str() {
-            "userspace" => Backend::Userspace,
-            "kernel" => {
-                log::error!("\"{}\" backend is not supported in current OS, using \"userspace\" instead", self.config.backend);
-                Backend::Userspace
-            }
-            _ => {
-                log::error!("Unknown backend \"{}\", use \"userspace\" by default", self.config.backend);
-                Backend::Userspace
-            }
-        };
+        let backend = parse_backend(&self.config.backend);
         // up the init iface
         let mut iface_init = Interface::new(&invite.iface_config, backend);
-        let name = iface_init.config.name.clone();
-        iface_init.up().unwrap();
+        iface_init.up().await?;
         // build rpc client
-        self.rpc_client = proto::rpc_client::RpcClient::connect(invite.server_socket.to_string()).await.unwrap();
-        // test rpc client
-        let req = proto::PingRequest {
-            msg: format!("I'm {}", invite.key),
-        };
-        let resp = self.rpc_client.ping(req).await.unwrap().into_inner();
-        log::debug!("Ping response: {}", resp.msg);
+        let servers = invite.servers();
+        let mut server = None;
+        for s in servers.iter() {
+            let mut rpc_client = connect_lazy(s, &invite.tls_ca)?;
+            // test rpc client
+            let req = proto::PingRequest {
+                msg: format!("I'm {}", invite.key),
+            };
+            match rpc_client.ping(req).await {
+                Ok(resp) => {
+                    log::debug!("Ping response: {}", resp.into_inner().msg);
+                    server = Some((s.clone(), rpc_client));
+                    break;
+                }
+                Err(e) => log::warn!("Server {s} unreachable: {}", e.message()),
+            }
+        }
+        let (server, mut rpc_client) = server.ok_or_else(|| io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "no server of the invite is reachable",
+        ))?;
         // redeem invite
         let req = proto::RedeemInviteRequest {
             key: invite.key.clone(),
         };
-        let resp = self.rpc_client.redeem_invite(req).await.unwrap().into_inner();
+        let resp = rpc_client.redeem_invite(req).await.map_err(status_to_io)?.into_inner();
         // down the init iface
-        iface_init.down().unwrap();
+        iface_init.down()?;
         // add real ifaces
         for r in resp.iface_config.iter() {
-            let iface_config = InterfaceConfig::from_proto_config(r).unwrap();
-            let iface = Interface::new(&iface_config, backend);
-            self.ifaces.insert(iface_config.name.clone(), iface);
+            let iface_config = InterfaceConfig::from_proto_config(r)?;
+            let name = iface_config.name.clone();
+            self.add_network(JoinedNetwork {
+                server_socket: server.clone(),
+                iface_config,
+                servers: servers.clone(),
+                signing_key: None,
+                member: None,
+                tls_ca: Some(invite.tls_ca.clone()),
+                adopted: false,
+            }).await?;
+            self.save_iface(&name)?;
+            log::info!("Interface {name} joined, saved in {}", self.config.iface_config_dir);
         }
         Ok(())
     }
 
+    /// Tell the server where interface `name` can be reached. A hostname endpoint is sent as is,
+    /// its address is only looked up to report it moving.
     pub async fn post_endpoint(&mut self, name: &str) -> Result<(), io::Error> {
         let iface = self.ifaces.get(name).unwrap();
+        let resolved = match &iface.config.external_endpoint {
+            Some(endpoint) => endpoint.resolve().await.ok(),
+            None => None,
+        };
         let req = proto::PostEndpointRequest {
             key: iface.config.private_key.clone(),
             internal_endpoint: iface.config.internal_endpoint.map(|e| { e.to_string() }),
-            external_endpoint: iface.config.external_endpoint.map(|e| { e.to_string() }),
+            external_endpoint: iface.config.external_endpoint.as_ref().map(|e| { e.to_string() }),
         };
-        let resp = self.rpc_client.post_endpoint(req).await.unwrap().into_inner();
-        if resp.ok {
-            log::debug!("Interface {}: post endpoint successfully", name);
-        } else {
-            log::error!("Post endpoint failed");
+        let resp = self.rpc(name).post_endpoint(req).await.map_err(status_to_io)?.into_inner();
+        if !resp.ok {
+            return Err(io::Error::other("the server refused the endpoint"));
+        }
+        let before = self.posted.insert(name.to_string(), resolved);
+        match (before, resolved) {
+            (Some(before), Some(addr)) if before != Some(addr) => {
+                log::info!("Interface {name}: external endpoint moved to {addr}");
+            }
+            _ => log::debug!("Interface {name}: endpoint posted"),
         }
         Ok(())
     }
 
-    pub async fn update_peers(&mut self, name: &str) -> Result<(), io::Error> {
+    /// Returns whether the server asks for a key rotation, and whether it was unreachable before.
+    pub async fn update_peers(&mut self, name: &str) -> Result<(bool, bool), io::Error> {
         let iface = self.ifaces.get(name).unwrap();
         let req = proto::GetPeersRequest {
             key: iface.config.private_key.clone(),
         };
-        let resp = self.rpc_client.get_peers(req).await.unwrap().into_inner();
-        println!("Get peers response: {:?}", resp);
-        let iface = self.ifaces.get_mut(name).unwrap();
-        // iface.update_peers(resp.peers);
-        panic!("Not implemented");
+        // each server once, until one answers
+        let mut tries = self.servers[name].len();
+        loop {
+            match self.rpc(name).get_peers(req.clone()).await {
+                Ok(resp) => return self.sync_peers(name, resp.into_inner()).await,
+                Err(e) if tries > 1 && server_down(&e) => {
+                    log::warn!("Interface {name}: server {} unreachable: {}", self.servers[name][0], e.message());
+                    self.fail_over(name)?;
+                    tries -= 1;
+                }
+                Err(e) => {
+                    if server_down(&e) {
+                        self.fail_over(name)?;
+                    }
+                    return Err(status_to_io(e));
+                }
+            }
+        }
+    }
+
+    /// Apply and cache the peers the server sent for interface `name`, the server being reachable.
+    /// Returns whether the server asks for a key rotation, and whether it was unreachable before.
+    async fn sync_peers(&mut self, name: &str, reply: proto::GetPeersReply) -> Result<(bool, bool), io::Error> {
+        let due = self.apply_peers(name, reply.clone()).await?;
+        if self.servers.contains_key(name) {
+            self.set_servers(name, &reply.servers).await?;
+        }
+        let dir = PathBuf::from(&self.config.iface_config_dir);
+        let health = match self.health.get_mut(name) {
+            Some(health) => health,
+            // removed from the network
+            None => return Ok((false, false)),
+        };
+        joined::save_peers(&dir, name, &reply)?;
+        let back = health.status.degraded;
+        if back {
+            log::info!("Interface {name}: server reachable again after {} tries", health.failures);
+            health.status.degraded = false;
+            health.status.since = unix_now();
+            health.status.last_error = None;
+        }
+        health.failures = 0;
+        health.next_poll = Instant::now() + Duration::from_secs(self.config.update_interval);
+        health.status.last_sync = Some(unix_now());
+        health.status.to_json_file(&NetworkStatus::path(&dir, name))?;
+        Ok((due, back))
+    }
+
+    /// Back off from the server of interface `name`, the tunnels keep the last peers meanwhile.
+    fn poll_failed(&mut self, name: &str, e: &io::Error) {
+        let dir = PathBuf::from(&self.config.iface_config_dir);
+        let health = match self.health.get_mut(name) {
+            Some(health) => health,
+            None => return,
+        };
+        health.failures += 1;
+        let delay = backoff(health.failures);
+        health.next_poll = Instant::now() + delay;
+        if !health.status.degraded {
+            log::warn!("Interface {name}: server unreachable, degraded, the tunnels keep the last peers: {e}");
+            health.status.degraded = true;
+            health.status.since = unix_now();
+        }
+        log::debug!("Interface {name}: try {} failed, next one in {:?}: {e}", health.failures, delay);
+        health.status.last_error = Some(e.to_string());
+        if let Err(e) = health.status.to_json_file(&NetworkStatus::path(&dir, name)) {
+            log::error!("Interface {name} failed to write its status: {e}");
+        }
+    }
+
+    /// Tell the server about the local networks routed by this host, and forward their traffic.
+    pub async fn advertise_routes(&mut self, name: &str) -> Result<(), io::Error> {
+        let routes = &self.config.advertise_routes;
+        // an empty list still goes out, it withdraws what was advertised before
+        if !routes.is_empty() {
+            enable_ip_forwarding(
+                routes.iter().any(|r| matches!(r, IpNet::V4(_))),
+                routes.iter().any(|r| matches!(r, IpNet::V6(_))),
+            )?;
+        }
+        // an exit node hides the members behind its own address, also while the server is down
+        let exit = routes.iter().any(|r| r.prefix_len() == 0);
+        self.ifaces.get_mut(name).unwrap().set_masquerade(exit)?;
+        let req = proto::AdvertiseRoutesRequest {
+            key: self.ifaces[name].config.private_key.clone(),
+            routes: routes.iter().map(|r| r.to_string()).collect(),
+        };
+        let resp = self.rpc(name).advertise_routes(req).await.map_err(status_to_io)?.into_inner();
+        log::info!("Interface {name}: routes {:?} approved", resp.approved);
+        if !resp.pending.is_empty() {
+            log::warn!("Interface {name}: routes {:?} wait for approval by an admin", resp.pending);
+        }
+        Ok(())
+    }
+
+    /// Tell the server about the services of this host.
+    pub async fn publish_services(&mut self, name: &str) -> Result<(), io::Error> {
+        let services = self.config.services.iter()
+            .map(|s| Service::parse(s).map(|s| s.to_proto_service()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let req = proto::PublishServicesRequest {
+            key: self.ifaces[name].config.private_key.clone(),
+            services,
+        };
+        self.rpc(name).publish_services(req).await.map_err(status_to_io)?;
+        log::info!("Interface {name}: services {:?} published", self.config.services);
         Ok(())
     }
 
-    // 扫描 config.iface_config_dir 目录，找到所有已知的 wg 配置文件
-    pub async fn scan_wg_config_dir(&mut self) {
-        self.config.iface_config_dir
+    /// Tell the server the key this member signs its gossip with, for the peers to check it.
+    pub async fn register_signing_key(&mut self, name: &str) -> Result<(), io::Error> {
+        let req = proto::RegisterSigningKeyRequest {
+            key: self.ifaces[name].config.private_key.clone(),
+            signing_key: gossip::public_signing_key(&self.gossip[name].secret)?,
+        };
+        self.rpc(name).register_signing_key(req).await.map_err(status_to_io)?;
+        Ok(())
+    }
+
+    /// Gossip on the first address of every interface.
+    async fn start_gossip(&self, tx: mpsc::Sender<(String, SocketAddr, GossipMessage)>) -> (HashMap<String, Arc<UdpSocket>>, Vec<JoinHandle<()>>) {
+        let mut sockets = HashMap::new();
+        let mut listeners = vec![];
+        if !self.config.gossip {
+            return (sockets, listeners);
+        }
+        for (name, iface) in self.ifaces.iter() {
+            let addr = match iface.config.addrs.first() {
+                Some(addr) => addr.addr(),
+                None => continue,
+            };
+            match gossip::bind(addr).await {
+                Ok(socket) => {
+                    listeners.push(tokio::spawn(gossip::listen(name.clone(), socket.clone(), tx.clone())));
+                    sockets.insert(name.clone(), socket);
+                }
+                Err(e) => log::error!("Interface {name} failed to gossip on {addr}: {e}"),
+            }
+        }
+        (sockets, listeners)
+    }
+
+    /// Send the records known to every peer, telling each where it is seen talking from.
+    async fn gossip_round(&self, sockets: &HashMap<String, Arc<UdpSocket>>) {
+        let now = unix_now();
+        for (name, socket) in sockets.iter() {
+            let (iface, gossip) = match (self.ifaces.get(name), self.gossip.get(name)) {
+                (Some(iface), Some(gossip)) => (iface, gossip),
+                _ => continue,
+            };
+            let devices = match iface.peer_endpoints() {
+                Ok(devices) => devices,
+                Err(e) => {
+                    log::warn!("Interface {name} failed to read its peers: {e}");
+                    continue;
+                }
+            };
+            let v4 = socket.local_addr().map_or(true, |a| a.is_ipv4());
+            for (peer, config) in iface.config.peers.iter() {
+                let to = match config.allowed_ips.iter().find(|ip| ip.prefix_len() == ip.max_prefix_len() && ip.addr().is_ipv4() == v4) {
+                    Some(ip) => ip.addr(),
+                    None => continue,
+                };
+                // only an endpoint of a live session tells where the peer is now
+                let observed = devices.get(&config.public_key)
+                    .filter(|(_, handshake)| !handshake_stale(*handshake))
+                    .and_then(|(endpoint, _)| *endpoint);
+                for message in gossip.messages(observed, now) {
+                    if let Err(e) = gossip::send(socket, to, &message).await {
+                        log::debug!("Interface {name}: gossip to {peer} failed: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take the records a peer sent on interface `name`, moving the peers that went quiet to
+    /// their newest endpoint.
+    fn take_gossip(&mut self, name: &str, from: SocketAddr, message: GossipMessage) -> Result<(), io::Error> {
+        let (iface, gossip) = match (self.ifaces.get_mut(name), self.gossip.get_mut(name)) {
+            (Some(iface), Some(gossip)) => (iface, gossip),
+            _ => return Ok(()),
+        };
+        // the tunnel only lets a peer send from its own addresses
+        let sender = iface.config.peers.iter()
+            .find(|(_, p)| p.allowed_ips.iter().any(|ip| ip.prefix_len() == ip.max_prefix_len() && ip.addr() == from.ip()))
+            .map(|(n, _)| n.clone());
+        let sender = match sender {
+            Some(sender) => sender,
+            None => {
+                log::debug!("Interface {name}: gossip from {from}, not a peer");
+                return Ok(());
+            }
+        };
+        let now = unix_now();
+        if let Some(observed) = message.observed {
+            gossip.observed(&sender, observed, now)?;
+        }
+        let taken: Vec<_> = message.records.into_iter().filter(|r| gossip.accept(r.clone(), now)).collect();
+        if taken.is_empty() {
+            return Ok(());
+        }
+        let devices = iface.peer_endpoints()?;
+        for record in taken {
+            let peer = match iface.config.peers.get(&record.member) {
+                Some(peer) => peer.clone(),
+                None => continue,
+            };
+            let (current, handshake) = devices.get(&peer.public_key).cloned().unwrap_or((None, None));
+            if handshake_stale(handshake) && current != Some(record.endpoint) {
+                log::info!("Interface {name}: peer {} moved to {}, heard from {sender}", record.member, record.endpoint);
+                iface.set_peer_endpoint(&peer.public_key, record.endpoint)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer the member names on the addresses of every interface, the system resolver
+    /// taking the other names.
+    fn serve_dns(&self) -> Vec<JoinHandle<()>> {
+        if !self.config.dns {
+            return vec![];
+        }
+        let own: Vec<IpAddr> = self.ifaces.values().flat_map(|i| i.config.addrs.iter().map(|a| a.addr())).collect();
+        let upstream = dns::system_resolver(&own);
+        own.into_iter().map(|addr| {
+            let zones = self.dns_zones.clone();
+            tokio::spawn(async move {
+                if let Err(e) = dns::serve(SocketAddr::new(addr, 53), zones, upstream).await {
+                    log::error!("DNS on {addr} failed: {e}");
+                }
+            })
+        }).collect()
+    }
+
+    /// Send the names of the network of interface `name` to its resolvers, the ones pushed by
+    /// the server or else the resolver of this client.
+    fn configure_resolver(&mut self, name: &str) -> Result<(), io::Error> {
+        let config = &self.ifaces[name].config;
+        let servers: Vec<IpAddr> = if !config.dns.is_empty() {
+            config.dns.clone()
+        } else if self.config.dns {
+            config.addrs.iter().map(|a| a.addr()).collect()
+        } else {
+            vec![]
+        };
+        if servers.is_empty() {
+            return Ok(());
+        }
+        let mut domains = vec![format!("{}.internal", name.to_lowercase())];
+        domains.extend(config.search.iter().cloned());
+        let method = resolver::configure(name, &servers, &domains)?;
+        log::info!("Interface {name}: {:?} resolve {:?} through {:?}", servers, domains, method);
+        self.resolvers.insert(name.to_string(), method);
+        Ok(())
+    }
+
+    /// Forward the peers the server pushes for interface `name` to `tx`, until the stream ends.
+    fn watch_peers(&self, name: &str, tx: mpsc::Sender<(String, proto::GetPeersReply)>) -> JoinHandle<()> {
+        let mut rpc_client = self.rpc(name);
+        let name = name.to_string();
+        let req = proto::GetPeersRequest {
+            key: self.ifaces[&name].config.private_key.clone(),
+        };
+        tokio::spawn(async move {
+            let mut stream = match rpc_client.watch_peers(req).await {
+                Ok(resp) => resp.into_inner(),
+                Err(e) => {
+                    log::warn!("Interface {name}: failed to watch peers: {}", e.message());
+                    return;
+                }
+            };
+            loop {
+                match stream.message().await {
+                    Ok(Some(reply)) => {
+                        if tx.send((name.clone(), reply)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(e) => {
+                        log::warn!("Interface {name}: peer watch broke: {}", e.message());
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Apply the peers sent by the server, tearing the interface down once its member is removed.
+    /// Returns whether the server asks for a key rotation.
+    async fn apply_peers(&mut self, name: &str, reply: proto::GetPeersReply) -> Result<bool, io::Error> {
+        if reply.removed {
+            log::warn!("Interface {name}: removed from the network, tearing it down");
+            self.dns_zones.write().unwrap().remove(name);
+            if let Some(path) = &self.config.hosts_file {
+                hosts::remove(Path::new(path), name)?;
+            }
+            if let Some(method) = self.resolvers.remove(name) {
+                resolver::restore(name, &method)?;
+            }
+            self.forget_iface(name)?;
+            if let Some(mut iface) = self.ifaces.remove(name) {
+                iface.teardown()?;
+            }
+            return Ok(false);
+        }
+        let peers = reply.peers.iter()
+            .map(|(n, p)| Ok((n.clone(), PeerConfig::from_proto_peer(p)?)))
+            .collect::<Result<HashMap<_, _>, io::Error>>()?;
+        let firewall = match reply.firewall {
+            Some(firewall) => Some(firewall.rules.iter()
+                .map(FirewallRule::from_proto_rule)
+                .collect::<Result<Vec<_>, io::Error>>()?),
+            None => None,
+        };
+        if let Some(iface) = self.ifaces.get_mut(name) {
+            iface.update_peers(peers).await?;
+            iface.set_firewall(firewall)?;
+            let mut zone = Zone::from_iface(&iface.config, &reply.member);
+            zone.services = reply.services.iter()
+                .filter_map(|r| Some((r.member.to_lowercase(), Service::from_proto_service(r.service.as_ref()?).ok()?)))
+                .collect();
+            if let Some(path) = &self.config.hosts_file {
+                hosts::update(Path::new(path), name, &zone)?;
+            }
+            self.dns_zones.write().unwrap().insert(name.to_string(), zone);
+        }
+        if let Some(gossip) = self.gossip.get_mut(name) {
+            gossip.keys = reply.signing_keys.clone();
+            // renamed by an admin, or the first name known
+            if !reply.member.is_empty() && gossip.member != reply.member {
+                gossip.set_member(&reply.member);
+                self.save_iface(name)?;
+            }
+        }
+        Ok(reply.rotate_key)
+    }
+
+    /// Replace the key of interface `name`. The server takes the new public key first and keeps
+    /// accepting the old one for a grace period, so a failure in between can be retried.
+    /// The new key is saved before the interface uses it, a restart then picks it up.
+    pub async fn rotate_key(&mut self, name: &str) -> Result<(), io::Error> {
+        let mut rpc_client = self.rpc(name);
+        let new_key = Key::generate_private();
+        let new_secret = gossip::generate_signing_key();
+        let req = proto::RotateKeyRequest {
+            key: self.ifaces[name].config.private_key.clone(),
+            new_public_key: new_key.generate_public().to_base64(),
+            new_signing_key: gossip::public_signing_key(&new_secret)?,
+        };
+        let resp = rpc_client.rotate_key(req).await.map_err(status_to_io)?.into_inner();
+        if let Some(gossip) = self.gossip.get_mut(name) {
+            gossip.set_secret(&new_secret);
+        }
+        let mut iface_config = self.ifaces[name].config.clone();
+        iface_config.private_key = new_key.to_base64();
+        self.save_network(name, iface_config)?;
+        self.ifaces.get_mut(name).unwrap().set_private_key(&new_key.to_base64())?;
+        log::info!("Interface {name}: key rotated, the server accepts the old one until {}", format_timestamp(resp.grace_until));
+        Ok(())
+    }
+
+    /// Keep the network of interface `name` in the iface config dir, private key included.
+    fn save_iface(&self, name: &str) -> Result<(), io::Error> {
+        self.save_network(name, self.ifaces[name].config.clone())
+    }
+
+    fn save_network(&self, name: &str, iface_config: InterfaceConfig) -> Result<(), io::Error> {
+        let network = JoinedNetwork {
+            server_socket: self.servers[name][0].clone(),
+            iface_config,
+            servers: self.servers[name].clone(),
+            signing_key: self.gossip.get(name).map(|g| g.secret.clone()),
+            member: self.gossip.get(name).map(|g| g.member.clone()).filter(|m| !m.is_empty()),
+            tls_ca: self.tls_cas.get(name).cloned(),
+            adopted: self.ifaces.get(name).is_some_and(|iface| iface.adopted),
+        };
+        network.to_yaml_file(&JoinedNetwork::path(Path::new(&self.config.iface_config_dir), name))
+    }
+
+    /// Forget the network of interface `name`, it is not resumed anymore.
+    fn forget_iface(&mut self, name: &str) -> Result<(), io::Error> {
+        self.rpc_clients.remove(name);
+        self.servers.remove(name);
+        self.health.remove(name);
+        self.gossip.remove(name);
+        self.posted.remove(name);
+        self.tls_cas.remove(name);
+        let dir = Path::new(&self.config.iface_config_dir);
+        for path in [
+            JoinedNetwork::path(dir, name),
+            joined::peers_path(dir, name),
+            NetworkStatus::path(dir, name),
+        ] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Addresses of `servers`, the ones failing to resolve are left out.
+async fn resolve_all(servers: &[Endpoint]) -> Vec<SocketAddr> {
+    let mut resolved = vec![];
+    for server in servers {
+        match server.resolve().await {
+            Ok(addr) => resolved.push(addr),
+            Err(e) => log::warn!("Failed to resolve server {server}: {e}"),
+        }
+    }
+    resolved
+}
+
+/// Whether a peer with its last handshake at `handshake` stopped talking to us.
+fn handshake_stale(handshake: Option<SystemTime>) -> bool {
+    handshake.is_none_or(|t| t.elapsed().map_or(true, |e| e > gossip::STALE_HANDSHAKE))
+}
+
+/// Whether `status` means the server could not be reached, rather than it refusing the request.
+fn server_down(status: &tonic::Status) -> bool {
+    matches!(status.code(), tonic::Code::Unavailable | tonic::Code::Unknown | tonic::Code::DeadlineExceeded | tonic::Code::Cancelled)
+}
+
+/// Ctrl-c, and SIGTERM where there is one. Listened for from creation on, so a signal coming
+/// while the client is busy between two polls is not missed. A signal it failed to listen for
+/// keeps its default action.
+struct ShutdownSignal {
+    #[cfg(unix)]
+    interrupt: Option<tokio::signal::unix::Signal>,
+    #[cfg(unix)]
+    terminate: Option<tokio::signal::unix::Signal>,
+    #[cfg(windows)]
+    ctrl_c: Option<tokio::signal::windows::CtrlC>,
+}
+
+impl ShutdownSignal {
+    fn new() -> Self {
+        fn listen<S>(name: &str, signal: Result<S, io::Error>) -> Option<S> {
+            signal.map_err(|e| log::error!("Failed to listen for {name}: {e}")).ok()
+        }
+        #[cfg(unix)]
+        use tokio::signal::unix::{signal, SignalKind};
+        ShutdownSignal {
+            #[cfg(unix)]
+            interrupt: listen("ctrl-c", signal(SignalKind::interrupt())),
+            #[cfg(unix)]
+            terminate: listen("SIGTERM", signal(SignalKind::terminate())),
+            #[cfg(windows)]
+            ctrl_c: listen("ctrl-c", tokio::signal::windows::ctrl_c()),
+        }
+    }
+
+    /// Resolves on the next signal.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        {
+            async fn next(signal: &mut Option<tokio::signal::unix::Signal>) -> Option<()> {
+                match signal {
+                    Some(signal) => signal.recv().await,
+                    None => std::future::pending().await,
+                }
+            }
+            tokio::select! {
+                Some(_) = next(&mut self.interrupt) => {}
+                Some(_) = next(&mut self.terminate) => {}
+                else => std::future::pending().await,
+            }
+        }
+        #[cfg(windows)]
+        match &mut self.ctrl_c {
+            Some(ctrl_c) => {
+                ctrl_c.recv().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Ask the server to send the internet traffic of interface `iface` through member `exit`,
+/// or back to normal routing with None. The running daemon gets the new peers pushed.
+pub async fn use_exit(config: &ClientConfig, iface: Option<&str>, server: Option<SocketAddr>, exit: Option<&str>) -> Result<(), io::Error> {
+    let (iface_config, mut rpc_client) = open_network(config, iface, server)?;
+    let req = proto::UseExitRequest {
+        key: iface_config.private_key.clone(),
+        exit: exit.map(|e| e.to_string()),
+    };
+    rpc_client.use_exit(req).await.map_err(status_to_io)?;
+    match exit {
+        Some(exit) => println!("Interface {}: traffic goes through exit node {exit}", iface_config.name),
+        None => println!("Interface {}: exit node off", iface_config.name),
+    }
+    Ok(())
+}
+
+/// Print the services of the members interface `iface` may reach.
+pub async fn list_services(config: &ClientConfig, iface: Option<&str>, server: Option<SocketAddr>, json: bool) -> Result<(), io::Error> {
+    let (iface_config, mut rpc_client) = open_network(config, iface, server)?;
+    let req = proto::ListServicesRequest {
+        key: iface_config.private_key.clone(),
+    };
+    let resp = rpc_client.list_services(req).await.map_err(status_to_io)?.into_inner();
+    if json {
+        return crate::ctl::print_json(&resp.services);
+    }
+    println!("{:<16}  {:<16}  {:<5}  {:<6}  DNS", "MEMBER", "SERVICE", "PROTO", "PORT");
+    for r in resp.services.iter() {
+        let s = r.service.clone().unwrap_or_default();
+        println!("{:<16}  {:<16}  {:<5}  {:<6}  _{}._{}.{}.internal",
+                 r.member, s.name, s.proto, s.port, s.name, s.proto, iface_config.name);
+    }
+    Ok(())
+}
+
+/// Print the networks joined and the health the daemon reported for them.
+pub fn print_status(config: &ClientConfig, json: bool) -> Result<(), io::Error> {
+    let dir = Path::new(&config.iface_config_dir);
+    let networks: Vec<(JoinedNetwork, Option<NetworkStatus>)> = JoinedNetwork::scan_dir(dir)?.into_iter()
+        .map(|n| {
+            let status = NetworkStatus::from_json_file(&NetworkStatus::path(dir, &n.iface_config.name)).ok();
+            (n, status)
+        })
+        .collect();
+    if json {
+        let statuses: HashMap<&str, &Option<NetworkStatus>> = networks.iter()
+            .map(|(n, s)| (n.iface_config.name.as_str(), s))
+            .collect();
+        return crate::ctl::print_json(&statuses);
+    }
+    println!("{:<16}  {:<28}  {:<9}  {:<23}  {:<23}  ERROR", "IFACE", "SERVER", "STATUS", "SINCE", "LAST SYNC");
+    for (n, s) in networks.iter() {
+        let (state, since, last_sync, error) = match s {
+            Some(s) => (
+                if s.degraded { "degraded" } else { "ok" },
+                format_timestamp(s.since),
+                s.last_sync.map_or("never".to_string(), format_timestamp),
+                s.last_error.clone().unwrap_or_default(),
+            ),
+            None => ("unknown", String::new(), String::new(), String::new()),
+        };
+        println!("{:<16}  {:<28}  {:<9}  {:<23}  {:<23}  {}",
+                 n.iface_config.name, n.server_socket.to_string(), state, since, last_sync, error);
+    }
+    Ok(())
+}
+
+/// The network of interface `iface`, or the only one joined, and a client of `server`,
+/// its own server by default.
+fn open_network(config: &ClientConfig, iface: Option<&str>, server: Option<SocketAddr>) -> Result<(InterfaceConfig, RpcClient), io::Error> {
+    let dir = Path::new(&config.iface_config_dir);
+    let network = match iface {
+        Some(iface) => JoinedNetwork::from_yaml_file(&JoinedNetwork::path(dir, iface))?,
+        None => {
+            let mut networks = JoinedNetwork::scan_dir(dir)?;
+            if networks.len() != 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} networks in {}, pick one with --iface", networks.len(), dir.display()),
+                ));
+            }
+            networks.remove(0)
+        }
+    };
+    let rpc_client = connect_lazy(&server.map(Endpoint::from).unwrap_or(network.server_socket.clone()), network.tls_ca()?)?;
+    Ok((network.iface_config, rpc_client))
+}
+
+/// Register a hand-built wireguard interface with the server as member `name`, and save it
+/// so that the daemon resumes it. The device is only read, its tunnels are left untouched.
+pub async fn adopt(config: &ClientConfig, iface_name: &str, name: &str, server: SocketAddr, token: &str, tls_ca: &str) -> Result<(), io::Error> {
+    let backend = parse_backend(&config.backend);
+    let iface = Interface::from_device(iface_name, backend)?;
+    let routes = iface.read_routes()?;
+    log::info!("Adopting interface {iface_name}: addrs {:?}, routes {:?}", iface.config.addrs, routes);
+    let mut iface_config = iface.config.to_proto_config()?;
+    iface_config.private_key = String::new();
+    let mut req = tonic::Request::new(proto::AdoptRequest {
+        key: iface.config.private_key.clone(),
+        name: name.to_string(),
+        iface_config: Some(iface_config),
+        routes: routes.iter().map(|r| r.to_string()).collect(),
+    });
+    let bearer = format!("Bearer {}", token).parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid admin token"))?;
+    req.metadata_mut().insert("authorization", bearer);
+    let server_socket = Endpoint::from(server);
+    let mut admin_client = proto::admin_client::AdminClient::new(connect_channel(&server_socket, tls_ca)?);
+    let resp = admin_client.adopt(req).await.map_err(status_to_io)?.into_inner();
+    log::info!("Interface {iface_name} adopted as member {name}, {} peers in the network", resp.peers.len());
+    for (peer, config) in resp.peers.iter() {
+        if !iface.config.peers.values().any(|p| p.public_key == config.public_key) {
+            log::warn!("Peer {peer} is not configured on {iface_name} yet");
+        }
+    }
+    let mut servers = resp.servers.iter()
+        .map(|s| s.parse::<Endpoint>())
+        .collect::<Result<Vec<_>, _>>()?;
+    servers.retain(|s| *s != server_socket);
+    servers.insert(0, server_socket.clone());
+    let dir = Path::new(&config.iface_config_dir);
+    fs::create_dir_all(dir)?;
+    let network = JoinedNetwork {
+        server_socket,
+        iface_config: iface.config,
+        servers,
+        signing_key: None,
+        member: Some(name.to_string()),
+        tls_ca: Some(tls_ca.to_string()),
+        adopted: true,
+    };
+    network.to_yaml_file(&JoinedNetwork::path(dir, iface_name))?;
+    log::info!("Interface {iface_name} saved in {}", config.iface_config_dir);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        for _ in 0..100 {
+            let first = backoff(1);
+            assert!(first >= Duration::from_millis(500) && first <= Duration::from_secs(1));
+            let fourth = backoff(4);
+            assert!(fourth >= Duration::from_secs(4) && fourth <= Duration::from_secs(8));
+            let last = backoff(40);
+            assert!(last >= MAX_BACKOFF / 2 && last <= MAX_BACKOFF);
+        }
     }
 }
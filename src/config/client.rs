@@ -1,8 +1,8 @@
 use std::fs::OpenOptions;
 use std::io;
-use std::io::{Read, Write};
-use wireguard_control::Backend;
+use std::io::Read;
 use std::path::Path;
+use ipnet::IpNet;
 use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
@@ -11,6 +11,32 @@ pub struct ClientConfig {
     pub update_interval: u64,
     pub iface_config_dir: String,
     pub backend: String,
+    // prefixes of the local networks this host routes for the others
+    #[serde(default)]
+    pub advertise_routes: Vec<IpNet>,
+    // drop traffic leaving outside the tunnel while an exit node is used
+    #[serde(default)]
+    pub kill_switch: bool,
+    // answer member names on the interface addresses
+    #[serde(default = "default_dns")]
+    pub dns: bool,
+    // hosts file keeping a block of member names, e.g. /etc/hosts, left alone by default
+    #[serde(default)]
+    pub hosts_file: Option<String>,
+    // services of this host published to the members, "name:port" or "name:port/udp"
+    #[serde(default)]
+    pub services: Vec<String>,
+    // share signed endpoints with the peers, to find each other while the server is down
+    #[serde(default = "default_gossip")]
+    pub gossip: bool,
+}
+
+fn default_dns() -> bool {
+    true
+}
+
+fn default_gossip() -> bool {
+    true
 }
 
 impl ClientConfig {
@@ -23,16 +49,6 @@ impl ClientConfig {
         let config = serde_yaml::from_str(&yaml_str).unwrap();
         Ok(config)
     }
-
-    pub fn to_yaml_file(&self, path: &Path) -> Result<(), io::Error> {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(path)?;
-        let yaml_str = serde_yaml::to_string(&self).unwrap();
-        file.write_all(yaml_str.as_bytes())?;
-        Ok(())
-    }
 }
 
 #[cfg(test)]
@@ -42,7 +58,7 @@ mod test {
     #[test]
     fn test_client_config() {
         let config = ClientConfig::from_yaml_file(Path::new("example/client.yaml")).unwrap();
-        config.to_yaml_file(Path::new("example/tmp/client.yml")).unwrap();
+        std::fs::write("example/tmp/client.yml", serde_yaml::to_string(&config).unwrap()).unwrap();
         let config2 = ClientConfig::from_yaml_file(Path::new("example/tmp/client.yml")).unwrap();
         assert_eq!(config, config2);
     }
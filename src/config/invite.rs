@@ -1,38 +1,49 @@
 use std::io;
 use serde::{Serialize, Deserialize};
-use std::net::SocketAddr;
-use clap::builder::Str;
-use wireguard_control::Key;
-use map_macro::map;
-use crate::config::wg::InterfaceConfig;
+use crate::config::wg::{Endpoint, InterfaceConfig};
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct InviteConfig {
     pub iface_config: InterfaceConfig,
-    pub server_socket: SocketAddr,
+    pub server_socket: Endpoint,
     pub key: String,
+    // all servers of the network, server_socket first
+    #[serde(default)]
+    pub servers: Vec<Endpoint>,
+    // pem certificate the servers present, empty in invites made before the rpc used tls
+    #[serde(default)]
+    pub tls_ca: String,
 }
 
 impl InviteConfig {
+    /// Servers to redeem the invite at, in order. Older invites only name `server_socket`.
+    pub fn servers(&self) -> Vec<Endpoint> {
+        if self.servers.is_empty() {
+            return vec![self.server_socket.clone()];
+        }
+        self.servers.clone()
+    }
+
     pub fn from_base64_json(base64_str: &String) -> Result<Self, io::Error> {
-        let json_u8 = base64::decode(&base64_str).unwrap();
+        let json_u8 = base64::decode(base64_str).unwrap();
         let json_str = String::from_utf8(json_u8).unwrap();
         let invite_config = serde_json::from_str(&json_str)?;
-        return Ok(invite_config);
+        Ok(invite_config)
     }
 
     pub fn to_base64_json(&self) -> String {
         let json_str = serde_json::to_string(&self).unwrap();
         log::debug!("json_str: {}", json_str);
         let json_u8 = json_str.as_bytes();
-        let base64_str = base64::encode(json_u8);
-        return base64_str;
+        base64::encode(json_u8)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use wireguard_control::Key;
+    use map_macro::hash_map as map;
     use crate::config::wg::*;
 
     #[test]
@@ -59,6 +70,7 @@ mod test {
                         ],
                         preshared_key: None,
                         persistent_keepalive: Some(25),
+                        next_preshared_key: None,
                     },
                     "peer2".to_string() => PeerConfig {
                         public_key: Key::generate_private().generate_public().to_base64(),
@@ -69,11 +81,16 @@ mod test {
                         ],
                         preshared_key: None,
                         persistent_keepalive: Some(25),
+                        next_preshared_key: None,
                     }
                 },
+                dns: vec![],
+                search: vec![],
             },
             server_socket: "10.1.0.0:8888".parse().unwrap(),
             key: "invite_key".to_string(),
+            servers: vec!["10.1.0.0:8888".parse().unwrap(), "vpn2.example.com:8888".parse().unwrap()],
+            tls_ca: "-----BEGIN CERTIFICATE-----".to_string(),
         };
         let base64_str = config.to_base64_json();
         log::debug!("base64_str: {}", base64_str);
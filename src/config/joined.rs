@@ -0,0 +1,176 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use crate::api::proto;
+use crate::config::wg::{Endpoint, InterfaceConfig};
+use crate::utils::write_atomic;
+
+/// A network the client joined, kept as `<iface>.yaml` in the iface config dir so the daemon
+/// resumes it on restart. Holds the private key, only root may read it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JoinedNetwork {
+    // the server in use
+    pub server_socket: Endpoint,
+    pub iface_config: InterfaceConfig,
+    // all servers of the network, server_socket first
+    #[serde(default)]
+    pub servers: Vec<Endpoint>,
+    // ed25519 secret signing the gossip of this member, generated when missing and replaced
+    // along with the private key on rotations
+    #[serde(default)]
+    pub signing_key: Option<String>,
+    // name of the member in the network, as the server last sent it
+    #[serde(default)]
+    pub member: Option<String>,
+    // pem certificate the servers present, missing for networks joined before the rpc used tls
+    #[serde(default)]
+    pub tls_ca: Option<String>,
+    // the device was built outside wgnet and adopted, it outlives the daemon
+    #[serde(default)]
+    pub adopted: bool,
+}
+
+impl JoinedNetwork {
+    /// Servers to fail over to, in order. Networks joined before server lists only name
+    /// `server_socket`.
+    pub fn servers(&self) -> Vec<Endpoint> {
+        if self.servers.is_empty() {
+            return vec![self.server_socket.clone()];
+        }
+        self.servers.clone()
+    }
+
+    /// Certificate to check the servers against, the servers can't be trusted without it.
+    pub fn tls_ca(&self) -> Result<&str, io::Error> {
+        self.tls_ca.as_deref().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("network of {} was joined before the servers used tls, join it again", self.iface_config.name),
+        ))
+    }
+
+    pub fn path(dir: &Path, iface: &str) -> PathBuf {
+        dir.join(format!("{}.yaml", iface))
+    }
+
+    pub fn from_yaml_file(path: &Path) -> Result<Self, io::Error> {
+        let yaml_str = fs::read_to_string(path)?;
+        serde_yaml::from_str(&yaml_str).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn to_yaml_file(&self, path: &Path) -> Result<(), io::Error> {
+        let yaml_str = serde_yaml::to_string(&self).unwrap();
+        write_atomic(path, yaml_str.as_bytes(), 0o600)
+    }
+
+    /// All the networks saved in `dir`.
+    pub fn scan_dir(dir: &Path) -> Result<Vec<Self>, io::Error> {
+        let mut networks = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "yaml") {
+                continue;
+            }
+            match Self::from_yaml_file(&path) {
+                Ok(network) => networks.push(network),
+                Err(e) => log::warn!("Skipping {}: {e}", path.display()),
+            }
+        }
+        networks.sort_by(|a, b| a.iface_config.name.cmp(&b.iface_config.name));
+        Ok(networks)
+    }
+}
+
+/// The last peer set the server sent for interface `iface`, `<iface>.peers.json` in `dir`.
+/// The tunnels are brought up from it while the server is unreachable.
+pub fn peers_path(dir: &Path, iface: &str) -> PathBuf {
+    dir.join(format!("{}.peers.json", iface))
+}
+
+pub fn save_peers(dir: &Path, iface: &str, reply: &proto::GetPeersReply) -> Result<(), io::Error> {
+    let json_str = serde_json::to_string(reply)?;
+    write_atomic(&peers_path(dir, iface), json_str.as_bytes(), 0o600)
+}
+
+/// The cached peer set of interface `iface`, if any.
+pub fn load_peers(dir: &Path, iface: &str) -> Result<Option<proto::GetPeersReply>, io::Error> {
+    match fs::read_to_string(peers_path(dir, iface)) {
+        Ok(json_str) => Ok(Some(serde_json::from_str(&json_str)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Health of a joined network as last reported by the daemon, `<iface>.status.json` in the
+/// iface config dir.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NetworkStatus {
+    // the server is unreachable and the tunnels run from the cached peers
+    pub degraded: bool,
+    // unix seconds, when `degraded` last changed
+    pub since: u64,
+    // unix seconds, when the server last sent the peers
+    pub last_sync: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl NetworkStatus {
+    pub fn path(dir: &Path, iface: &str) -> PathBuf {
+        dir.join(format!("{}.status.json", iface))
+    }
+
+    pub fn from_json_file(path: &Path) -> Result<Self, io::Error> {
+        let json_str = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json_str)?)
+    }
+
+    pub fn to_json_file(&self, path: &Path) -> Result<(), io::Error> {
+        let json_str = serde_json::to_string(&self)?;
+        write_atomic(path, json_str.as_bytes(), 0o644)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use wireguard_control::Key;
+
+    #[test]
+    fn test_scan_dir() {
+        let dir = std::env::temp_dir().join(format!("wgnet-joined-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let network = JoinedNetwork {
+            server_socket: "vpn.example.com:51821".parse().unwrap(),
+            iface_config: InterfaceConfig {
+                name: "wg0".to_string(),
+                private_key: Key::generate_private().to_base64(),
+                addrs: vec!["10.1.1.2/16".parse().unwrap()],
+                listen_port: None,
+                mtu: None,
+                internal_endpoint: None,
+                external_endpoint: None,
+                peers: HashMap::new(),
+                dns: vec![],
+                search: vec![],
+            },
+            servers: vec![],
+            signing_key: None,
+            member: Some("laptop".to_string()),
+            tls_ca: Some("-----BEGIN CERTIFICATE-----".to_string()),
+            adopted: true,
+        };
+        network.to_yaml_file(&JoinedNetwork::path(&dir, "wg0")).unwrap();
+        fs::write(dir.join("client.conf"), "ignored").unwrap();
+        assert_eq!(load_peers(&dir, "wg0").unwrap(), None);
+        let reply = proto::GetPeersReply {
+            rotate_key: true,
+            ..Default::default()
+        };
+        save_peers(&dir, "wg0", &reply).unwrap();
+        assert_eq!(load_peers(&dir, "wg0").unwrap(), Some(reply));
+        assert_eq!(JoinedNetwork::scan_dir(&dir).unwrap(), vec![network]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use ipnet::IpNet;
+use serde::{Serialize, Deserialize};
+use wireguard_control::Key;
+
+use crate::state::{AclRule, Member, NetworkState};
+
+/// Network membership as kept in git and applied with `wgnet apply`.
+/// Sections replace the server state as a whole, runtime data such as endpoints is kept.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct NetworkManifest {
+    #[serde(default)]
+    pub members: BTreeMap<String, ManifestMember>,
+    // name: address kept out of allocation
+    #[serde(default)]
+    pub reservations: BTreeMap<String, IpAddr>,
+    #[serde(default)]
+    pub acls: Vec<AclRule>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestMember {
+    pub public_key: String,
+    pub addrs: Vec<IpNet>,
+    // routes behind the member
+    #[serde(default)]
+    pub allowed_ips: Vec<IpNet>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl NetworkManifest {
+    pub fn from_yaml_file(path: &Path) -> Result<Self, io::Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(path)?;
+        let mut yaml_str = String::new();
+        file.read_to_string(&mut yaml_str)?;
+        Self::from_yaml_str(&yaml_str)
+    }
+
+    pub fn from_yaml_str(yaml_str: &str) -> Result<Self, io::Error> {
+        let manifest: Self = serde_yaml::from_str(yaml_str)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    pub fn validate(&self) -> Result<(), io::Error> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        let mut used: BTreeMap<IpAddr, &String> = BTreeMap::new();
+        let mut keys: BTreeMap<&String, &String> = BTreeMap::new();
+        for (name, member) in self.members.iter() {
+            if Key::from_base64(&member.public_key).is_err() {
+                return invalid(format!("member {}: invalid public key", name));
+            }
+            if let Some(other) = keys.insert(&member.public_key, name) {
+                return invalid(format!("member {}: public key already used by {}", name, other));
+            }
+            for addr in member.addrs.iter() {
+                if let Some(other) = used.insert(addr.addr(), name) {
+                    return invalid(format!("member {}: address {} already used by {}", name, addr.addr(), other));
+                }
+            }
+        }
+        for (name, addr) in self.reservations.iter() {
+            if let Some(other) = used.insert(*addr, name) {
+                return invalid(format!("reservation {}: address {} already used by {}", name, addr, other));
+            }
+        }
+        for acl in self.acls.iter() {
+            if let Err(e) = crate::policy::validate_acl(acl) {
+                return invalid(e);
+            }
+            for selector in [&acl.from, &acl.to] {
+                if !self.selector_known(selector) {
+                    return invalid(format!("acl {} -> {}: unknown selector {}", acl.from, acl.to, selector));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the addresses against what the manifest does not replace: pending invites and
+    /// the `taken` addresses of the server, as `allocate` avoids them.
+    pub fn check_addrs(&self, state: &NetworkState, taken: &[IpAddr]) -> Result<(), io::Error> {
+        let members = self.members.iter()
+            .flat_map(|(name, m)| m.addrs.iter().map(move |a| (format!("member {}", name), a.addr())));
+        let reservations = self.reservations.iter().map(|(name, a)| (format!("reservation {}", name), *a));
+        for (claim, addr) in members.chain(reservations) {
+            let holder = if taken.contains(&addr) {
+                Some("the server".to_string())
+            } else {
+                state.invites.values()
+                    .find(|i| i.addrs.iter().any(|a| a.addr() == addr))
+                    .map(|i| format!("the invite of {}", i.name))
+            };
+            if let Some(holder) = holder {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{}: address {} already used by {}", claim, addr, holder),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn selector_known(&self, selector: &str) -> bool {
+        match selector.split_once(':') {
+            _ if selector == "*" => true,
+            Some(("member", name)) => self.members.contains_key(name),
+            Some(("group", group)) => self.members.values().any(|m| m.groups.iter().any(|g| g == group)),
+            Some(("tag", tag)) => self.members.values().any(|m| m.tags.iter().any(|t| t == tag)),
+            _ => false,
+        }
+    }
+
+    /// Human readable changes needed to bring `state` to this manifest.
+    pub fn plan(&self, state: &NetworkState) -> Vec<String> {
+        let mut plan = vec![];
+        for name in state.members.keys() {
+            if !self.members.contains_key(name) {
+                plan.push(format!("- member {}", name));
+            }
+        }
+        for (name, wanted) in self.members.iter() {
+            match state.members.get(name) {
+                None => plan.push(format!("+ member {} {:?}", name, wanted.addrs)),
+                Some(current) => {
+                    let mut diff = |field: &str, from: String, to: String| {
+                        if from != to {
+                            plan.push(format!("~ member {} {}: {} -> {}", name, field, from, to));
+                        }
+                    };
+                    diff("public_key", current.public_key.clone(), wanted.public_key.clone());
+                    diff("addrs", format!("{:?}", current.addrs), format!("{:?}", wanted.addrs));
+                    diff("allowed_ips", format!("{:?}", current.allowed_ips), format!("{:?}", wanted.allowed_ips));
+                    diff("groups", format!("{:?}", current.groups), format!("{:?}", wanted.groups));
+                    diff("tags", format!("{:?}", current.tags), format!("{:?}", wanted.tags));
+                    diff("persistent_keepalive", format!("{:?}", current.persistent_keepalive), format!("{:?}", wanted.persistent_keepalive));
+                }
+            }
+        }
+        for (name, addr) in state.reservations.iter() {
+            match self.reservations.get(name) {
+                None => plan.push(format!("- reservation {} {}", name, addr)),
+                Some(wanted) if wanted != addr => plan.push(format!("~ reservation {}: {} -> {}", name, addr, wanted)),
+                _ => {}
+            }
+        }
+        for (name, addr) in self.reservations.iter() {
+            if !state.reservations.contains_key(name) {
+                plan.push(format!("+ reservation {} {}", name, addr));
+            }
+        }
+        for acl in state.acls.iter() {
+            if !self.acls.contains(acl) {
+                plan.push(format!("- acl {}", acl_line(acl)));
+            }
+        }
+        for acl in self.acls.iter() {
+            if !state.acls.contains(acl) {
+                plan.push(format!("+ acl {}", acl_line(acl)));
+            }
+        }
+        plan
+    }
+
+    /// Replace the declared parts of `state` with the manifest.
+    pub fn apply(&self, state: &mut NetworkState) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let gone: Vec<String> = state.members.keys().filter(|n| !self.members.contains_key(*n)).cloned().collect();
+        for name in gone {
+            state.remove_member(&name);
+        }
+        for (name, wanted) in self.members.iter() {
+            match state.members.get_mut(name) {
+                Some(member) => {
+                    if member.public_key != wanted.public_key {
+                        member.public_key = wanted.public_key.clone();
+                        member.key_created_at = now;
+                        member.retired_key = None;
+                    }
+                    member.addrs = wanted.addrs.clone();
+                    member.allowed_ips = wanted.allowed_ips.clone();
+                    member.groups = wanted.groups.clone();
+                    member.tags = wanted.tags.clone();
+                    member.persistent_keepalive = wanted.persistent_keepalive;
+                }
+                None => {
+                    state.members.insert(name.clone(), Member {
+                        public_key: wanted.public_key.clone(),
+                        key_created_at: now,
+                        retired_key: None,
+                        addrs: wanted.addrs.clone(),
+                        routes: vec![],
+                        allowed_ips: wanted.allowed_ips.clone(),
+                        advertised_routes: vec![],
+                        groups: wanted.groups.clone(),
+                        tags: wanted.tags.clone(),
+                        listen_port: None,
+                        internal_endpoint: None,
+                        external_endpoint: None,
+                        persistent_keepalive: wanted.persistent_keepalive,
+                        disabled: false,
+                        exit: None,
+                        services: vec![],
+                        signing_key: None,
+                    });
+                }
+            }
+        }
+        state.reservations = self.reservations.clone();
+        state.acls = self.acls.clone();
+    }
+}
+
+/// `from -> to`, followed by the ports when the rule is limited to some.
+fn acl_line(acl: &AclRule) -> String {
+    if acl.ports.is_empty() {
+        format!("{} -> {}", acl.from, acl.to)
+    } else {
+        format!("{} -> {} {}", acl.from, acl.to, acl.ports.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::Invite;
+
+    #[test]
+    fn test_manifest_plan() {
+        let manifest = NetworkManifest::from_yaml_file(Path::new("example/net.yaml")).unwrap();
+        let mut state = NetworkState::default();
+        let plan = manifest.plan(&state);
+        assert_eq!(plan.iter().filter(|c| c.starts_with("+ member")).count(), manifest.members.len());
+        manifest.apply(&mut state);
+        assert!(manifest.plan(&state).is_empty());
+    }
+
+    #[test]
+    fn test_manifest_duplicate_key() {
+        let mut manifest = NetworkManifest::from_yaml_file(Path::new("example/net.yaml")).unwrap();
+        let mut members = manifest.members.values().cloned();
+        let first = members.next().unwrap();
+        let mut second = members.next().unwrap();
+        second.public_key = first.public_key.clone();
+        second.addrs = vec![];
+        manifest.members.insert("dup".to_string(), second);
+        let err = manifest.validate().unwrap_err();
+        assert!(err.to_string().contains("public key already used"));
+    }
+
+    #[test]
+    fn test_manifest_invite_addr() {
+        let manifest = NetworkManifest::from_yaml_file(Path::new("example/net.yaml")).unwrap();
+        let mut state = NetworkState::default();
+        state.invites.insert("key".to_string(), Invite {
+            name: "phone".to_string(),
+            public_key: Key::generate_private().generate_public().to_base64(),
+            addrs: vec!["10.1.0.10/16".parse().unwrap()],
+            created_at: 0,
+            expires_at: u64::MAX,
+            created_by: "admin".to_string(),
+        });
+        let err = manifest.check_addrs(&state, &[]).unwrap_err();
+        assert!(err.to_string().contains("the invite of phone"));
+        state.invites.clear();
+        let err = manifest.check_addrs(&state, &["10.1.0.100".parse().unwrap()]).unwrap_err();
+        assert!(err.to_string().contains("reservation printer"));
+        assert!(manifest.check_addrs(&state, &["10.1.0.254".parse().unwrap()]).is_ok());
+    }
+
+    #[test]
+    fn test_manifest_plan_acl_ports() {
+        let manifest = NetworkManifest::from_yaml_file(Path::new("example/net.yaml")).unwrap();
+        let plan = manifest.plan(&NetworkState::default());
+        assert!(plan.contains(&"+ acl group:dev -> tag:db tcp/5432".to_string()));
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use crate::config::wg::Endpoint;
+use crate::utils::write_atomic;
+use std::path::Path;
+use ipnet::IpNet;
+use serde::{Serialize, Deserialize};
+
+/// Declarative description of a static network, rendered offline by `wgnet render`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MeshConfig {
+    pub network: String,
+    pub iface_name: String,
+    #[serde(default)]
+    pub topology: Topology,
+    // members every other member connects to, required by the `hub` topology
+    #[serde(default)]
+    pub hubs: Vec<String>,
+    pub mtu: Option<u32>,
+    pub persistent_keepalive: Option<u16>,
+    pub members: BTreeMap<String, MeshMember>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    /// every member peers with every other member
+    #[default]
+    Mesh,
+    /// members only peer with the hubs, hubs peer with everyone
+    Hub,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MeshMember {
+    pub addrs: Vec<IpNet>,
+    pub endpoint: Option<Endpoint>,
+    pub listen_port: Option<u16>,
+    // prefixes routed behind the member
+    #[serde(default)]
+    pub allowed_ips: Vec<IpNet>,
+}
+
+/// Keys generated by previous renders, kept so re-rendering gives the same configs.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct MeshKeys {
+    // member: private key
+    pub private_keys: BTreeMap<String, String>,
+    // "a,b" with a < b: preshared key
+    pub preshared_keys: BTreeMap<String, String>,
+}
+
+impl MeshConfig {
+    pub fn from_yaml_file(path: &Path) -> Result<Self, io::Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(path)?;
+        let mut yaml_str = String::new();
+        file.read_to_string(&mut yaml_str)?;
+        let config = serde_yaml::from_str(&yaml_str)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
+}
+
+impl MeshKeys {
+    pub fn from_yaml_file(path: &Path) -> Result<Self, io::Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(path)?;
+        let mut yaml_str = String::new();
+        file.read_to_string(&mut yaml_str)?;
+        let keys = serde_yaml::from_str(&yaml_str)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(keys)
+    }
+
+    pub fn to_yaml_file(&self, path: &Path) -> Result<(), io::Error> {
+        let yaml_str = serde_yaml::to_string(&self).unwrap();
+        // private keys, keep them to the owner
+        write_atomic(path, yaml_str.as_bytes(), 0o600)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mesh_config() {
+        let config = MeshConfig::from_yaml_file(Path::new("example/mesh.yaml")).unwrap();
+        assert_eq!(config.topology, Topology::Hub);
+        assert_eq!(config.hubs, vec!["gateway".to_string()]);
+        assert_eq!(config.members.len(), 3);
+    }
+}
@@ -2,3 +2,7 @@ pub mod wg;
 pub mod client;
 pub mod invite;
 pub mod server;
+pub mod mesh;
+pub mod manifest;
+pub mod policy;
+pub mod joined;
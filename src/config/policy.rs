@@ -0,0 +1,53 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+
+use crate::policy::validate_acl;
+use crate::state::AclRule;
+
+/// Who may reach whom, set with `wgnet admin policy set`.
+/// Unlike a manifest, it only replaces the acls of the network.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Default)]
+pub struct PolicyConfig {
+    #[serde(default)]
+    pub acls: Vec<AclRule>,
+}
+
+impl PolicyConfig {
+    pub fn from_yaml_file(path: &Path) -> Result<Self, io::Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(path)?;
+        let mut yaml_str = String::new();
+        file.read_to_string(&mut yaml_str)?;
+        Self::from_yaml_str(&yaml_str)
+    }
+
+    pub fn from_yaml_str(yaml_str: &str) -> Result<Self, io::Error> {
+        let policy: Self = serde_yaml::from_str(yaml_str)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        for acl in policy.acls.iter() {
+            validate_acl(acl).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(policy)
+    }
+
+    pub fn to_yaml_string(&self) -> String {
+        serde_yaml::to_string(&self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_policy_config() {
+        let policy = PolicyConfig::from_yaml_file(Path::new("example/policy.yaml")).unwrap();
+        assert_eq!(PolicyConfig::from_yaml_str(&policy.to_yaml_string()).unwrap(), policy);
+        assert!(PolicyConfig::from_yaml_str("acls:\n  - from: team:dev\n    to: \"*\"\n").is_err());
+        assert!(PolicyConfig::from_yaml_str("acls:\n  - from: \"*\"\n    to: \"*\"\n    ports: [tcp/http]\n").is_err());
+    }
+}
@@ -1,16 +1,78 @@
+use std::collections::HashMap;
 use std::fs::OpenOptions;
 use std::io;
-use std::io::{Read, Write};
-use std::net::SocketAddr;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
-use serde::{Serialize, Deserialize, Serializer};
-use wireguard_control::Backend;
+use ipnet::IpNet;
+use serde::{Serialize, Deserialize};
+use crate::config::wg::Endpoint;
+use crate::utils::write_atomic;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ServerConfig {
     pub listen: SocketAddr,
     pub iface_config_path: String,
     pub backend: String,
+    // name: token
+    #[serde(default)]
+    pub admins: HashMap<String, String>,
+    // "yaml", "json" or "sqlite", kept under the data directory
+    #[serde(default = "default_store")]
+    pub store: String,
+    // seconds a rotated key still authenticates with the server, peers switch to the new key right away
+    #[serde(default = "default_key_grace")]
+    pub key_grace: u64,
+    // seconds before members are asked to rotate their key, never by default
+    #[serde(default)]
+    pub max_key_age: Option<u64>,
+    // seconds before the preshared key of each pair of members is replaced, never by default.
+    // The next key reaches both members ahead and they switch to it together.
+    #[serde(default)]
+    pub psk_rotation: Option<u64>,
+    // routes advertised by members inside these prefixes need no admin approval
+    #[serde(default)]
+    pub auto_approve_routes: Vec<IpNet>,
+    // resolvers and search domains pushed to the members, DNS = of the server iface by default
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    #[serde(default)]
+    pub search: Vec<String>,
+    // rpc sockets of all servers of the network, handed to the members to fail over to,
+    // only this one by default
+    #[serde(default)]
+    pub servers: Vec<Endpoint>,
+    // rpc socket of the server this one replicates. A follower is a read-only failover: it serves
+    // the peers while the leader is down, but admin changes, invite redemptions, key rotations and
+    // route advertisements are forwarded and fail until the leader is back. Nothing promotes a
+    // follower on its own, removing its leader and restarting it makes it the leader.
+    #[serde(default)]
+    pub leader: Option<Endpoint>,
+    // shared by the servers, the state is only replicated to those presenting it
+    #[serde(default)]
+    pub replication_token: Option<String>,
+    // pem certificate and key of the rpc service, generated self-signed when missing. Members
+    // get the certificate with their invite, the servers of a network share the pair.
+    #[serde(default = "default_tls_cert")]
+    pub tls_cert: String,
+    #[serde(default = "default_tls_key")]
+    pub tls_key: String,
+}
+
+fn default_store() -> String {
+    "yaml".to_string()
+}
+
+fn default_key_grace() -> u64 {
+    300
+}
+
+fn default_tls_cert() -> String {
+    "/etc/wgnet/rpc.crt".to_string()
+}
+
+fn default_tls_key() -> String {
+    "/etc/wgnet/rpc.key".to_string()
 }
 
 impl ServerConfig {
@@ -25,13 +87,9 @@ impl ServerConfig {
     }
 
     pub fn to_yaml_file(&self, path: &Path) -> Result<(), io::Error> {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(path)?;
         let yaml_str = serde_yaml::to_string(&self).unwrap();
-        file.write_all(yaml_str.as_bytes())?;
-        Ok(())
+        // admin tokens, keep them to the owner
+        write_atomic(path, yaml_str.as_bytes(), 0o600)
     }
 }
 
@@ -1,56 +1,118 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
 use std::io;
-use serde::{Serialize, Deserialize};
+use std::path::Path;
+use serde::{Serialize, Serializer, Deserialize};
 use ipnet::IpNet;
-use std::net::{AddrParseError, IpAddr, SocketAddr, ToSocketAddrs};
-use std::str::{FromStr, Split};
-use tonic;
-
-// #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-// pub enum HostEnum {
-//     Ip(IpAddr),
-//     Hostname(String),
-// }
-//
-// impl FromStr for HostEnum {
-//     type Err = io::Error;
-//
-//     fn from_str(s: &str) -> Result<Self, Self::Err> {
-//         Ok(match IpAddr::from_str(s) {
-//             Ok(ip) => Self::Ip(ip),
-//             Err(_) => Self::Hostname(s.to_string()),
-//         })
-//     }
-// }
-//
-// impl ToString for HostEnum {
-//     fn to_string(&self) -> String {
-//         match self {
-//             Self::Ip(ip) => ip.to_string(),
-//             Self::Hostname(h) => h.clone(),
-//         }
-//     }
-// }
-//
-// #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-// pub struct Endpoint {
-//     host: HostEnum,
-//     port: u16,
-// }
-//
-// impl FromStr for Endpoint {
-//     type Err = io::Error;
-//
-//     fn from_str(s: &str) -> Result<Self, Self::Err> {
-//         todo!()
-//     }
-// }
-//
-// impl ToString for Endpoint {
-//     fn to_string(&self) -> String {
-//         format!("{}:{}", self.host.to_string(), self.port)
-//     }
-// }
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::lookup_host;
+use tokio::time::timeout;
+
+// how long a hostname lookup may take before it counts as failed
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum HostEnum {
+    Ip(IpAddr),
+    Hostname(String),
+}
+
+impl FromStr for HostEnum {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match IpAddr::from_str(s) {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::Hostname(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for HostEnum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{}", ip),
+            Self::Hostname(h) => write!(f, "{}", h),
+        }
+    }
+}
+
+/// `ip:port` or `hostname:port`, hostnames being resolved when the endpoint is used.
+/// Serialized as a string, so plain socket addresses read as before.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub host: HostEnum,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn is_hostname(&self) -> bool {
+        matches!(self.host, HostEnum::Hostname(_))
+    }
+
+    /// The address of the endpoint, looked up without blocking when it is a hostname.
+    pub async fn resolve(&self) -> Result<SocketAddr, io::Error> {
+        match &self.host {
+            HostEnum::Ip(ip) => Ok(SocketAddr::new(*ip, self.port)),
+            HostEnum::Hostname(h) => timeout(RESOLVE_TIMEOUT, lookup_host((h.as_str(), self.port))).await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("looking up {} timed out", h)))??
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", h))),
+        }
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Self {
+        Endpoint { host: HostEnum::Ip(addr.ip()), port: addr.port() }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = SocketAddr::from_str(s) {
+            return Ok(addr.into());
+        }
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid endpoint {}, expected host:port", s));
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        // bare ipv6 addresses without brackets are not hostnames either
+        if host.is_empty() || host.contains(':') || host.contains(char::is_whitespace) {
+            return Err(invalid());
+        }
+        Ok(Endpoint {
+            host: HostEnum::from_str(host)?,
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host {
+            HostEnum::Ip(ip) => write!(f, "{}", SocketAddr::new(ip, self.port)),
+            HostEnum::Hostname(_) => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Serialize for Endpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Endpoint::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct InterfaceConfig {
@@ -60,21 +122,177 @@ pub struct InterfaceConfig {
     pub listen_port: Option<u16>,
     pub mtu: Option<u32>,
     pub internal_endpoint: Option<SocketAddr>,
-    pub external_endpoint: Option<SocketAddr>,
+    pub external_endpoint: Option<Endpoint>,
     // pub peers: Vec<PeerConfig>,
+    #[serde(serialize_with = "ordered_map")]
     pub peers: HashMap<String, PeerConfig>,  // name: peer
+    // resolvers and search domains of the network, wg-quick's DNS =
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    #[serde(default)]
+    pub search: Vec<String>,
+}
+
+// keep serialized peers in a stable order
+fn ordered_map<S: Serializer>(peers: &HashMap<String, PeerConfig>, serializer: S) -> Result<S::Ok, S::Error> {
+    peers.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct PeerConfig {
     pub public_key: String,
-    pub endpoint: Option<SocketAddr>,
+    pub endpoint: Option<Endpoint>,
     pub allowed_ips: Vec<IpNet>,
     pub preshared_key: Option<String>,
     pub persistent_keepalive: Option<u16>,
+    #[serde(default)]
+    pub next_preshared_key: Option<NextPresharedKey>,
+}
+
+/// Preshared key announced ahead of a rotation. Both members of the pair switch to it at
+/// `switch_at`, unix seconds, so their handshakes never see different keys for long.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NextPresharedKey {
+    pub key: String,
+    pub switch_at: u64,
+}
+
+impl InterfaceConfig {
+    /// Render as a wg-quick configuration file, peers sorted by name.
+    pub fn to_wg_quick(&self) -> String {
+        let join = |ips: &Vec<IpNet>| ips.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(", ");
+        let mut s = String::new();
+        writeln!(s, "[Interface]").unwrap();
+        writeln!(s, "PrivateKey = {}", self.private_key).unwrap();
+        if !self.addrs.is_empty() {
+            writeln!(s, "Address = {}", join(&self.addrs)).unwrap();
+        }
+        if let Some(port) = self.listen_port {
+            writeln!(s, "ListenPort = {}", port).unwrap();
+        }
+        if let Some(mtu) = self.mtu {
+            writeln!(s, "MTU = {}", mtu).unwrap();
+        }
+        if !self.dns.is_empty() || !self.search.is_empty() {
+            let dns: Vec<String> = self.dns.iter().map(|d| d.to_string()).chain(self.search.iter().cloned()).collect();
+            writeln!(s, "DNS = {}", dns.join(", ")).unwrap();
+        }
+        let names: BTreeMap<_, _> = self.peers.iter().collect();
+        for (name, peer) in names {
+            writeln!(s).unwrap();
+            writeln!(s, "[Peer]").unwrap();
+            writeln!(s, "# {}", name).unwrap();
+            writeln!(s, "PublicKey = {}", peer.public_key).unwrap();
+            if let Some(psk) = &peer.preshared_key {
+                writeln!(s, "PresharedKey = {}", psk).unwrap();
+            }
+            writeln!(s, "AllowedIPs = {}", join(&peer.allowed_ips)).unwrap();
+            if let Some(endpoint) = &peer.endpoint {
+                writeln!(s, "Endpoint = {}", endpoint).unwrap();
+            }
+            if let Some(keepalive) = peer.persistent_keepalive {
+                writeln!(s, "PersistentKeepalive = {}", keepalive).unwrap();
+            }
+        }
+        s
+    }
+
+    /// Parse a wg-quick configuration file, the interface is named after the file.
+    /// Peers are named by a `# name` comment inside their section, or by public key.
+    pub fn from_wg_quick_file(path: &Path) -> Result<Self, io::Error> {
+        let name = path.file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no interface name"))?;
+        Self::from_wg_quick(&name, &fs::read_to_string(path)?)
+    }
+
+    pub fn from_wg_quick(name: &str, s: &str) -> Result<Self, io::Error> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let ips = |v: &str| -> Result<Vec<IpNet>, io::Error> {
+            v.split(',')
+                .map(|ip| IpNet::from_str(ip.trim()).map_err(|e| invalid(e.to_string())))
+                .collect()
+        };
+        let mut config = InterfaceConfig {
+            name: name.to_string(),
+            private_key: String::new(),
+            addrs: vec![],
+            listen_port: None,
+            mtu: None,
+            internal_endpoint: None,
+            external_endpoint: None,
+            peers: HashMap::new(),
+            dns: vec![],
+            search: vec![],
+        };
+        // (comment name, peer) of the section being parsed, None while in [Interface]
+        let mut peer: Option<(Option<String>, PeerConfig)> = None;
+        let mut peers = vec![];
+        for line in s.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            if line == "[Interface]" {
+                continue;
+            }
+            if line == "[Peer]" {
+                peers.extend(peer.take());
+                peer = Some((None, PeerConfig {
+                    public_key: String::new(),
+                    endpoint: None,
+                    allowed_ips: vec![],
+                    preshared_key: None,
+                    persistent_keepalive: None,
+                    next_preshared_key: None,
+                }));
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some((peer_name @ None, _)) = peer.as_mut() {
+                    *peer_name = Some(comment.trim().to_string());
+                }
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .ok_or_else(|| invalid(format!("invalid line: {}", line)))?;
+            match peer.as_mut() {
+                None => match key {
+                    "PrivateKey" => config.private_key = value.to_string(),
+                    "Address" => config.addrs.extend(ips(value)?),
+                    "ListenPort" => config.listen_port = Some(value.parse().map_err(|_| invalid(format!("invalid port: {}", value)))?),
+                    "MTU" => config.mtu = Some(value.parse().map_err(|_| invalid(format!("invalid mtu: {}", value)))?),
+                    // addresses are resolvers, anything else a search domain
+                    "DNS" => for d in value.split(',').map(|d| d.trim()) {
+                        match IpAddr::from_str(d) {
+                            Ok(ip) => config.dns.push(ip),
+                            Err(_) => config.search.push(d.to_string()),
+                        }
+                    },
+                    _ => log::warn!("Ignoring wg-quick interface option {}", key),
+                },
+                Some((_, p)) => match key {
+                    "PublicKey" => p.public_key = value.to_string(),
+                    "PresharedKey" => p.preshared_key = Some(value.to_string()),
+                    "AllowedIPs" => p.allowed_ips.extend(ips(value)?),
+                    "Endpoint" => p.endpoint = Some(Endpoint::from_str(value)?),
+                    "PersistentKeepalive" => p.persistent_keepalive = Some(value.parse().map_err(|_| invalid(format!("invalid keepalive: {}", value)))?),
+                    _ => log::warn!("Ignoring wg-quick peer option {}", key),
+                },
+            }
+        }
+        peers.extend(peer);
+        if config.private_key.is_empty() {
+            return Err(invalid(format!("interface {} has no private key", name)));
+        }
+        for (peer_name, p) in peers {
+            if p.public_key.is_empty() {
+                return Err(invalid(format!("peer {:?} has no public key", peer_name)));
+            }
+            config.peers.insert(peer_name.unwrap_or(p.public_key.clone()), p);
+        }
+        Ok(config)
+    }
 }
 
-/// converting between gRPC config
+// converting between gRPC config
 
 use crate::api::proto;
 
@@ -83,13 +301,22 @@ impl InterfaceConfig {
         let c = InterfaceConfig {
             name: config.name.clone(),
             private_key: config.private_key.clone(),
-            addrs: config.addrs.iter().map(|a| IpNet::from_str(&a).unwrap()).collect(),
+            addrs: config.addrs.iter()
+                .map(|a| IpNet::from_str(a).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+                .collect::<Result<_, _>>()?,
             listen_port: config.listen_port.map(|p| p as u16),
             mtu: config.mtu,
-            internal_endpoint: config.internal_endpoint.as_ref().map(|e| SocketAddr::from_str(&e).unwrap()),
-            external_endpoint: config.external_endpoint.as_ref().map(|e| SocketAddr::from_str(&e).unwrap()),
-            // peers: config.peers.iter().map(|p| PeerConfig::from_proto_peer(p).unwrap()).collect(),
-            peers: config.peers.iter().map(|(k, v)| { (k.clone(), PeerConfig::from_proto_peer(v).unwrap()) }).collect(),
+            internal_endpoint: config.internal_endpoint.as_ref()
+                .map(|e| SocketAddr::from_str(e).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+                .transpose()?,
+            external_endpoint: config.external_endpoint.as_ref().map(|e| Endpoint::from_str(e)).transpose()?,
+            peers: config.peers.iter()
+                .map(|(k, v)| Ok((k.clone(), PeerConfig::from_proto_peer(v)?)))
+                .collect::<Result<_, io::Error>>()?,
+            dns: config.dns.iter()
+                .map(|d| IpAddr::from_str(d).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+                .collect::<Result<_, _>>()?,
+            search: config.search.clone(),
         };
         Ok(c)
     }
@@ -102,9 +329,10 @@ impl InterfaceConfig {
             listen_port: self.listen_port.map(|p| p as u32),
             mtu: self.mtu,
             internal_endpoint: self.internal_endpoint.map(|e| e.to_string()),
-            external_endpoint: self.external_endpoint.map(|e| e.to_string()),
-            // peers: self.peers.iter().map(|p| p.to_proto_peer().unwrap()).collect(),
+            external_endpoint: self.external_endpoint.as_ref().map(|e| e.to_string()),
             peers: self.peers.iter().map(|(k, v)| { (k.clone(), v.to_proto_peer().unwrap()) }).collect(),
+            dns: self.dns.iter().map(|d| d.to_string()).collect(),
+            search: self.search.clone(),
         };
         Ok(c)
     }
@@ -114,10 +342,16 @@ impl PeerConfig {
     pub fn from_proto_peer(config: &proto::PeerConfig) -> Result<Self, io::Error> {
         let c = PeerConfig {
             public_key: config.public_key.clone(),
-            endpoint: config.endpoint.as_ref().map(|e| SocketAddr::from_str(&e).unwrap()),
-            allowed_ips: config.allowed_ips.iter().map(|a| IpNet::from_str(&a).unwrap()).collect(),
+            endpoint: config.endpoint.as_ref().map(|e| Endpoint::from_str(e)).transpose()?,
+            allowed_ips: config.allowed_ips.iter()
+                .map(|a| IpNet::from_str(a).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+                .collect::<Result<_, _>>()?,
             preshared_key: config.preshared_key.clone(),
             persistent_keepalive: config.persistent_keepalive.map(|p| p as u16),
+            next_preshared_key: config.next_preshared_key.as_ref().map(|key| NextPresharedKey {
+                key: key.clone(),
+                switch_at: config.next_preshared_key_at,
+            }),
         };
         Ok(c)
     }
@@ -125,11 +359,89 @@ impl PeerConfig {
     pub fn to_proto_peer(&self) -> Result<proto::PeerConfig, io::Error> {
         let c = proto::PeerConfig {
             public_key: self.public_key.clone(),
-            endpoint: self.endpoint.map(|e| e.to_string()),
+            endpoint: self.endpoint.as_ref().map(|e| e.to_string()),
             allowed_ips: self.allowed_ips.iter().map(|addr| addr.to_string()).collect(),
             preshared_key: self.preshared_key.clone(),
             persistent_keepalive: self.persistent_keepalive.map(|p| p as u32),
+            next_preshared_key: self.next_preshared_key.as_ref().map(|k| k.key.clone()),
+            next_preshared_key_at: self.next_preshared_key.as_ref().map_or(0, |k| k.switch_at),
         };
         Ok(c)
     }
+
+    /// Take the next preshared key when it is due at `now`, returns whether it was.
+    pub fn switch_psk(&mut self, now: u64) -> bool {
+        match self.next_preshared_key.take_if(|k| k.switch_at <= now) {
+            Some(next) => {
+                self.preshared_key = Some(next.key);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use wireguard_control::Key;
+
+    #[test]
+    fn test_wg_quick() {
+        let config = InterfaceConfig {
+            name: "wg0".to_string(),
+            private_key: Key::generate_private().to_base64(),
+            addrs: vec!["10.1.1.1/16".parse().unwrap(), "fd01:1::1/64".parse().unwrap()],
+            listen_port: Some(51820),
+            mtu: Some(1420),
+            internal_endpoint: None,
+            external_endpoint: None,
+            peers: HashMap::from([
+                ("peer1".to_string(), PeerConfig {
+                    public_key: Key::generate_private().generate_public().to_base64(),
+                    endpoint: Some("vpn.example.com:51820".parse().unwrap()),
+                    allowed_ips: vec!["10.1.0.0/16".parse().unwrap(), "fd01::/64".parse().unwrap()],
+                    preshared_key: Some(Key::generate_preshared().to_base64()),
+                    persistent_keepalive: Some(25),
+                    next_preshared_key: None,
+                }),
+            ]),
+            dns: vec!["10.1.0.1".parse().unwrap()],
+            search: vec!["corp.example".to_string()],
+        };
+        let config2 = InterfaceConfig::from_wg_quick("wg0", &config.to_wg_quick()).unwrap();
+        assert_eq!(config, config2);
+    }
+
+    #[test]
+    fn test_proto_config() {
+        let mut proto = proto::InterfaceConfig {
+            name: "wg0".to_string(),
+            addrs: vec!["10.1.1.1/16".to_string()],
+            peers: HashMap::from([("peer1".to_string(), proto::PeerConfig {
+                allowed_ips: vec!["10.1.0.0/16".to_string()],
+                ..Default::default()
+            })]),
+            ..Default::default()
+        };
+        assert!(InterfaceConfig::from_proto_config(&proto).is_ok());
+        proto.peers.get_mut("peer1").unwrap().allowed_ips.push("10.1.0.0/33".to_string());
+        assert!(InterfaceConfig::from_proto_config(&proto).is_err());
+        proto.peers.get_mut("peer1").unwrap().allowed_ips.pop();
+        proto.addrs.push("10.1.1.300/16".to_string());
+        assert!(InterfaceConfig::from_proto_config(&proto).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_endpoint() {
+        let e = Endpoint::from_str("[fd01::1]:51820").unwrap();
+        assert_eq!(e.resolve().await.unwrap(), "[fd01::1]:51820".parse::<SocketAddr>().unwrap());
+        assert_eq!(e.to_string(), "[fd01::1]:51820");
+        let e = Endpoint::from_str("vpn.example.com:51820").unwrap();
+        assert!(e.is_hostname());
+        assert_eq!(e.to_string(), "vpn.example.com:51820");
+        assert_eq!(serde_json::to_string(&e).unwrap(), "\"vpn.example.com:51820\"");
+        assert!(Endpoint::from_str("fd01::1:51820").is_err());
+        assert!(Endpoint::from_str("vpn.example.com").is_err());
+    }
 }
@@ -0,0 +1,287 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use ipnet::IpNet;
+use serde::Serialize;
+use tonic::Request;
+use tonic::transport::Channel;
+use crate::api::proto;
+use crate::client::connect_channel;
+use crate::config::manifest::NetworkManifest;
+use crate::config::policy::PolicyConfig;
+use crate::config::server::ServerConfig;
+use crate::config::wg::Endpoint;
+use crate::utils::format_timestamp;
+
+/// Admin side commands, talking to the server's admin service.
+pub struct Ctl {
+    token: String,
+    admin_client: proto::admin_client::AdminClient<Channel>,
+}
+
+impl Ctl {
+    /// Connect to `server`, checking it presents the certificate at `ca`.
+    pub async fn connect(server: SocketAddr, token: &str, ca: &Path) -> Result<Self, io::Error> {
+        let channel = connect_channel(&Endpoint::from(server), &std::fs::read_to_string(ca)?)?;
+        let admin_client = proto::admin_client::AdminClient::new(channel);
+        Ok(Ctl {
+            token: token.to_string(),
+            admin_client,
+        })
+    }
+
+    /// Connect to the server running on this host with the token of `admin` from its config,
+    /// or of the first admin.
+    pub async fn connect_local(config: &ServerConfig, admin: Option<&str>) -> Result<Self, io::Error> {
+        let mut admins: Vec<_> = config.admins.iter().collect();
+        admins.sort();
+        let token = match admin {
+            Some(name) => config.admins.get(name),
+            None => admins.first().map(|(_, token)| *token),
+        }.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such admin in server config"))?;
+        let mut server = config.listen;
+        if server.ip().is_unspecified() {
+            server.set_ip(match server.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        Self::connect(server, token, Path::new(&config.tls_cert)).await
+    }
+
+    fn request<T>(&self, msg: T) -> Request<T> {
+        let mut req = Request::new(msg);
+        req.metadata_mut().insert("authorization", format!("Bearer {}", self.token).parse().unwrap());
+        req
+    }
+
+    /// Show what applying the manifest at `path` changes, then apply it once confirmed.
+    pub async fn apply(&mut self, path: &Path, yes: bool) -> Result<(), io::Error> {
+        // validate locally before bothering the server
+        NetworkManifest::from_yaml_file(path)?;
+        let manifest = std::fs::read_to_string(path)?;
+        let plan = self.admin_client.apply(self.request(proto::ApplyRequest {
+            manifest: manifest.clone(),
+            dry_run: true,
+            revision: None,
+        })).await.map_err(status_to_io)?.into_inner();
+        if plan.plan.is_empty() {
+            println!("No changes, network is at revision {}", plan.revision);
+            return Ok(());
+        }
+        println!("Plan against revision {}:", plan.revision);
+        for change in plan.plan.iter() {
+            println!("  {}", change);
+        }
+        if !yes && !confirm("Apply these changes?")? {
+            println!("Aborted");
+            return Ok(());
+        }
+        let resp = self.admin_client.apply(self.request(proto::ApplyRequest {
+            manifest,
+            dry_run: false,
+            revision: Some(plan.revision),
+        })).await.map_err(status_to_io)?.into_inner();
+        println!("Applied {} changes, network is at revision {}", resp.plan.len(), resp.revision);
+        Ok(())
+    }
+
+    pub async fn history(&mut self) -> Result<(), io::Error> {
+        let resp = self.admin_client.history(self.request(proto::HistoryRequest {}))
+            .await.map_err(status_to_io)?.into_inner();
+        println!("{:>8}  {:<23}  {:<16}  CHANGE", "REVISION", "TIME", "AUTHOR");
+        for r in resp.revisions.iter().rev() {
+            println!("{:>8}  {:<23}  {:<16}  {}", r.revision, format_timestamp(r.timestamp), r.author, r.message);
+        }
+        Ok(())
+    }
+
+    pub async fn rollback(&mut self, revision: u64) -> Result<(), io::Error> {
+        let resp = self.admin_client.rollback(self.request(proto::RollbackRequest { revision }))
+            .await.map_err(status_to_io)?.into_inner();
+        println!("Rolled back to revision {}, network is at revision {}", revision, resp.revision);
+        Ok(())
+    }
+
+    pub async fn list_members(&mut self, json: bool) -> Result<(), io::Error> {
+        let resp = self.admin_client.list_members(self.request(proto::ListMembersRequest {}))
+            .await.map_err(status_to_io)?.into_inner();
+        if json {
+            return print_json(&resp.members);
+        }
+        println!("{:<16}  {:<44}  {:<28}  {:<22}  STATUS", "NAME", "PUBLIC KEY", "ADDRS", "ENDPOINT");
+        for m in resp.members.iter() {
+            let endpoint = m.external_endpoint.as_ref().or(m.internal_endpoint.as_ref()).map_or("-", |e| e.as_str());
+            println!("{:<16}  {:<44}  {:<28}  {:<22}  {}",
+                     m.name, m.public_key, m.addrs.join(","), endpoint, if m.disabled { "disabled" } else { "enabled" });
+        }
+        Ok(())
+    }
+
+    pub async fn show_member(&mut self, name: &str, json: bool) -> Result<(), io::Error> {
+        let m = self.admin_client.get_member(self.request(proto::GetMemberRequest { name: name.to_string() }))
+            .await.map_err(status_to_io)?.into_inner();
+        if json {
+            return print_json(&m);
+        }
+        let none = || "-".to_string();
+        println!("name:                 {}", m.name);
+        println!("public key:           {}", m.public_key);
+        println!("addrs:                {}", m.addrs.join(", "));
+        println!("allowed ips:          {}", m.allowed_ips.join(", "));
+        println!("groups:               {}", m.groups.join(", "));
+        println!("tags:                 {}", m.tags.join(", "));
+        println!("persistent keepalive: {}", m.persistent_keepalive.map_or_else(none, |k| k.to_string()));
+        println!("internal endpoint:    {}", m.internal_endpoint.unwrap_or_else(none));
+        println!("external endpoint:    {}", m.external_endpoint.unwrap_or_else(none));
+        println!("disabled:             {}", m.disabled);
+        println!("exit node:            {}", m.exit_node);
+        println!("exit:                 {}", m.exit.unwrap_or_else(none));
+        Ok(())
+    }
+
+    pub async fn remove_member(&mut self, name: &str, yes: bool) -> Result<(), io::Error> {
+        if !yes && !confirm(&format!("Remove member {} from the network?", name))? {
+            println!("Aborted");
+            return Ok(());
+        }
+        self.admin_client.remove_member(self.request(proto::RemoveMemberRequest { name: name.to_string() }))
+            .await.map_err(status_to_io)?;
+        println!("Member {} removed", name);
+        Ok(())
+    }
+
+    pub async fn set_member_disabled(&mut self, name: &str, disabled: bool) -> Result<(), io::Error> {
+        self.admin_client.set_member_disabled(self.request(proto::SetMemberDisabledRequest {
+            name: name.to_string(),
+            disabled,
+        })).await.map_err(status_to_io)?;
+        println!("Member {} {}", name, if disabled { "disabled" } else { "enabled" });
+        Ok(())
+    }
+
+    pub async fn set_member_key(&mut self, name: &str, public_key: &str) -> Result<(), io::Error> {
+        self.admin_client.update_member(self.request(proto::UpdateMemberRequest {
+            name: name.to_string(),
+            public_key: Some(public_key.to_string()),
+            ..Default::default()
+        })).await.map_err(status_to_io)?;
+        println!("Member {} has a new key, its previous keys stop working", name);
+        Ok(())
+    }
+
+    pub async fn list_invites(&mut self, json: bool) -> Result<(), io::Error> {
+        let resp = self.admin_client.list_invites(self.request(proto::ListInvitesRequest {}))
+            .await.map_err(status_to_io)?.into_inner();
+        if json {
+            return print_json(&resp.invites);
+        }
+        println!("{:<8}  {:<16}  {:<28}  {:<23}  CREATED BY", "ID", "NAME", "ADDRS", "EXPIRES");
+        for i in resp.invites.iter() {
+            println!("{:<8}  {:<16}  {:<28}  {:<23}  {}",
+                     i.id, i.name, i.addrs.join(","), format_timestamp(i.expires_at), i.created_by);
+        }
+        Ok(())
+    }
+
+    pub async fn create_invite(&mut self, name: &str, addrs: &[IpNet], ttl: u64, json: bool) -> Result<(), io::Error> {
+        let resp = self.admin_client.create_invite(self.request(proto::CreateInviteRequest {
+            name: name.to_string(),
+            addrs: addrs.iter().map(|a| a.to_string()).collect(),
+            ttl,
+        })).await.map_err(status_to_io)?.into_inner();
+        if json {
+            return print_json(&resp);
+        }
+        let invite = resp.invite.unwrap_or_default();
+        println!("Invite {} for {} ({}), expires {}", invite.id, invite.name, invite.addrs.join(", "), format_timestamp(invite.expires_at));
+        println!("Run on the new member: wgnet client --init {}", resp.code);
+        Ok(())
+    }
+
+    pub async fn revoke_invite(&mut self, id: &str) -> Result<(), io::Error> {
+        self.admin_client.revoke_invite(self.request(proto::RevokeInviteRequest { id: id.to_string() }))
+            .await.map_err(status_to_io)?;
+        println!("Invite {} revoked", id);
+        Ok(())
+    }
+
+    pub async fn list_routes(&mut self, json: bool) -> Result<(), io::Error> {
+        let resp = self.admin_client.list_routes(self.request(proto::ListRoutesRequest {}))
+            .await.map_err(status_to_io)?.into_inner();
+        if json {
+            return print_json(&resp.routes);
+        }
+        println!("{:<16}  {:<43}  STATUS", "MEMBER", "PREFIX");
+        for r in resp.routes.iter() {
+            println!("{:<16}  {:<43}  {}", r.member, r.prefix, if r.approved { "approved" } else { "pending" });
+        }
+        Ok(())
+    }
+
+    pub async fn approve_route(&mut self, member: &str, prefix: &IpNet) -> Result<(), io::Error> {
+        self.admin_client.approve_route(self.request(proto::ApproveRouteRequest {
+            member: member.to_string(),
+            prefix: prefix.to_string(),
+        })).await.map_err(status_to_io)?;
+        println!("Route {} of {} approved", prefix, member);
+        Ok(())
+    }
+
+    pub async fn show_policy(&mut self) -> Result<(), io::Error> {
+        let resp = self.admin_client.get_policy(self.request(proto::GetPolicyRequest {}))
+            .await.map_err(status_to_io)?.into_inner();
+        print!("{}", resp.policy);
+        Ok(())
+    }
+
+    pub async fn set_policy(&mut self, path: &Path) -> Result<(), io::Error> {
+        // validate locally before bothering the server
+        PolicyConfig::from_yaml_file(path)?;
+        let resp = self.admin_client.set_policy(self.request(proto::SetPolicyRequest {
+            policy: std::fs::read_to_string(path)?,
+        })).await.map_err(status_to_io)?.into_inner();
+        println!("Policy set, network is at revision {}", resp.revision);
+        Ok(())
+    }
+
+    pub async fn test_policy(&mut self, from: &str, to: &str, json: bool) -> Result<(), io::Error> {
+        let resp = self.admin_client.test_policy(self.request(proto::TestPolicyRequest {
+            from: from.to_string(),
+            to: to.to_string(),
+        })).await.map_err(status_to_io)?.into_inner();
+        if json {
+            return print_json(&resp);
+        }
+        println!("{} {} reach {}", from, if resp.allowed { "may" } else { "may not" }, to);
+        for line in resp.explanation.iter() {
+            println!("  {}", line);
+        }
+        Ok(())
+    }
+}
+
+pub fn print_json<T: Serialize>(value: &T) -> Result<(), io::Error> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn confirm(question: &str) -> Result<bool, io::Error> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+pub fn status_to_io(status: tonic::Status) -> io::Error {
+    let kind = match status.code() {
+        tonic::Code::NotFound => io::ErrorKind::NotFound,
+        tonic::Code::Unauthenticated | tonic::Code::PermissionDenied => io::ErrorKind::PermissionDenied,
+        tonic::Code::InvalidArgument => io::ErrorKind::InvalidInput,
+        tonic::Code::AlreadyExists => io::ErrorKind::AlreadyExists,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, status.message().to_string())
+}
@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::config::wg::InterfaceConfig;
+use crate::state::Service;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+// members come and go, keep the caches short
+const TTL: u32 = 60;
+
+/// Names of the members of one network, `<member>.<network>.internal`.
+/// The network is named after its interface.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Zone {
+    pub domain: String,
+    // member: addresses
+    pub records: HashMap<String, Vec<IpAddr>>,
+    // (member, service) published in the network, `_<name>._<proto>.<domain>`
+    pub services: Vec<(String, Service)>,
+}
+
+impl Zone {
+    /// Zone of the network of interface `config`, `name` being the member of this host.
+    pub fn from_iface(config: &InterfaceConfig, name: &str) -> Self {
+        let mut records = HashMap::new();
+        // empty in peers cached from servers that did not send the member name
+        if !name.is_empty() {
+            records.insert(name.to_lowercase(), config.addrs.iter().map(|a| a.addr()).collect());
+        }
+        for (peer, p) in config.peers.iter() {
+            // the addresses of the member, not the subnets it routes
+            let addrs = p.allowed_ips.iter()
+                .filter(|ip| ip.prefix_len() == ip.max_prefix_len())
+                .filter(|ip| config.addrs.iter().any(|a| a.contains(&ip.addr())))
+                .map(|ip| ip.addr())
+                .collect();
+            records.insert(peer.to_lowercase(), addrs);
+        }
+        Zone {
+            domain: format!("{}.internal", config.name.to_lowercase()),
+            records,
+            services: vec![],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rdata {
+    Addr(IpAddr),
+    Name(String),
+    Srv { port: u16, target: String },
+    Txt(String),
+}
+
+/// Answer `qname` from `zones`: the records, or the rcode of a failure.
+/// None when the name is not ours and goes to the system resolver.
+pub fn resolve<'a>(zones: impl Iterator<Item = &'a Zone>, qname: &str, qtype: u16) -> Option<Result<Vec<Rdata>, u16>> {
+    let qname = qname.trim_end_matches('.').to_lowercase();
+    for zone in zones {
+        if let Some(member) = qname.strip_suffix(&format!(".{}", zone.domain)) {
+            if let Some((name, proto)) = member.split_once('.').filter(|_| member.starts_with('_')) {
+                let services: Vec<&(String, Service)> = zone.services.iter()
+                    .filter(|(_, s)| name == format!("_{}", s.name) && proto == format!("_{}", s.proto))
+                    .collect();
+                if services.is_empty() {
+                    return Some(Err(RCODE_NXDOMAIN));
+                }
+                return Some(Ok(services.iter().filter_map(|(m, s)| match qtype {
+                    TYPE_SRV => Some(Rdata::Srv { port: s.port, target: format!("{}.{}", m, zone.domain) }),
+                    TYPE_TXT => Some(Rdata::Txt(format!("member={}", m))),
+                    _ => None,
+                }).collect()));
+            }
+            let addrs = match zone.records.get(member) {
+                Some(addrs) => addrs,
+                None => return Some(Err(RCODE_NXDOMAIN)),
+            };
+            return Some(Ok(addrs.iter()
+                .filter(|a| (qtype == TYPE_A && a.is_ipv4()) || (qtype == TYPE_AAAA && a.is_ipv6()))
+                .map(|a| Rdata::Addr(*a))
+                .collect()));
+        }
+        if qtype == TYPE_PTR {
+            for (member, addrs) in zone.records.iter() {
+                if addrs.iter().any(|a| reverse_name(a) == qname) {
+                    return Some(Ok(vec![Rdata::Name(format!("{}.{}", member, zone.domain))]));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Name of `addr` in the in-addr.arpa or ip6.arpa zone.
+pub fn reverse_name(addr: &IpAddr) -> String {
+    match addr {
+        IpAddr::V4(a) => {
+            let o = a.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        }
+        IpAddr::V6(a) => {
+            let mut nibbles: Vec<String> = a.octets().iter()
+                .flat_map(|b| [b >> 4, b & 0xf])
+                .map(|n| format!("{:x}", n))
+                .collect();
+            nibbles.reverse();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub id: u16,
+    pub flags: u16,
+    pub name: String,
+    pub qtype: u16,
+    // end of the question section in the packet
+    question_end: usize,
+}
+
+/// Parse a query with a single question, None for anything else.
+pub fn parse_query(packet: &[u8]) -> Option<Query> {
+    let be = |i: usize| -> Option<u16> { Some(u16::from_be_bytes([*packet.get(i)?, *packet.get(i + 1)?])) };
+    if be(4)? != 1 {
+        return None;
+    }
+    let mut labels = vec![];
+    let mut i = 12;
+    loop {
+        let len = *packet.get(i)? as usize;
+        i += 1;
+        if len == 0 {
+            break;
+        }
+        // questions are never compressed
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(packet.get(i..i + len)?).to_string());
+        i += len;
+    }
+    let qtype = be(i)?;
+    be(i + 2)?;
+    Some(Query {
+        id: be(0)?,
+        flags: be(2)?,
+        name: labels.join("."),
+        qtype,
+        question_end: i + 4,
+    })
+}
+
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+/// Reply to `query` read from `packet` with `answer`.
+pub fn build_reply(packet: &[u8], query: &Query, answer: Result<Vec<Rdata>, u16>) -> Vec<u8> {
+    let (answers, rcode) = match answer {
+        Ok(answers) => (answers, 0),
+        Err(rcode) => (vec![], rcode),
+    };
+    let mut out = vec![];
+    out.extend_from_slice(&query.id.to_be_bytes());
+    // response, authoritative, recursion desired as asked, recursion available
+    let flags = 0x8000 | 0x0400 | (query.flags & 0x0100) | 0x0080 | rcode;
+    out.extend_from_slice(&flags.to_be_bytes());
+    for count in [1, answers.len() as u16, 0, 0] {
+        out.extend_from_slice(&count.to_be_bytes());
+    }
+    out.extend_from_slice(&packet[12..query.question_end]);
+    for rdata in answers {
+        let (rtype, data) = match rdata {
+            Rdata::Addr(IpAddr::V4(a)) => (TYPE_A, a.octets().to_vec()),
+            Rdata::Addr(IpAddr::V6(a)) => (TYPE_AAAA, a.octets().to_vec()),
+            Rdata::Name(name) => {
+                let mut data = vec![];
+                encode_name(&name, &mut data);
+                (TYPE_PTR, data)
+            }
+            Rdata::Srv { port, target } => {
+                // priority and weight, all instances are equal
+                let mut data = vec![0, 0, 0, 0];
+                data.extend_from_slice(&port.to_be_bytes());
+                encode_name(&target, &mut data);
+                (TYPE_SRV, data)
+            }
+            Rdata::Txt(text) => {
+                let mut data = vec![text.len().min(255) as u8];
+                data.extend_from_slice(&text.as_bytes()[..text.len().min(255)]);
+                (TYPE_TXT, data)
+            }
+        };
+        // pointer to the name in the question
+        out.extend_from_slice(&0xc00cu16.to_be_bytes());
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&TTL.to_be_bytes());
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&data);
+    }
+    out
+}
+
+/// First nameserver of /etc/resolv.conf that is not one of `own`, where wgnet itself answers.
+pub fn system_resolver(own: &[IpAddr]) -> Option<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    parse_resolv_conf(&conf, own)
+}
+
+fn parse_resolv_conf(conf: &str, own: &[IpAddr]) -> Option<SocketAddr> {
+    conf.lines()
+        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+        .filter_map(|ns| ns.trim().parse::<IpAddr>().ok())
+        .find(|ip| !own.contains(ip))
+        .map(|ip| SocketAddr::new(ip, 53))
+}
+
+async fn forward(packet: &[u8], upstream: Option<SocketAddr>) -> Result<Vec<u8>, io::Error> {
+    let upstream = upstream.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no system resolver"))?;
+    let bind: SocketAddr = if upstream.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(upstream).await?;
+    socket.send(packet).await?;
+    let mut buf = vec![0u8; 4096];
+    let len = tokio::time::timeout(Duration::from_secs(3), socket.recv(&mut buf)).await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "system resolver timed out"))??;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Answer the member names of `zones` on `listen`, forwarding everything else to `upstream`.
+pub async fn serve(listen: SocketAddr, zones: Arc<RwLock<HashMap<String, Zone>>>, upstream: Option<SocketAddr>) -> Result<(), io::Error> {
+    let socket = Arc::new(UdpSocket::bind(listen).await?);
+    log::info!("DNS listening on {listen}, forwarding to {:?}", upstream);
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let packet = buf[..len].to_vec();
+        let query = match parse_query(&packet) {
+            Some(query) => query,
+            None => continue,
+        };
+        let answer = resolve(zones.read().unwrap().values(), &query.name, query.qtype);
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            let reply = match answer {
+                Some(answer) => build_reply(&packet, &query, answer),
+                None => match forward(&packet, upstream).await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        log::debug!("DNS: failed to forward {}: {e}", query.name);
+                        build_reply(&packet, &query, Err(RCODE_SERVFAIL))
+                    }
+                },
+            };
+            if let Err(e) = socket.send_to(&reply, from).await {
+                log::debug!("DNS: failed to reply to {from}: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        encode_name(name, &mut packet);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_resolve() {
+        let zone = Zone {
+            domain: "wg0.internal".to_string(),
+            records: HashMap::from([
+                ("db".to_string(), vec!["10.1.1.2".parse().unwrap(), "fd01::2".parse().unwrap()]),
+            ]),
+            services: vec![("db".to_string(), Service::parse("postgres:5432").unwrap())],
+        };
+        let zones = [zone];
+        assert_eq!(resolve(zones.iter(), "DB.wg0.internal.", TYPE_A), Some(Ok(vec![Rdata::Addr("10.1.1.2".parse().unwrap())])));
+        assert_eq!(resolve(zones.iter(), "db.wg0.internal", TYPE_AAAA), Some(Ok(vec![Rdata::Addr("fd01::2".parse().unwrap())])));
+        assert_eq!(resolve(zones.iter(), "nas.wg0.internal", TYPE_A), Some(Err(RCODE_NXDOMAIN)));
+        assert_eq!(resolve(zones.iter(), "2.1.1.10.in-addr.arpa", TYPE_PTR), Some(Ok(vec![Rdata::Name("db.wg0.internal".to_string())])));
+        assert!(reverse_name(&"fd01::2".parse().unwrap()).starts_with("2.0.0.0.0.0.0.0."));
+        assert_eq!(resolve(zones.iter(), "example.com", TYPE_A), None);
+        assert_eq!(resolve(zones.iter(), "_postgres._tcp.wg0.internal", TYPE_SRV),
+                   Some(Ok(vec![Rdata::Srv { port: 5432, target: "db.wg0.internal".to_string() }])));
+        assert_eq!(resolve(zones.iter(), "_postgres._udp.wg0.internal", TYPE_SRV), Some(Err(RCODE_NXDOMAIN)));
+
+        let packet = query("db.wg0.internal", TYPE_A);
+        let q = parse_query(&packet).unwrap();
+        assert_eq!((q.id, q.name.as_str(), q.qtype), (0x1234, "db.wg0.internal", TYPE_A));
+        let reply = build_reply(&packet, &q, resolve(zones.iter(), &q.name, q.qtype).unwrap());
+        assert_eq!(&reply[6..8], &[0, 1]);
+        assert_eq!(&reply[reply.len() - 4..], &[10, 1, 1, 2]);
+
+        let conf = "# generated\nnameserver 10.1.1.1\nnameserver 1.1.1.1\n";
+        assert_eq!(parse_resolv_conf(conf, &["10.1.1.1".parse().unwrap()]), Some("1.1.1.1:53".parse().unwrap()));
+    }
+}
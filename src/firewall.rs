@@ -0,0 +1,253 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use ipnet::IpNet;
+
+use crate::api::proto;
+
+/// Traffic coming in through the wgnet interface that is let through.
+/// Everything else arriving on the interface is dropped, replies to our own traffic excepted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FirewallRule {
+    pub sources: Vec<IpNet>,
+    // "tcp", "udp" or "icmp", any protocol when None
+    pub proto: Option<String>,
+    // any port when None
+    pub ports: Option<PortRange>,
+}
+
+/// Inclusive port range.
+pub type PortRange = (u16, u16);
+
+/// Parse an acl port spec: "*", "icmp", "tcp", "tcp/22" or "udp/8000-8100".
+pub fn parse_port_spec(spec: &str) -> Result<(Option<String>, Option<PortRange>), String> {
+    if spec == "*" {
+        return Ok((None, None));
+    }
+    let (proto, ports) = match spec.split_once('/') {
+        Some((proto, ports)) => (proto, Some(ports)),
+        None => (spec, None),
+    };
+    if !matches!(proto, "tcp" | "udp" | "icmp") {
+        return Err(format!("invalid protocol in {}", spec));
+    }
+    let ports = match ports {
+        None => None,
+        Some(_) if proto == "icmp" => return Err(format!("icmp has no ports: {}", spec)),
+        Some(ports) => {
+            let (from, to) = ports.split_once('-').unwrap_or((ports, ports));
+            let parse = |p: &str| p.parse::<u16>().map_err(|_| format!("invalid port in {}", spec));
+            let (from, to) = (parse(from)?, parse(to)?);
+            if from > to {
+                return Err(format!("invalid port range in {}", spec));
+            }
+            Some((from, to))
+        }
+    };
+    Ok((Some(proto.to_string()), ports))
+}
+
+impl FirewallRule {
+    pub fn from_proto_rule(rule: &proto::FirewallRule) -> Result<Self, io::Error> {
+        Ok(FirewallRule {
+            sources: rule.sources.iter()
+                .map(|s| IpNet::from_str(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+                .collect::<Result<Vec<_>, _>>()?,
+            proto: if rule.proto.is_empty() { None } else { Some(rule.proto.clone()) },
+            ports: if rule.port_from == 0 { None } else { Some((rule.port_from as u16, rule.port_to as u16)) },
+        })
+    }
+
+    pub fn to_proto_rule(&self) -> proto::FirewallRule {
+        proto::FirewallRule {
+            sources: self.sources.iter().map(|s| s.to_string()).collect(),
+            proto: self.proto.clone().unwrap_or_default(),
+            port_from: self.ports.map_or(0, |p| p.0 as u32),
+            port_to: self.ports.map_or(0, |p| p.1 as u32),
+        }
+    }
+
+    fn to_nft(&self, iface: &str) -> Vec<String> {
+        let mut lines = vec![];
+        for (family, icmp) in [("ip", "icmp"), ("ip6", "ipv6-icmp")] {
+            let sources: Vec<String> = self.sources.iter()
+                .filter(|s| matches!((family, s), ("ip", IpNet::V4(_)) | ("ip6", IpNet::V6(_))))
+                .map(|s| s.to_string())
+                .collect();
+            if sources.is_empty() {
+                continue;
+            }
+            let mut line = format!("iifname \"{}\" {} saddr {{ {} }}", iface, family, sources.join(", "));
+            match (self.proto.as_deref(), self.ports) {
+                (None, _) => {}
+                (Some("icmp"), _) => line.push_str(&format!(" meta l4proto {}", icmp)),
+                (Some(proto), None) => line.push_str(&format!(" meta l4proto {}", proto)),
+                (Some(proto), Some((from, to))) if from == to => line.push_str(&format!(" {} dport {}", proto, from)),
+                (Some(proto), Some((from, to))) => line.push_str(&format!(" {} dport {}-{}", proto, from, to)),
+            }
+            line.push_str(" accept");
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// The table wgnet owns for interface `iface`, nothing else goes in it.
+pub fn table_name(iface: &str) -> String {
+    format!("wgnet_{}", iface)
+}
+
+/// nft script replacing the table of `iface` with `rules`, in a single transaction.
+pub fn ruleset(iface: &str, rules: &[FirewallRule]) -> String {
+    let table = table_name(iface);
+    let established = format!("iifname \"{}\" ct state established,related accept", iface);
+    // gossip between the members, to this host only, its records carry their own signatures
+    let gossip = format!("iifname \"{}\" udp dport {} accept", iface, crate::gossip::GOSSIP_PORT);
+    let mut lines: Vec<String> = rules.iter().flat_map(|r| r.to_nft(iface)).collect();
+    lines.push(format!("iifname \"{}\" drop", iface));
+    let chain = |name: &str, first: &[&String]| {
+        let mut chain = format!("    chain {} {{\n        type filter hook {} priority 0; policy accept;\n", name, name);
+        for line in first.iter().copied().chain(lines.iter()) {
+            chain.push_str(&format!("        {}\n", line));
+        }
+        chain.push_str("    }\n");
+        chain
+    };
+    // creating the table first makes the delete succeed when it doesn't exist yet
+    format!(
+        "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n{}{}}}\n",
+        chain("input", &[&established, &gossip]),
+        chain("forward", &[&established]),
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub fn apply(iface: &str, rules: &[FirewallRule]) -> Result<(), io::Error> {
+    crate::utils::run_command_with_input("nft", &vec!["-f", "-"], &ruleset(iface, rules))?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn clear(iface: &str) -> Result<(), io::Error> {
+    let table = table_name(iface);
+    crate::utils::run_command_with_input("nft", &vec!["-f", "-"], &format!("table inet {table}\ndelete table inet {table}\n"))?;
+    Ok(())
+}
+
+/// nft script masquerading what the members send through `iface` to other interfaces.
+pub fn masquerade_ruleset(iface: &str) -> String {
+    let table = format!("{}_nat", table_name(iface));
+    format!(
+        "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n    chain postrouting {{\n        \
+         type nat hook postrouting priority 100; policy accept;\n        \
+         iifname \"{iface}\" oifname != \"{iface}\" masquerade\n    }}\n}}\n",
+    )
+}
+
+#[cfg(target_os = "linux")]
+pub fn apply_masquerade(iface: &str) -> Result<(), io::Error> {
+    crate::utils::run_command_with_input("nft", &vec!["-f", "-"], &masquerade_ruleset(iface))?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn clear_masquerade(iface: &str) -> Result<(), io::Error> {
+    let table = format!("{}_nat", table_name(iface));
+    crate::utils::run_command_with_input("nft", &vec!["-f", "-"], &format!("table inet {table}\ndelete table inet {table}\n"))?;
+    Ok(())
+}
+
+/// nft script letting out only the traffic of the tunnel: packets through `iface`, packets of
+/// the device itself carrying `mark`, the flow to the exit `endpoint` and the control plane
+/// connections to the `servers`. `servers` are addresses, resolved before the rules go in.
+/// DHCP and IPv6 neighbor and router discovery stay allowed, or the host loses its address.
+/// Lookups only leave outside the tunnel to the host's own `resolvers`.
+pub fn kill_switch_ruleset(iface: &str, mark: u32, endpoint: Option<SocketAddr>, servers: &[SocketAddr], resolvers: &[IpAddr]) -> String {
+    let table = format!("{}_killswitch", table_name(iface));
+    let mut lines = vec![
+        "oifname \"lo\" accept".to_string(),
+        format!("oifname \"{}\" accept", iface),
+        format!("meta mark {} accept", mark),
+        "udp sport 68 udp dport 67 accept".to_string(),
+        "udp sport 546 udp dport 547 accept".to_string(),
+        "icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept".to_string(),
+    ];
+    let family = |addr: &IpAddr| if addr.is_ipv4() { "ip" } else { "ip6" };
+    if let Some(endpoint) = endpoint {
+        lines.push(format!("{} daddr {} udp dport {} accept", family(&endpoint.ip()), endpoint.ip(), endpoint.port()));
+    }
+    for server in servers {
+        lines.push(format!("{} daddr {} tcp dport {} accept", family(&server.ip()), server.ip(), server.port()));
+    }
+    for resolver in resolvers {
+        lines.push(format!("{} daddr {} meta l4proto {{ tcp, udp }} th dport 53 accept", family(resolver), resolver));
+    }
+    let mut script = format!(
+        "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n    chain output {{\n        \
+         type filter hook output priority 0; policy drop;\n",
+    );
+    for line in lines {
+        script.push_str(&format!("        {}\n", line));
+    }
+    script.push_str("    }\n}\n");
+    script
+}
+
+#[cfg(target_os = "linux")]
+pub fn apply_kill_switch(iface: &str, mark: u32, endpoint: Option<SocketAddr>, servers: &[SocketAddr], resolvers: &[IpAddr]) -> Result<(), io::Error> {
+    crate::utils::run_command_with_input("nft", &vec!["-f", "-"], &kill_switch_ruleset(iface, mark, endpoint, servers, resolvers))?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn clear_kill_switch(iface: &str) -> Result<(), io::Error> {
+    let table = format!("{}_killswitch", table_name(iface));
+    crate::utils::run_command_with_input("nft", &vec!["-f", "-"], &format!("table inet {table}\ndelete table inet {table}\n"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ruleset() {
+        assert_eq!(parse_port_spec("tcp/22").unwrap(), (Some("tcp".to_string()), Some((22, 22))));
+        assert_eq!(parse_port_spec("udp/8000-8100").unwrap(), (Some("udp".to_string()), Some((8000, 8100))));
+        assert_eq!(parse_port_spec("*").unwrap(), (None, None));
+        assert!(parse_port_spec("icmp/1").is_err());
+        assert!(parse_port_spec("sctp/22").is_err());
+
+        let rule = FirewallRule {
+            sources: vec!["10.1.1.2/32".parse().unwrap(), "fd01::2/128".parse().unwrap()],
+            proto: Some("tcp".to_string()),
+            ports: Some((22, 22)),
+        };
+        assert_eq!(FirewallRule::from_proto_rule(&rule.to_proto_rule()).unwrap(), rule);
+        let nft = ruleset("wg0", &[rule]);
+        assert!(nft.contains("table inet wgnet_wg0 {"));
+        assert_eq!(nft.matches("iifname \"wg0\" udp dport 51822 accept").count(), 1);
+        assert!(nft.find("udp dport 51822").unwrap() < nft.find("chain forward").unwrap());
+        assert!(nft.contains("iifname \"wg0\" ip saddr { 10.1.1.2/32 } tcp dport 22 accept"));
+        assert!(nft.contains("iifname \"wg0\" ip6 saddr { fd01::2/128 } tcp dport 22 accept"));
+        assert!(nft.ends_with("        iifname \"wg0\" drop\n    }\n}\n"));
+        assert!(masquerade_ruleset("wg0").contains("iifname \"wg0\" oifname != \"wg0\" masquerade\n"));
+    }
+
+    #[test]
+    fn test_kill_switch_ruleset() {
+        let nft = kill_switch_ruleset(
+            "wg0", 51820, Some("1.2.3.4:51820".parse().unwrap()), &["[fd00::1]:8080".parse().unwrap()], &["192.168.1.1".parse().unwrap()]);
+        assert!(nft.contains("policy drop;\n        oifname \"lo\" accept\n"));
+        assert!(nft.contains("ip daddr 1.2.3.4 udp dport 51820 accept"));
+        assert!(nft.contains("udp sport 68 udp dport 67 accept"));
+        assert!(nft.contains("udp sport 546 udp dport 547 accept"));
+        assert!(nft.contains("icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept"));
+        assert!(nft.contains("ip daddr 192.168.1.1 meta l4proto { tcp, udp } th dport 53 accept"));
+        assert!(nft.contains("ip6 daddr fd00::1 tcp dport 8080 accept"));
+        // no lookup leaves outside the tunnel but to the host's resolvers
+        let nft = kill_switch_ruleset("wg0", 51820, None, &[], &[]);
+        assert!(!nft.contains("daddr"));
+        assert!(!nft.contains("dport 53"));
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use rand::RngCore;
+use serde::{Serialize, Deserialize};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Port the members gossip on, on their addresses inside the network.
+pub const GOSSIP_PORT: u16 = 51822;
+
+/// How often the members gossip.
+pub const GOSSIP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A peer whose last handshake is older than this is not talking to us anymore, a record
+/// may move it. wireguard rejects sessions after the same time.
+pub const STALE_HANDSHAKE: Duration = Duration::from_secs(180);
+
+// records per datagram, keeps them below the mtu of the tunnel
+const MAX_RECORDS: usize = 8;
+
+// a member signs its own endpoint again at most this often, as peers behind different
+// NATs may each see it elsewhere
+const RESIGN_AFTER: u64 = 60;
+
+// peers that must see the same endpoint before a member signs it, a single one could lie
+const MIN_OBSERVERS: usize = 2;
+
+/// Records older than this are dropped, so one replayed after a restart can't move a peer
+/// back to an old endpoint. Members sign their endpoint again at half this age.
+pub const MAX_RECORD_AGE: u64 = 600;
+
+// how far ahead of our clock a record may be, a later one would win over every fresh record
+const MAX_CLOCK_SKEW: u64 = 60;
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// New ed25519 secret signing the records of a member, base64.
+pub fn generate_signing_key() -> String {
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    base64::encode(secret)
+}
+
+fn keypair(secret: &str) -> Result<Keypair, io::Error> {
+    let secret = SecretKey::from_bytes(&base64::decode(secret).map_err(invalid)?).map_err(invalid)?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+/// Public key of the ed25519 `secret`, the one the server hands to the peers, base64.
+pub fn public_signing_key(secret: &str) -> Result<String, io::Error> {
+    Ok(base64::encode(keypair(secret)?.public.as_bytes()))
+}
+
+/// Whether `key` is a base64 ed25519 public key.
+pub fn is_signing_key(key: &str) -> bool {
+    base64::decode(key).ok().and_then(|k| PublicKey::from_bytes(&k).ok()).is_some()
+}
+
+/// Where a member says it can be reached, signed with its ed25519 key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EndpointRecord {
+    pub member: String,
+    pub endpoint: SocketAddr,
+    // unix seconds, the newest record of a member wins
+    pub timestamp: u64,
+    // base64
+    pub signature: String,
+}
+
+impl EndpointRecord {
+    fn signed_bytes(member: &str, endpoint: &SocketAddr, timestamp: u64) -> Vec<u8> {
+        format!("wgnet endpoint {} {} {}", member, endpoint, timestamp).into_bytes()
+    }
+
+    pub fn sign(member: &str, endpoint: SocketAddr, timestamp: u64, secret: &str) -> Result<Self, io::Error> {
+        let signature = keypair(secret)?.sign(&Self::signed_bytes(member, &endpoint, timestamp));
+        Ok(EndpointRecord {
+            member: member.to_string(),
+            endpoint,
+            timestamp,
+            signature: base64::encode(signature.to_bytes()),
+        })
+    }
+
+    /// Whether the record was signed by the holder of the secret of `public_key`.
+    pub fn verify(&self, public_key: &str) -> bool {
+        let verify = || -> Result<(), io::Error> {
+            let public = PublicKey::from_bytes(&base64::decode(public_key).map_err(invalid)?).map_err(invalid)?;
+            let signature = Signature::try_from(base64::decode(&self.signature).map_err(invalid)?.as_slice()).map_err(invalid)?;
+            public.verify(&Self::signed_bytes(&self.member, &self.endpoint, self.timestamp), &signature).map_err(invalid)
+        };
+        verify().is_ok()
+    }
+}
+
+/// A datagram between two members, through the tunnel.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GossipMessage {
+    // endpoint the receiver talks from, as the sender's device sees it
+    pub observed: Option<SocketAddr>,
+    pub records: Vec<EndpointRecord>,
+}
+
+/// Gossip of this member in one network.
+pub struct Gossip {
+    // name of this member in the network, empty until the server sent it
+    pub member: String,
+    // ed25519 secret, base64
+    pub secret: String,
+    // member: ed25519 public key, sent by the server with the peers
+    pub keys: HashMap<String, String>,
+    // member: newest record known
+    pub records: HashMap<String, EndpointRecord>,
+    // peer: endpoint it last saw this member talk from, and when, unix seconds
+    observations: HashMap<String, (SocketAddr, u64)>,
+}
+
+impl Gossip {
+    pub fn new(member: &str, secret: &str) -> Self {
+        Gossip {
+            member: member.to_string(),
+            secret: secret.to_string(),
+            keys: HashMap::new(),
+            records: HashMap::new(),
+            observations: HashMap::new(),
+        }
+    }
+
+    /// Take the name the server knows this member by, the record signed for another name
+    /// is dropped.
+    pub fn set_member(&mut self, member: &str) {
+        self.records.remove(&self.member);
+        self.member = member.to_string();
+    }
+
+    /// Sign with `secret` from now on, the record signed with the old one is dropped.
+    pub fn set_secret(&mut self, secret: &str) {
+        self.records.remove(&self.member);
+        self.secret = secret.to_string();
+    }
+
+    /// Keep `record` if its member signed it, it is newer than the one known and not older
+    /// than `MAX_RECORD_AGE` at unix seconds `now`. Returns whether it was taken.
+    pub fn accept(&mut self, record: EndpointRecord, now: u64) -> bool {
+        if record.member == self.member {
+            return false;
+        }
+        if record.timestamp + MAX_RECORD_AGE <= now || record.timestamp > now + MAX_CLOCK_SKEW {
+            log::debug!("Dropping the gossip about {}, signed at {}", record.member, record.timestamp);
+            return false;
+        }
+        if self.records.get(&record.member).is_some_and(|r| r.timestamp >= record.timestamp) {
+            return false;
+        }
+        match self.keys.get(&record.member) {
+            Some(key) if record.verify(key) => {
+                self.records.insert(record.member.clone(), record);
+                true
+            }
+            _ => {
+                log::debug!("Dropping the gossip about {}, not signed by its key", record.member);
+                false
+            }
+        }
+    }
+
+    /// Peer `peer` sees this member talk from `endpoint` at unix seconds `now`. The endpoint is
+    /// signed as the record of this member once `MIN_OBSERVERS` peers saw it lately.
+    pub fn observed(&mut self, peer: &str, endpoint: SocketAddr, now: u64) -> Result<(), io::Error> {
+        self.observations.insert(peer.to_string(), (endpoint, now));
+        if self.member.is_empty() {
+            return Ok(());
+        }
+        let observers = self.observations.values()
+            .filter(|(e, t)| *e == endpoint && t + STALE_HANDSHAKE.as_secs() > now)
+            .count();
+        if observers < MIN_OBSERVERS {
+            return Ok(());
+        }
+        if let Some(own) = self.records.get(&self.member) {
+            let fresh = own.timestamp + MAX_RECORD_AGE / 2 > now;
+            if (own.endpoint == endpoint && fresh) || own.timestamp + RESIGN_AFTER > now {
+                return Ok(());
+            }
+        }
+        let record = EndpointRecord::sign(&self.member, endpoint, now, &self.secret)?;
+        log::debug!("Signed endpoint {endpoint} of {}", self.member);
+        self.records.insert(self.member.clone(), record);
+        Ok(())
+    }
+
+    /// All records known still fresh at unix seconds `now`, for a peer seen talking from
+    /// `observed`.
+    pub fn messages(&self, observed: Option<SocketAddr>, now: u64) -> Vec<GossipMessage> {
+        let records: Vec<EndpointRecord> = self.records.values()
+            .filter(|r| r.timestamp + MAX_RECORD_AGE > now)
+            .cloned()
+            .collect();
+        if records.is_empty() {
+            return vec![GossipMessage { observed, records }];
+        }
+        records.chunks(MAX_RECORDS)
+            .map(|chunk| GossipMessage { observed, records: chunk.to_vec() })
+            .collect()
+    }
+}
+
+/// Socket to gossip on from `addr`, an address of this member inside the network.
+pub async fn bind(addr: IpAddr) -> Result<Arc<UdpSocket>, io::Error> {
+    Ok(Arc::new(UdpSocket::bind(SocketAddr::new(addr, GOSSIP_PORT)).await?))
+}
+
+pub async fn send(socket: &UdpSocket, to: IpAddr, message: &GossipMessage) -> Result<(), io::Error> {
+    socket.send_to(&serde_json::to_vec(message)?, SocketAddr::new(to, GOSSIP_PORT)).await?;
+    Ok(())
+}
+
+/// Forward the messages arriving on `socket` to `tx` with the interface `iface` and their
+/// source, until `tx` is closed.
+pub async fn listen(iface: String, socket: Arc<UdpSocket>, tx: mpsc::Sender<(String, SocketAddr, GossipMessage)>) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!("Interface {iface}: gossip receive failed: {e}");
+                return;
+            }
+        };
+        match serde_json::from_slice::<GossipMessage>(&buf[..n]) {
+            Ok(message) => {
+                if tx.send((iface.clone(), from, message)).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => log::debug!("Interface {iface}: bad gossip from {from}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_gossip() {
+        let secret = generate_signing_key();
+        let public = public_signing_key(&secret).unwrap();
+        assert!(is_signing_key(&public));
+        let record = EndpointRecord::sign("laptop", "1.2.3.4:51820".parse().unwrap(), 100, &secret).unwrap();
+        assert!(record.verify(&public));
+        let spoofed = EndpointRecord { endpoint: "6.6.6.6:51820".parse().unwrap(), ..record.clone() };
+        assert!(!spoofed.verify(&public));
+
+        let mut gossip = Gossip::new("nas", &generate_signing_key());
+        assert!(!gossip.accept(record.clone(), 100));
+        gossip.keys.insert("laptop".to_string(), public.clone());
+        assert!(!gossip.accept(spoofed, 100));
+        assert!(gossip.accept(record.clone(), 100));
+        assert!(!gossip.accept(record.clone(), 100));
+        let newer = EndpointRecord::sign("laptop", "5.6.7.8:51820".parse().unwrap(), 200, &secret).unwrap();
+        assert!(gossip.accept(newer, 200));
+        assert_eq!(gossip.records["laptop"].endpoint, "5.6.7.8:51820".parse().unwrap());
+
+        // a restarted member forgot the newer record, the old one is too old to replay
+        let mut restarted = Gossip::new("nas", &generate_signing_key());
+        restarted.keys.insert("laptop".to_string(), public);
+        assert!(!restarted.accept(record.clone(), 100 + MAX_RECORD_AGE));
+        let ahead = EndpointRecord::sign("laptop", "6.6.6.6:51820".parse().unwrap(), 100 + MAX_CLOCK_SKEW + 1, &secret).unwrap();
+        assert!(!restarted.accept(ahead, 100));
+
+        gossip.observed("laptop", "9.9.9.9:51820".parse().unwrap(), 1000).unwrap();
+        assert!(!gossip.records.contains_key("nas"));
+        gossip.observed("phone", "9.9.9.9:51820".parse().unwrap(), 1005).unwrap();
+        gossip.observed("laptop", "8.8.8.8:51820".parse().unwrap(), 1010).unwrap();
+        gossip.observed("phone", "8.8.8.8:51820".parse().unwrap(), 1020).unwrap();
+        assert_eq!(gossip.records["nas"].endpoint, "9.9.9.9:51820".parse().unwrap());
+        assert_eq!(gossip.messages(None, 700)[0].records.len(), 2);
+        // the record of laptop is too old to pass on, the own one is signed again
+        assert_eq!(gossip.messages(None, 1020)[0].records.len(), 1);
+        let resign = 1005 + MAX_RECORD_AGE / 2;
+        gossip.observed("laptop", "9.9.9.9:51820".parse().unwrap(), resign).unwrap();
+        gossip.observed("phone", "9.9.9.9:51820".parse().unwrap(), resign).unwrap();
+        assert_eq!(gossip.records["nas"].timestamp, resign);
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::dns::Zone;
+use crate::utils::write_atomic;
+
+fn begin_marker(iface: &str) -> String {
+    format!("# BEGIN wgnet {}, managed by wgnet, do not edit", iface)
+}
+
+fn end_marker(iface: &str) -> String {
+    format!("# END wgnet {}", iface)
+}
+
+/// Lines of the block of interface `iface`, one per address, sorted by member name.
+fn block(iface: &str, zone: &Zone) -> Vec<String> {
+    let mut lines = vec![begin_marker(iface)];
+    let members: BTreeMap<_, _> = zone.records.iter().collect();
+    for (member, addrs) in members {
+        for addr in addrs {
+            lines.push(format!("{}\t{}.{} {}", addr, member, zone.domain, member));
+        }
+    }
+    lines.push(end_marker(iface));
+    lines
+}
+
+/// `hosts` without the block of `iface`, then `block` appended if any.
+fn replace_block(hosts: &str, iface: &str, block: Option<Vec<String>>) -> String {
+    let (begin, end) = (begin_marker(iface), end_marker(iface));
+    let mut lines = vec![];
+    let mut inside = false;
+    for line in hosts.lines() {
+        if line == begin {
+            inside = true;
+        } else if line == end {
+            inside = false;
+        } else if !inside {
+            lines.push(line.to_string());
+        }
+    }
+    lines.extend(block.into_iter().flatten());
+    let mut s = lines.join("\n");
+    s.push('\n');
+    s
+}
+
+/// Map the member names of `zone` to their addresses in the block of interface `iface`
+/// in the hosts file at `path`. The rest of the file is left as it is.
+pub fn update(path: &Path, iface: &str, zone: &Zone) -> Result<(), io::Error> {
+    let hosts = fs::read_to_string(path).unwrap_or_default();
+    let new = replace_block(&hosts, iface, Some(block(iface, zone)));
+    if new != hosts {
+        write_atomic(path, new.as_bytes(), 0o644)?;
+    }
+    Ok(())
+}
+
+/// Remove the block of interface `iface` from the hosts file at `path`.
+pub fn remove(path: &Path, iface: &str) -> Result<(), io::Error> {
+    let hosts = fs::read_to_string(path)?;
+    let new = replace_block(&hosts, iface, None);
+    if new != hosts {
+        write_atomic(path, new.as_bytes(), 0o644)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_update() {
+        let path = std::env::temp_dir().join(format!("wgnet-hosts-{}", std::process::id()));
+        fs::write(&path, "127.0.0.1\tlocalhost\n").unwrap();
+        let mut zone = Zone {
+            domain: "wg0.internal".to_string(),
+            records: HashMap::from([
+                ("db".to_string(), vec!["10.1.1.2".parse().unwrap()]),
+                ("nas".to_string(), vec!["10.1.1.3".parse().unwrap()]),
+            ]),
+            services: vec![],
+        };
+        update(&path, "wg0", &zone).unwrap();
+        zone.records.remove("nas");
+        update(&path, "wg0", &zone).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!(
+            "127.0.0.1\tlocalhost\n{}\n10.1.1.2\tdb.wg0.internal db\n{}\n",
+            begin_marker("wg0"), end_marker("wg0"),
+        ));
+        remove(&path, "wg0").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "127.0.0.1\tlocalhost\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,12 @@
+// tonic::Status is the error of every rpc, boxing it buys nothing
+#![allow(clippy::result_large_err)]
+
 extern crate core;
 
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 mod client;
 mod server;
@@ -10,8 +14,20 @@ mod config;
 mod wg;
 mod utils;
 mod api;
+mod state;
+mod render;
+mod admin;
+mod ctl;
+mod store;
+mod archive;
+mod policy;
+mod firewall;
+mod dns;
+mod resolver;
+mod hosts;
+mod gossip;
+mod tls;
 
-use tonic;
 
 
 #[derive(Parser)]
@@ -38,29 +54,487 @@ enum Command {
         #[arg(short, long)]
         init: Option<String>,
     },
-    #[command(about = "Run server daemon")]
+    #[command(
+        about = "Run server daemon",
+        long_about = "Run server daemon\n\n\
+            A server with a leader in its config follows it as a read-only failover. Only reads fail \
+            over to it: members keep getting their peers while the leader is down, but admin changes, \
+            redeeming invites, key rotations and route advertisements fail until the leader is back. \
+            There is no automatic promotion, to make a follower the leader remove its leader and \
+            restart it.",
+    )]
     Server {
         #[arg(short, long, default_value = "/etc/wgnet/server.yaml")]
         config: PathBuf,
 
         #[arg(short, long, default_value = "/var/lib/wgnet")]
         data: PathBuf,
+
+        #[command(subcommand)]
+        action: Option<ServerAction>,
+    },
+    #[command(about = "Show the networks joined and whether their servers are reachable")]
+    Status {
+        #[arg(short, long, default_value = "/etc/wgnet/client.yaml")]
+        config: PathBuf,
+
+        /// "table" or "json"
+        #[arg(short, long, default_value = "table")]
+        output: String,
+    },
+    #[command(about = "Register an existing wireguard interface with the server")]
+    Adopt {
+        #[arg(short, long, default_value = "/etc/wgnet/client.yaml")]
+        config: PathBuf,
+
+        /// Interface to adopt, e.g. wg0
+        iface: String,
+
+        /// Member name, the client name by default
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Server rpc socket
+        #[arg(short, long)]
+        server: SocketAddr,
+
+        /// Admin token
+        #[arg(short, long)]
+        token: String,
+
+        /// Certificate the server presents, its tls_cert
+        #[arg(long)]
+        ca: PathBuf,
+    },
+    #[command(about = "Render a static network description into per-member configs")]
+    Render {
+        /// Network description
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Output directory
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// "wg-quick" or "yaml"
+        #[arg(long, default_value = "wg-quick")]
+        format: String,
+
+        /// Generated keys, reused by later renders. <output>/keys.yaml by default
+        #[arg(short, long)]
+        keys: Option<PathBuf>,
+    },
+    #[command(about = "Apply a network manifest to the server")]
+    Apply {
+        /// Network manifest
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Server rpc socket
+        #[arg(short, long)]
+        server: SocketAddr,
+
+        /// Admin token
+        #[arg(short, long)]
+        token: String,
+
+        /// Certificate the server presents, its tls_cert
+        #[arg(long)]
+        ca: PathBuf,
+
+        /// Apply without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    #[command(about = "Manage the network through the server's admin service")]
+    Admin {
+        /// Server rpc socket, the server running on this host by default
+        #[arg(short, long)]
+        server: Option<SocketAddr>,
+
+        /// Admin token, needed with --server
+        #[arg(short, long)]
+        token: Option<String>,
+
+        /// Certificate the server presents, its tls_cert, needed with --server
+        #[arg(long)]
+        ca: Option<PathBuf>,
+
+        /// Server config to take the socket and token from without --server
+        #[arg(short, long, default_value = "/etc/wgnet/server.yaml")]
+        config: PathBuf,
+
+        /// Admin to act as, the first one of the server config by default
+        #[arg(long)]
+        admin: Option<String>,
+
+        /// "table" or "json"
+        #[arg(short, long, default_value = "table")]
+        output: String,
+
+        #[command(subcommand)]
+        action: AdminAction,
+    },
+    #[command(subcommand, about = "Route internet traffic through an exit node")]
+    Exit(ExitAction),
+    #[command(about = "List the services of the members this host may reach")]
+    Services {
+        #[command(flatten)]
+        target: MemberTarget,
+
+        /// "table" or "json"
+        #[arg(short, long, default_value = "table")]
+        output: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ExitAction {
+    #[command(about = "Send all traffic through exit node <member>")]
+    Use {
+        member: String,
+
+        #[command(flatten)]
+        target: MemberTarget,
+    },
+    #[command(about = "Restore normal routing")]
+    Off {
+        #[command(flatten)]
+        target: MemberTarget,
+    },
+}
+
+#[derive(Args)]
+struct MemberTarget {
+    #[arg(short, long, default_value = "/etc/wgnet/client.yaml")]
+    config: PathBuf,
+
+    /// Interface of the network, the only one in the iface config dir by default
+    #[arg(short, long)]
+    iface: Option<String>,
+
+    /// Server rpc socket, the one the network was joined through by default
+    #[arg(short, long)]
+    server: Option<SocketAddr>,
+}
+
+#[derive(Subcommand)]
+enum AdminAction {
+    #[command(subcommand, about = "Manage members")]
+    Members(MembersAction),
+    #[command(subcommand, about = "Manage invites")]
+    Invites(InvitesAction),
+    #[command(subcommand, about = "Manage routes advertised by members")]
+    Routes(RoutesAction),
+    #[command(subcommand, about = "Manage who may reach whom")]
+    Policy(PolicyAction),
+}
+
+#[derive(Subcommand)]
+enum PolicyAction {
+    #[command(about = "Print the current policy")]
+    Show,
+    #[command(about = "Replace the policy with a policy file")]
+    Set {
+        /// Policy file
+        #[arg(short, long)]
+        file: PathBuf,
+    },
+    #[command(about = "Explain whether a member may reach another")]
+    Test { from: String, to: String },
+}
+
+#[derive(Subcommand)]
+enum MembersAction {
+    #[command(about = "List members")]
+    Ls,
+    #[command(about = "Show a member")]
+    Show { name: String },
+    #[command(about = "Remove a member from the network")]
+    Rm {
+        name: String,
+
+        /// Remove without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    #[command(about = "Disable a member, keeping it in the state")]
+    Disable { name: String },
+    #[command(about = "Enable a disabled member")]
+    Enable { name: String },
+    #[command(about = "Replace the key of a member that lost its own")]
+    SetKey {
+        name: String,
+
+        /// New public key of the member
+        public_key: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum InvitesAction {
+    #[command(about = "List pending invites")]
+    Ls,
+    #[command(about = "Invite a new member")]
+    Create {
+        /// Member name once redeemed
+        name: String,
+
+        /// Addresses of the member, allocated from the server network by default
+        #[arg(short, long)]
+        addr: Vec<ipnet::IpNet>,
+
+        /// Validity in seconds, a day by default
+        #[arg(long, default_value_t = 0)]
+        ttl: u64,
+    },
+    #[command(about = "Revoke a pending invite")]
+    Revoke { id: String },
+}
+
+#[derive(Subcommand)]
+enum RoutesAction {
+    #[command(about = "List approved and pending routes")]
+    Ls,
+    #[command(about = "Approve a route advertised by a member")]
+    Approve {
+        member: String,
+        prefix: ipnet::IpNet,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServerAction {
+    #[command(about = "Export the server state and settings to an archive")]
+    Export {
+        /// Archive to write
+        output: PathBuf,
+
+        /// File holding the passphrase encrypting the secrets
+        #[arg(long)]
+        passphrase_file: Option<PathBuf>,
+    },
+    #[command(about = "Replace the server state with an archive, the server must be stopped")]
+    Import {
+        /// Archive to read
+        input: PathBuf,
+
+        /// File holding the passphrase decrypting the secrets
+        #[arg(long)]
+        passphrase_file: Option<PathBuf>,
+
+        /// Also restore the server config and iface config
+        #[arg(long)]
+        settings: bool,
+    },
+    #[command(about = "List configuration revisions of the running server")]
+    History {
+        /// Admin to act as, the first one of the server config by default
+        #[arg(long)]
+        admin: Option<String>,
+    },
+    #[command(about = "Restore the configuration of a revision on the running server")]
+    Rollback {
+        revision: u64,
+
+        /// Admin to act as, the first one of the server config by default
+        #[arg(long)]
+        admin: Option<String>,
     },
 }
 
-fn main() {
+async fn admin_action(ctl: &mut ctl::Ctl, action: AdminAction, json: bool) -> Result<(), std::io::Error> {
+    match action {
+        AdminAction::Members(MembersAction::Ls) => ctl.list_members(json).await,
+        AdminAction::Members(MembersAction::Show { name }) => ctl.show_member(&name, json).await,
+        AdminAction::Members(MembersAction::Rm { name, yes }) => ctl.remove_member(&name, yes).await,
+        AdminAction::Members(MembersAction::Disable { name }) => ctl.set_member_disabled(&name, true).await,
+        AdminAction::Members(MembersAction::Enable { name }) => ctl.set_member_disabled(&name, false).await,
+        AdminAction::Members(MembersAction::SetKey { name, public_key }) => ctl.set_member_key(&name, &public_key).await,
+        AdminAction::Invites(InvitesAction::Ls) => ctl.list_invites(json).await,
+        AdminAction::Invites(InvitesAction::Create { name, addr, ttl }) => ctl.create_invite(&name, &addr, ttl, json).await,
+        AdminAction::Invites(InvitesAction::Revoke { id }) => ctl.revoke_invite(&id).await,
+        AdminAction::Routes(RoutesAction::Ls) => ctl.list_routes(json).await,
+        AdminAction::Routes(RoutesAction::Approve { member, prefix }) => ctl.approve_route(&member, &prefix).await,
+        AdminAction::Policy(PolicyAction::Show) => ctl.show_policy().await,
+        AdminAction::Policy(PolicyAction::Set { file }) => ctl.set_policy(&file).await,
+        AdminAction::Policy(PolicyAction::Test { from, to }) => ctl.test_policy(&from, &to, json).await,
+    }
+}
+
+fn read_passphrase(path: &Option<PathBuf>) -> Result<Option<String>, std::io::Error> {
+    match path {
+        Some(p) => Ok(Some(std::fs::read_to_string(p)?.trim_end_matches('\n').to_string())),
+        None => Ok(None),
+    }
+}
+
+#[tokio::main]
+async fn main() {
     let cli = Cli::parse();
+    // warnings by default, RUST_LOG takes precedence over -v
+    let level = match cli.verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    env_logger::Builder::new().filter_level(level).parse_default_env().init();
 
     match cli.command {
         Command::Client { config, init } => {
-            if let Some(reginfo) = init {
-                // client::init(&config, &reginfo);
-            } else {
-                // client::run(&config);
+            let config = config::client::ClientConfig::from_yaml_file(&config).unwrap();
+            let mut client = match client::Client::new(config).await {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("Failed to start client: {e}");
+                    std::process::exit(1);
+                }
+            };
+            if let Some(code) = init {
+                let joined = match config::invite::InviteConfig::from_base64_json(&code) {
+                    Ok(invite) => client.redeem_invite(&invite).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = joined {
+                    eprintln!("Failed to redeem invite: {e}");
+                    std::process::exit(1);
+                }
+            }
+            client.run().await;
+        }
+        Command::Server { config, data, action: None } => {
+            let config = config::server::ServerConfig::from_yaml_file(&config).unwrap();
+            match server::Server::new(config, &data).await {
+                Ok(mut server) => server.run().await,
+                Err(e) => {
+                    eprintln!("Failed to start server: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Command::Server { config, data, action: Some(ServerAction::Export { output, passphrase_file }) } => {
+            let config = config::server::ServerConfig::from_yaml_file(&config).unwrap();
+            let result = match read_passphrase(&passphrase_file) {
+                Ok(passphrase) => archive::export(&config, &data, &output, passphrase.as_deref()).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to export: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Server { config, data, action: Some(ServerAction::Import { input, passphrase_file, settings }) } => {
+            let result = match read_passphrase(&passphrase_file) {
+                Ok(passphrase) => archive::import(&config, &data, &input, passphrase.as_deref(), settings).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to import {}: {e}", input.display());
+                std::process::exit(1);
+            }
+        }
+        Command::Server { config, action: Some(ServerAction::History { admin }), .. } => {
+            let config = config::server::ServerConfig::from_yaml_file(&config).unwrap();
+            let result = match ctl::Ctl::connect_local(&config, admin.as_deref()).await {
+                Ok(mut ctl) => ctl.history().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to get history: {e}");
+                std::process::exit(1);
             }
         }
-        Command::Server { config, data } => {
-            // server::run(&config, &data);
+        Command::Server { config, action: Some(ServerAction::Rollback { revision, admin }), .. } => {
+            let config = config::server::ServerConfig::from_yaml_file(&config).unwrap();
+            let result = match ctl::Ctl::connect_local(&config, admin.as_deref()).await {
+                Ok(mut ctl) => ctl.rollback(revision).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to roll back to revision {revision}: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Status { config, output } => {
+            let config = config::client::ClientConfig::from_yaml_file(&config).unwrap();
+            if let Err(e) = client::print_status(&config, output == "json") {
+                eprintln!("Failed to read the status: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Adopt { config, iface, name, server, token, ca } => {
+            let config = config::client::ClientConfig::from_yaml_file(&config).unwrap();
+            let name = name.unwrap_or(config.name.clone());
+            let result = match std::fs::read_to_string(&ca) {
+                Ok(ca) => client::adopt(&config, &iface, &name, server, &token, &ca).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to adopt {iface}: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Exit(action) => {
+            let (exit, target) = match action {
+                ExitAction::Use { member, target } => (Some(member), target),
+                ExitAction::Off { target } => (None, target),
+            };
+            let config = config::client::ClientConfig::from_yaml_file(&target.config).unwrap();
+            if let Err(e) = client::use_exit(&config, target.iface.as_deref(), target.server, exit.as_deref()).await {
+                eprintln!("Failed to switch exit node: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Services { target, output } => {
+            let config = config::client::ClientConfig::from_yaml_file(&target.config).unwrap();
+            if let Err(e) = client::list_services(&config, target.iface.as_deref(), target.server, output == "json").await {
+                eprintln!("Failed to list services: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Render { file, output, format, keys } => {
+            let mesh = config::mesh::MeshConfig::from_yaml_file(&file).unwrap();
+            let keys = keys.unwrap_or(output.join("keys.yaml"));
+            if let Err(e) = render::render_to_dir(&mesh, &keys, &output, &format) {
+                eprintln!("Failed to render {}: {e}", file.display());
+                std::process::exit(1);
+            }
+        }
+        Command::Apply { file, server, token, ca, yes } => {
+            let result = match ctl::Ctl::connect(server, &token, &ca).await {
+                Ok(mut ctl) => ctl.apply(&file, yes).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to apply {}: {e}", file.display());
+                std::process::exit(1);
+            }
+        }
+        Command::Admin { server, token, ca, config, admin, output, action } => {
+            if output != "table" && output != "json" {
+                eprintln!("Unknown output format {output}, use table or json");
+                std::process::exit(1);
+            }
+            let ctl = match (server, token, ca) {
+                (Some(server), Some(token), Some(ca)) => ctl::Ctl::connect(server, &token, &ca).await,
+                (Some(_), _, _) => {
+                    eprintln!("--token and --ca are needed with --server");
+                    std::process::exit(1);
+                }
+                (None, _, _) => {
+                    let config = config::server::ServerConfig::from_yaml_file(&config).unwrap();
+                    ctl::Ctl::connect_local(&config, admin.as_deref()).await
+                }
+            };
+            let result = match ctl {
+                Ok(mut ctl) => admin_action(&mut ctl, action, output == "json").await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed: {e}");
+                std::process::exit(1);
+            }
         }
     }
 }
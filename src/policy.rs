@@ -0,0 +1,160 @@
+use ipnet::IpNet;
+
+use crate::firewall::{parse_port_spec, FirewallRule};
+use crate::state::{AclRule, Member, NetworkState};
+
+/// Whether `selector` is one of `*`, `member:<name>`, `group:<name>` or `tag:<name>`.
+pub fn valid_selector(selector: &str) -> bool {
+    match selector.split_once(':') {
+        _ if selector == "*" => true,
+        Some(("member" | "group" | "tag", name)) => !name.is_empty(),
+        _ => false,
+    }
+}
+
+pub fn selector_matches(selector: &str, name: &str, member: &Member) -> bool {
+    match selector.split_once(':') {
+        _ if selector == "*" => true,
+        Some(("member", n)) => n == name,
+        Some(("group", group)) => member.groups.iter().any(|g| g == group),
+        Some(("tag", tag)) => member.tags.iter().any(|t| t == tag),
+        _ => false,
+    }
+}
+
+/// Index of the first rule letting `from` reach `to`.
+pub fn allowing_rule(state: &NetworkState, from: &str, to: &str) -> Option<usize> {
+    let (a, b) = (state.members.get(from)?, state.members.get(to)?);
+    state.acls.iter().position(|acl| selector_matches(&acl.from, from, a) && selector_matches(&acl.to, to, b))
+}
+
+/// Members see each other as peers when either may reach the other, the tunnel carries the replies.
+/// Without any rule everyone may reach everyone.
+pub fn may_peer(state: &NetworkState, a: &str, b: &str) -> bool {
+    state.acls.is_empty() || allowing_rule(state, a, b).is_some() || allowing_rule(state, b, a).is_some()
+}
+
+/// Whether `from` may reach `proto` port `port` of `to`.
+pub fn may_reach(state: &NetworkState, from: &str, to: &str, proto: &str, port: u16) -> bool {
+    if state.acls.is_empty() {
+        return true;
+    }
+    let (a, b) = match (state.members.get(from), state.members.get(to)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return false,
+    };
+    state.acls.iter()
+        .filter(|acl| selector_matches(&acl.from, from, a) && selector_matches(&acl.to, to, b))
+        .any(|acl| acl.ports.is_empty() || acl.ports.iter().any(|spec| match parse_port_spec(spec) {
+            Ok((None, _)) => true,
+            Ok((Some(p), ports)) => p == proto && ports.is_none_or(|(lo, hi)| lo <= port && port <= hi),
+            Err(_) => false,
+        }))
+}
+
+/// Check the selectors and port specs of `acl`.
+pub fn validate_acl(acl: &AclRule) -> Result<(), String> {
+    for selector in [&acl.from, &acl.to] {
+        if !valid_selector(selector) {
+            return Err(format!("acl {}: invalid selector {}", describe(acl), selector));
+        }
+    }
+    for spec in acl.ports.iter() {
+        parse_port_spec(spec).map_err(|e| format!("acl {}: {}", describe(acl), e))?;
+    }
+    Ok(())
+}
+
+/// What member `name` lets in through its interface, None when there are no acls
+/// and everything is let in.
+pub fn firewall_for(state: &NetworkState, name: &str) -> Option<Vec<FirewallRule>> {
+    if state.acls.is_empty() {
+        return None;
+    }
+    let member = state.members.get(name)?;
+    let mut rules = vec![];
+    for acl in state.acls.iter().filter(|acl| selector_matches(&acl.to, name, member)) {
+        // traffic of the sources and of the subnets they route
+        let sources: Vec<IpNet> = state.members.iter()
+            .filter(|(n, m)| n.as_str() != name && !m.disabled && selector_matches(&acl.from, n, m))
+            .flat_map(|(_, m)| m.to_peer_config().allowed_ips)
+            .collect();
+        if sources.is_empty() {
+            continue;
+        }
+        if acl.ports.is_empty() {
+            rules.push(FirewallRule { sources: sources.clone(), proto: None, ports: None });
+        }
+        for spec in acl.ports.iter() {
+            // validated when the policy was set
+            let (proto, ports) = parse_port_spec(spec).unwrap();
+            rules.push(FirewallRule { sources: sources.clone(), proto, ports });
+        }
+    }
+    Some(rules)
+}
+
+fn describe(acl: &AclRule) -> String {
+    if acl.ports.is_empty() {
+        format!("{} -> {}", acl.from, acl.to)
+    } else {
+        format!("{} -> {} on {}", acl.from, acl.to, acl.ports.join(", "))
+    }
+}
+
+/// Whether `from` may reach `to`, with the reasoning for `wgnet admin policy test`.
+pub fn explain(state: &NetworkState, from: &str, to: &str) -> Result<(bool, Vec<String>), String> {
+    for name in [from, to] {
+        if !state.members.contains_key(name) {
+            return Err(format!("no member {}", name));
+        }
+    }
+    if state.acls.is_empty() {
+        return Ok((true, vec!["no acl rules, every member may reach every other".to_string()]));
+    }
+    let mut lines = vec![];
+    let allowed = match allowing_rule(state, from, to) {
+        Some(i) => {
+            lines.push(format!("rule {} ({}) lets {} reach {}", i, describe(&state.acls[i]), from, to));
+            true
+        }
+        None => {
+            lines.push(format!("no rule lets {} reach {}", from, to));
+            false
+        }
+    };
+    match allowing_rule(state, to, from) {
+        Some(i) => lines.push(format!("rule {} ({}) lets {} reach {}, they are peers", i, describe(&state.acls[i]), to, from)),
+        None if allowed => lines.push(format!("{} and {} are peers", from, to)),
+        None => lines.push(format!("{} and {} are not peers", from, to)),
+    }
+    Ok((allowed, lines))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::manifest::NetworkManifest;
+    use std::path::Path;
+
+    #[test]
+    fn test_may_peer() {
+        let manifest = NetworkManifest::from_yaml_file(Path::new("example/net.yaml")).unwrap();
+        let mut state = NetworkState::default();
+        manifest.apply(&mut state);
+        assert!(may_peer(&state, "laptop", "db"));
+        assert!(may_peer(&state, "db", "gateway"));
+        let rules = firewall_for(&state, "db").unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].sources, vec!["10.1.1.2/32".parse::<IpNet>().unwrap()]);
+        assert_eq!(rules[0].ports, Some((5432, 5432)));
+        assert!(!state.peers_of("laptop").contains_key("nas"));
+        assert!(!explain(&state, "db", "laptop").unwrap().0);
+        assert!(may_reach(&state, "laptop", "db", "tcp", 5432));
+        assert!(!may_reach(&state, "laptop", "db", "tcp", 22));
+        assert!(may_reach(&state, "db", "gateway", "udp", 53));
+        assert!(explain(&state, "laptop", "nobody").is_err());
+        state.acls.clear();
+        assert!(may_peer(&state, "laptop", "db"));
+    }
+}
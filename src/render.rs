@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use ipnet::IpNet;
+use wireguard_control::Key;
+
+use crate::config::mesh::{MeshConfig, MeshKeys, MeshMember, Topology};
+use crate::config::wg::{InterfaceConfig, PeerConfig};
+use crate::state::pair_name;
+use crate::utils::write_atomic;
+
+/// Compute the interface config of every member, generating missing keys into `keys`.
+pub fn render(mesh: &MeshConfig, keys: &mut MeshKeys) -> Result<BTreeMap<String, InterfaceConfig>, io::Error> {
+    if mesh.topology == Topology::Hub && mesh.hubs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "hub topology needs at least one hub"));
+    }
+    for hub in mesh.hubs.iter() {
+        if !mesh.members.contains_key(hub) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown hub {}", hub)));
+        }
+    }
+    // drop keys of removed members so the keys file doesn't grow forever
+    keys.private_keys.retain(|name, _| mesh.members.contains_key(name));
+    for name in mesh.members.keys() {
+        keys.private_keys.entry(name.clone())
+            .or_insert_with(|| Key::generate_private().to_base64());
+    }
+
+    let mut configs = BTreeMap::new();
+    let mut used_pairs = vec![];
+    for (name, member) in mesh.members.iter() {
+        let mut peers = HashMap::new();
+        for (peer_name, peer) in mesh.members.iter() {
+            if peer_name == name || !connected(mesh, name, peer_name) {
+                continue;
+            }
+            let pair = pair_name(name, peer_name);
+            let psk = keys.preshared_keys.entry(pair.clone())
+                .or_insert_with(|| Key::generate_preshared().to_base64())
+                .clone();
+            used_pairs.push(pair);
+            let public_key = Key::from_base64(&keys.private_keys[peer_name]).unwrap()
+                .generate_public()
+                .to_base64();
+            peers.insert(peer_name.clone(), PeerConfig {
+                public_key,
+                endpoint: peer.endpoint.clone(),
+                allowed_ips: allowed_ips(mesh, name, peer_name),
+                preshared_key: Some(psk),
+                persistent_keepalive: match peer.endpoint {
+                    Some(_) => mesh.persistent_keepalive,
+                    None => None,
+                },
+                next_preshared_key: None,
+            });
+        }
+        configs.insert(name.clone(), InterfaceConfig {
+            name: mesh.iface_name.clone(),
+            private_key: keys.private_keys[name].clone(),
+            addrs: member.addrs.clone(),
+            listen_port: member.listen_port,
+            mtu: mesh.mtu,
+            internal_endpoint: None,
+            external_endpoint: member.endpoint.clone(),
+            peers,
+            dns: vec![],
+            search: vec![],
+        });
+    }
+    keys.preshared_keys.retain(|pair, _| used_pairs.contains(pair));
+    Ok(configs)
+}
+
+/// Render `mesh` and write one file per member into `output`, in "wg-quick" or "yaml" format.
+pub fn render_to_dir(mesh: &MeshConfig, keys_path: &Path, output: &Path, format: &str) -> Result<(), io::Error> {
+    let mut keys = if keys_path.exists() {
+        MeshKeys::from_yaml_file(keys_path)?
+    } else {
+        MeshKeys::default()
+    };
+    let configs = render(mesh, &mut keys)?;
+    fs::create_dir_all(output)?;
+    for (name, config) in configs.iter() {
+        let (path, content) = match format {
+            "wg-quick" => (output.join(format!("{}.conf", name)), config.to_wg_quick()),
+            "yaml" => (output.join(format!("{}.yaml", name)), serde_yaml::to_string(config).unwrap()),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown format {}", format))),
+        };
+        write_atomic(&path, content.as_bytes(), 0o600)?;
+        log::info!("Rendered {} for member {}", path.display(), name);
+    }
+    keys.to_yaml_file(keys_path)?;
+    Ok(())
+}
+
+fn connected(mesh: &MeshConfig, a: &str, b: &str) -> bool {
+    match mesh.topology {
+        Topology::Mesh => true,
+        Topology::Hub => mesh.hubs.iter().any(|h| h == a || h == b),
+    }
+}
+
+fn host_routes(member: &MeshMember) -> Vec<IpNet> {
+    member.addrs.iter()
+        .map(|a| IpNet::new(a.addr(), a.max_prefix_len()).unwrap())
+        .chain(member.allowed_ips.iter().cloned())
+        .collect()
+}
+
+/// What `name` routes to `peer`. In the hub topology the first hub also carries
+/// the members `name` has no direct link to.
+fn allowed_ips(mesh: &MeshConfig, name: &str, peer: &str) -> Vec<IpNet> {
+    let mut ips = host_routes(&mesh.members[peer]);
+    if mesh.topology == Topology::Hub && mesh.hubs.first().map(|h| h.as_str()) == Some(peer) {
+        for (other_name, other) in mesh.members.iter() {
+            if other_name != name && other_name != peer && !connected(mesh, name, other_name) {
+                ips.extend(host_routes(other));
+            }
+        }
+    }
+    ips
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mesh = MeshConfig::from_yaml_file(Path::new("example/mesh.yaml")).unwrap();
+        let mut keys = MeshKeys::default();
+        let configs = render(&mesh, &mut keys).unwrap();
+        assert_eq!(configs["gateway"].peers.len(), 2);
+        assert_eq!(configs["laptop"].peers.len(), 1);
+        // the laptop reaches the nas through the gateway
+        let via_gateway = &configs["laptop"].peers["gateway"].allowed_ips;
+        assert!(via_gateway.contains(&"10.1.0.3/32".parse().unwrap()));
+        assert!(via_gateway.contains(&"192.168.1.0/24".parse().unwrap()));
+        assert_eq!(
+            configs["laptop"].peers["gateway"].preshared_key,
+            configs["gateway"].peers["laptop"].preshared_key,
+        );
+        // same keys, same output
+        let configs2 = render(&mesh, &mut keys).unwrap();
+        for (name, config) in configs.iter() {
+            assert_eq!(config.to_wg_quick(), configs2[name].to_wg_quick());
+        }
+    }
+}
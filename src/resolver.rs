@@ -0,0 +1,277 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::utils::{run_command, run_command_with_input, write_atomic};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+// the resolv.conf found before wgnet wrote its own, a symlink when it was one
+const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.wgnet";
+// one file per interface in the managed block, the backup is restored once the last one goes
+const RESOLV_CONF_IFACES: &str = "/etc/resolv.conf.wgnet.d";
+// the resolvers systemd-resolved forwards to from its stub
+const RESOLVED_UPSTREAM: &str = "/run/systemd/resolve/resolv.conf";
+
+/// How the host resolver was pointed at the network's resolvers, to undo it the same way.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Method {
+    // systemd-resolved over D-Bus, split per link
+    Resolved,
+    Resolvconf,
+    // /etc/resolv.conf rewritten, the original kept aside
+    File,
+}
+
+/// Send the queries for `domains` on interface `iface` to `servers`. systemd-resolved makes
+/// it a split setup. openresolv splits by the `domain` line only when it feeds a local resolver
+/// such as dnsmasq or unbound. A plain resolv.conf is never split: `servers` become the first
+/// resolvers of the host for all names, fine as long as they forward other names.
+pub fn configure(iface: &str, servers: &[IpAddr], domains: &[String]) -> Result<Method, io::Error> {
+    if Path::new("/run/systemd/resolve").exists() {
+        match configure_resolved(iface, servers, domains) {
+            Ok(_) => return Ok(Method::Resolved),
+            Err(e) => log::warn!("Interface {iface}: systemd-resolved failed, trying resolvconf: {e}"),
+        }
+    }
+    // no -x, which would make `servers` the only resolvers of the host
+    let mut conf = resolv_conf_lines(servers, domains);
+    if let Some(domain) = domains.first() {
+        conf.insert_str(0, &format!("domain {}\n", domain));
+    }
+    match run_command_with_input("resolvconf", &vec!["-a", iface, "-m", "0"], &conf) {
+        Ok(_) => {
+            log::info!("Interface {iface}: resolvconf routes only {:?} to {:?} when it feeds a local resolver \
+                        such as dnsmasq, otherwise they resolve all names first", domains, servers);
+            return Ok(Method::Resolvconf);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => log::warn!("Interface {iface}: resolvconf failed, writing {RESOLV_CONF}: {e}"),
+    }
+    log::warn!("Interface {iface}: no split DNS with a plain {RESOLV_CONF}, all names are resolved through {:?} first", servers);
+    backup_resolv_conf()?;
+    fs::create_dir_all(RESOLV_CONF_IFACES)?;
+    write_atomic(&iface_path(iface), resolv_conf_lines(servers, domains).as_bytes(), 0o644)?;
+    write_resolv_conf()?;
+    Ok(Method::File)
+}
+
+/// Resolvers the host used before wgnet, loopback stubs left out for their upstreams.
+pub fn host_resolvers() -> Vec<IpAddr> {
+    let own = if fs::symlink_metadata(RESOLV_CONF_BACKUP).is_ok() { RESOLV_CONF_BACKUP } else { RESOLV_CONF };
+    let mut servers = vec![];
+    for path in [own, RESOLVED_UPSTREAM] {
+        let (found, _) = parse_resolv_conf_lines(&fs::read_to_string(path).unwrap_or_default());
+        for server in found {
+            if !server.is_loopback() && !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+    }
+    servers
+}
+
+fn iface_path(iface: &str) -> PathBuf {
+    Path::new(RESOLV_CONF_IFACES).join(iface)
+}
+
+/// Keep the resolv.conf of the host aside, once for all interfaces. A symlink, as left by
+/// systemd-resolved or NetworkManager, is kept as a link to the same target.
+fn backup_resolv_conf() -> Result<(), io::Error> {
+    let backup = Path::new(RESOLV_CONF_BACKUP);
+    if backup.symlink_metadata().is_ok() {
+        return Ok(());
+    }
+    match fs::symlink_metadata(RESOLV_CONF) {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(fs::read_link(RESOLV_CONF)?, backup)?;
+            #[cfg(not(unix))]
+            fs::copy(RESOLV_CONF, backup)?;
+        }
+        Ok(_) => {
+            fs::copy(RESOLV_CONF, backup)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fs::write(backup, "")?,
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+/// Write resolv.conf with the block of every interface still configured in front of the
+/// backed up one, or put the backup back when none is left.
+fn write_resolv_conf() -> Result<(), io::Error> {
+    let mut ifaces = vec![];
+    if Path::new(RESOLV_CONF_IFACES).exists() {
+        for entry in fs::read_dir(RESOLV_CONF_IFACES)? {
+            ifaces.push(entry?.path());
+        }
+    }
+    ifaces.sort();
+    if ifaces.is_empty() {
+        if fs::symlink_metadata(RESOLV_CONF_BACKUP).is_ok() {
+            // rename keeps a symlink a symlink
+            fs::rename(RESOLV_CONF_BACKUP, RESOLV_CONF)?;
+        }
+        return match fs::remove_dir(RESOLV_CONF_IFACES) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+    }
+    let (mut servers, mut domains) = (vec![], vec![]);
+    for path in ifaces.iter() {
+        let (iface_servers, iface_domains) = parse_resolv_conf_lines(&fs::read_to_string(path)?);
+        for server in iface_servers {
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+        for domain in iface_domains {
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+    }
+    // read through the link of a symlinked backup
+    let original = fs::read_to_string(RESOLV_CONF_BACKUP).unwrap_or_default();
+    write_atomic(Path::new(RESOLV_CONF), resolv_conf(&servers, &domains, &original).as_bytes(), 0o644)
+}
+
+/// Undo `configure` of interface `iface`.
+pub fn restore(iface: &str, method: &Method) -> Result<(), io::Error> {
+    match method {
+        Method::Resolved => {
+            let index = link_index(iface)?;
+            run_command("busctl", &vec![
+                "call", "org.freedesktop.resolve1", "/org/freedesktop/resolve1",
+                "org.freedesktop.resolve1.Manager", "RevertLink", "i", &index,
+            ])?;
+        }
+        Method::Resolvconf => {
+            run_command("resolvconf", &vec!["-d", iface, "-f"])?;
+        }
+        Method::File => {
+            match fs::remove_file(iface_path(iface)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            write_resolv_conf()?;
+        }
+    }
+    Ok(())
+}
+
+fn link_index(iface: &str) -> Result<String, io::Error> {
+    Ok(fs::read_to_string(format!("/sys/class/net/{}/ifindex", iface))?.trim().to_string())
+}
+
+fn configure_resolved(iface: &str, servers: &[IpAddr], domains: &[String]) -> Result<(), io::Error> {
+    let index = link_index(iface)?;
+    // SetLinkDNS takes a(iay): family and address bytes
+    let mut args: Vec<String> = vec![servers.len().to_string()];
+    for server in servers {
+        let (family, octets) = match server {
+            IpAddr::V4(a) => ("2", a.octets().to_vec()),
+            IpAddr::V6(a) => ("10", a.octets().to_vec()),
+        };
+        args.push(family.to_string());
+        args.push(octets.len().to_string());
+        args.extend(octets.iter().map(|o| o.to_string()));
+    }
+    let mut call = vec![
+        "call", "org.freedesktop.resolve1", "/org/freedesktop/resolve1",
+        "org.freedesktop.resolve1.Manager", "SetLinkDNS", "ia(iay)", &index,
+    ];
+    call.extend(args.iter().map(|a| a.as_str()));
+    run_command("busctl", &call)?;
+    // SetLinkDomains takes a(sb), the queries for these domains go to this link only,
+    // false keeping them search domains as well
+    let mut args: Vec<String> = vec![domains.len().to_string()];
+    for domain in domains {
+        args.push(domain.clone());
+        args.push("false".to_string());
+    }
+    let mut call = vec![
+        "call", "org.freedesktop.resolve1", "/org/freedesktop/resolve1",
+        "org.freedesktop.resolve1.Manager", "SetLinkDomains", "ia(sb)", &index,
+    ];
+    call.extend(args.iter().map(|a| a.as_str()));
+    run_command("busctl", &call)?;
+    Ok(())
+}
+
+/// `nameserver` and `search` lines of `servers` and `domains`.
+fn resolv_conf_lines(servers: &[IpAddr], domains: &[String]) -> String {
+    let mut conf = String::new();
+    for server in servers {
+        conf.push_str(&format!("nameserver {}\n", server));
+    }
+    if !domains.is_empty() {
+        conf.push_str(&format!("search {}\n", domains.join(" ")));
+    }
+    conf
+}
+
+/// Servers and domains back from `resolv_conf_lines`.
+fn parse_resolv_conf_lines(conf: &str) -> (Vec<IpAddr>, Vec<String>) {
+    let (mut servers, mut domains) = (vec![], vec![]);
+    for line in conf.lines() {
+        match line.split_once(' ') {
+            Some(("nameserver", server)) => servers.extend(IpAddr::from_str(server.trim()).ok()),
+            Some(("search", search)) => domains.extend(search.split_whitespace().map(|d| d.to_string())),
+            _ => {}
+        }
+    }
+    (servers, domains)
+}
+
+/// `servers` and `domains` in front of the resolv.conf `original`.
+fn resolv_conf(servers: &[IpAddr], domains: &[String], original: &str) -> String {
+    let mut conf = String::from("# generated by wgnet, restored when it exits\n");
+    for server in servers {
+        conf.push_str(&format!("nameserver {}\n", server));
+    }
+    let mut search: Vec<String> = domains.to_vec();
+    let mut rest = vec![];
+    for line in original.lines() {
+        match line.trim().strip_prefix("search") {
+            Some(old) => search.extend(old.split_whitespace().map(|d| d.to_string())),
+            None => rest.push(line),
+        }
+    }
+    if !search.is_empty() {
+        conf.push_str(&format!("search {}\n", search.join(" ")));
+    }
+    for line in rest {
+        conf.push_str(line);
+        conf.push('\n');
+    }
+    conf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolv_conf() {
+        let conf = resolv_conf(
+            &["10.1.1.1".parse().unwrap()],
+            &["wg0.internal".to_string()],
+            "search lan\nnameserver 192.168.1.1\n",
+        );
+        assert_eq!(conf, "# generated by wgnet, restored when it exits\n\
+                          nameserver 10.1.1.1\n\
+                          search wg0.internal lan\n\
+                          nameserver 192.168.1.1\n");
+    }
+
+    #[test]
+    fn test_resolv_conf_lines() {
+        let servers: Vec<IpAddr> = vec!["10.1.1.1".parse().unwrap(), "fd00::1".parse().unwrap()];
+        let domains = vec!["wg0.internal".to_string(), "corp".to_string()];
+        let conf = resolv_conf_lines(&servers, &domains);
+        assert_eq!(parse_resolv_conf_lines(&conf), (servers, domains));
+    }
+}
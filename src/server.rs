@@ -1,48 +1,682 @@
-use tonic;
-use tokio;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ipnet::IpNet;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport, Request, Response, Status};
 use crate::config::server::ServerConfig;
+use crate::config::wg::{Endpoint, InterfaceConfig};
 use crate::api::proto;
-use crate::api::proto::{GetPeersReply, GetPeersRequest, PingRequest, PingResponse, PostEndpointReply, PostEndpointRequest, RedeemInviteReply, RedeemInviteRequest};
+use crate::api::proto::{ReplicateReply, ReplicateRequest};
+use crate::api::proto::{AdvertiseRoutesReply, AdvertiseRoutesRequest, GetPeersReply, GetPeersRequest, PingRequest, PingResponse, PostEndpointReply, PostEndpointRequest, RedeemInviteReply, RedeemInviteRequest, RotateKeyReply, RotateKeyRequest, UseExitReply, UseExitRequest, PublishServicesReply, PublishServicesRequest, ListServicesReply, ListServicesRequest, RegisterSigningKeyReply, RegisterSigningKeyRequest};
+use crate::admin::{AdminGuard, AdminServer};
+use crate::state::{Member, NetworkState, RetiredKey, Service};
+use crate::client::{connect_channel, connect_lazy, RpcClient};
+use crate::ctl::status_to_io;
+use crate::gossip;
+use crate::policy;
+use crate::store::{Revision, Store, SCHEMA_VERSION};
+use crate::utils::{parse_backend, token_eq};
+use crate::tls;
 use crate::wg::Interface;
 
 pub struct Server {
     config: ServerConfig,
     iface: Interface,
+    store: Arc<Store>,
+    // set when this server follows a leader, as a read-only failover
+    leader: Option<RpcClient>,
+    // pem certificate and key of the rpc service
+    tls_cert: String,
+    tls_key: String,
 }
 
-#[derive(Debug, Default)]
-struct RpcServer {}
+struct RpcServer {
+    iface_config: InterfaceConfig,
+    key_grace: u64,
+    max_key_age: Option<u64>,
+    auto_approve_routes: Vec<IpNet>,
+    // rpc sockets of all servers, handed to the members
+    servers: Vec<String>,
+    // changes of the members go there when this server is a follower
+    leader: Option<RpcClient>,
+    store: Arc<Store>,
+}
 
 #[tonic::async_trait]
 impl proto::rpc_server::Rpc for RpcServer {
     async fn ping(&self, req: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
+        let remote_addr = req.remote_addr().unwrap_or("0.0.0.0:0".parse().unwrap());
         log::info!("Got ping msg from {}", req.into_inner().msg);
         let resp = PingResponse {
-            msg: format!("Hi {}, I'm server", req.remote_addr().unwrap_or("0.0.0.0:0".parse().unwrap())),
+            msg: format!("Hi {}, I'm server", remote_addr),
         };
         Ok(Response::new(resp))
     }
 
     async fn redeem_invite(&self, req: Request<RedeemInviteRequest>) -> Result<Response<RedeemInviteReply>, Status> {
-        todo!()
+        // a follower takes no changes, the leader makes them and replicates them back
+        if let Some(mut leader) = self.leader.clone() {
+            return leader.redeem_invite(Request::new(req.into_inner())).await;
+        }
+        let req = req.into_inner();
+        let public_key = wireguard_control::Key::from_base64(&req.key)
+            .map_err(|_| Status::invalid_argument("invalid key"))?
+            .generate_public()
+            .to_base64();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let (id, invite) = {
+            let state = self.store.read().await;
+            state.invites.iter()
+                .find(|(_, i)| i.public_key == public_key)
+                .map(|(id, i)| (id.clone(), i.clone()))
+                .ok_or_else(|| Status::unauthenticated("unknown invite"))?
+        };
+        if invite.expires_at < now {
+            return Err(Status::permission_denied(format!("invite {id} expired")));
+        }
+        let message = format!("redeem invite {id} as member {}", invite.name);
+        let peers = self.store.commit(&invite.created_by, &message, |state| -> Result<_, Status> {
+            if state.invites.remove(&id).is_none() {
+                return Err(Status::unauthenticated("unknown invite"));
+            }
+            if state.members.contains_key(&invite.name) {
+                return Err(Status::already_exists(format!("member {} already exists", invite.name)));
+            }
+            state.members.insert(invite.name.clone(), Member {
+                public_key: invite.public_key.clone(),
+                key_created_at: now,
+                retired_key: None,
+                addrs: invite.addrs.clone(),
+                routes: vec![],
+                allowed_ips: vec![],
+                advertised_routes: vec![],
+                groups: vec![],
+                tags: vec![],
+                listen_port: None,
+                internal_endpoint: None,
+                external_endpoint: None,
+                persistent_keepalive: None,
+                disabled: false,
+                exit: None,
+                services: vec![],
+                signing_key: None,
+            });
+            log::info!("Invite {id} redeemed by member {}", invite.name);
+            Ok(state.peers_of(&invite.name))
+        }).await?;
+        let iface_config = InterfaceConfig {
+            name: self.iface_config.name.clone(),
+            private_key: req.key,
+            addrs: invite.addrs,
+            listen_port: None,
+            mtu: self.iface_config.mtu,
+            internal_endpoint: None,
+            external_endpoint: None,
+            peers,
+            dns: self.iface_config.dns.clone(),
+            search: self.iface_config.search.clone(),
+        };
+        Ok(Response::new(RedeemInviteReply {
+            iface_config: vec![iface_config.to_proto_config().unwrap()],
+        }))
     }
 
     async fn post_endpoint(&self, req: Request<PostEndpointRequest>) -> Result<Response<PostEndpointReply>, Status> {
-        todo!()
+        if let Some(mut leader) = self.leader.clone() {
+            return leader.post_endpoint(Request::new(req.into_inner())).await;
+        }
+        let req = req.into_inner();
+        let internal_endpoint = req.internal_endpoint.as_ref()
+            .map(|e| SocketAddr::from_str(e).map_err(|e| Status::invalid_argument(e.to_string())))
+            .transpose()?;
+        // the external endpoint may be a hostname, resolved by the members
+        let external_endpoint = req.external_endpoint.as_ref()
+            .map(|e| Endpoint::from_str(e).map_err(|e| Status::invalid_argument(e.to_string())))
+            .transpose()?;
+        self.store.update(|state| -> Result<(), Status> {
+            let name = state.find_by_private_key(&req.key)
+                .ok_or_else(|| Status::unauthenticated("unknown member key"))?
+                .clone();
+            let member = state.members.get_mut(&name).unwrap();
+            if member.disabled {
+                return Err(Status::permission_denied(format!("member {name} is disabled")));
+            }
+            member.internal_endpoint = internal_endpoint.or(member.internal_endpoint);
+            member.external_endpoint = external_endpoint.or(member.external_endpoint.take());
+            log::debug!("Member {name} posted endpoint {:?} / {:?}", member.internal_endpoint, member.external_endpoint);
+            Ok(())
+        }).await?;
+        Ok(Response::new(PostEndpointReply { ok: true }))
     }
 
     async fn get_peers(&self, req: Request<GetPeersRequest>) -> Result<Response<GetPeersReply>, Status> {
-        todo!()
+        let req = req.into_inner();
+        let state = self.store.read().await;
+        let name = match state.find_by_private_key(&req.key) {
+            Some(name) => name,
+            // removed while the member was offline, let it tear down its interface
+            None if state.was_removed(&req.key) => return Ok(Response::new(peers_reply(&state, &req.key, None))),
+            None => return Err(Status::unauthenticated("unknown member key")),
+        };
+        if state.members[name].disabled {
+            return Err(Status::permission_denied(format!("member {name} is disabled")));
+        }
+        let mut reply = peers_reply(&state, &req.key, self.max_key_age);
+        reply.servers = self.servers.clone();
+        Ok(Response::new(reply))
+    }
+
+    type WatchPeersStream = ReceiverStream<Result<GetPeersReply, Status>>;
+
+    async fn watch_peers(&self, req: Request<GetPeersRequest>) -> Result<Response<Self::WatchPeersStream>, Status> {
+        let key = req.into_inner().key;
+        let name = {
+            let state = self.store.read().await;
+            match state.find_by_private_key(&key) {
+                Some(name) => name.clone(),
+                // the first reply tells it was removed and ends the stream
+                None if state.was_removed(&key) => "removed member".to_string(),
+                None => return Err(Status::unauthenticated("unknown member key")),
+            }
+        };
+        log::debug!("Member {name} watching peers");
+        let store = self.store.clone();
+        let max_key_age = self.max_key_age;
+        let servers = self.servers.clone();
+        let mut changes = store.subscribe();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            loop {
+                let mut reply = peers_reply(&*store.read().await, &key, max_key_age);
+                reply.servers = servers.clone();
+                let removed = reply.removed;
+                if tx.send(Ok(reply)).await.is_err() || removed {
+                    break;
+                }
+                if changes.changed().await.is_err() {
+                    break;
+                }
+            }
+            log::debug!("Member {name} stopped watching peers");
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn rotate_key(&self, req: Request<RotateKeyRequest>) -> Result<Response<RotateKeyReply>, Status> {
+        if let Some(mut leader) = self.leader.clone() {
+            return leader.rotate_key(Request::new(req.into_inner())).await;
+        }
+        let req = req.into_inner();
+        let old_key = wireguard_control::Key::from_base64(&req.key)
+            .map_err(|_| Status::unauthenticated("invalid key"))?
+            .generate_public()
+            .to_base64();
+        let new_key = wireguard_control::Key::from_base64(&req.new_public_key)
+            .map_err(|_| Status::invalid_argument("invalid new public key"))?
+            .to_base64();
+        if !req.new_signing_key.is_empty() && !gossip::is_signing_key(&req.new_signing_key) {
+            return Err(Status::invalid_argument("invalid new signing key"));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let grace_until = now + self.key_grace;
+        let grace_until = self.store.update(|state| -> Result<_, Status> {
+            let name = state.find_by_public_key(&old_key)
+                .ok_or_else(|| Status::unauthenticated("unknown member key"))?
+                .clone();
+            if let Some(other) = state.find_by_public_key(&new_key) {
+                if *other != name {
+                    return Err(Status::already_exists(format!("key already used by member {other}")));
+                }
+            }
+            let member = state.members.get_mut(&name).unwrap();
+            if member.disabled {
+                return Err(Status::permission_denied(format!("member {name} is disabled")));
+            }
+            if member.public_key == new_key {
+                // retried after a lost reply, possibly with the key now retired
+                return Ok(member.retired_key.as_ref().map_or(now, |k| k.expires_at));
+            }
+            // the retired key only authenticates retries, a member that lost the key it rotated
+            // to gets a new one from an admin
+            if member.public_key != old_key {
+                return Err(Status::unauthenticated("retired member key"));
+            }
+            if !req.new_signing_key.is_empty() {
+                member.signing_key = Some(req.new_signing_key.clone());
+            }
+            member.retired_key = Some(RetiredKey { public_key: old_key.clone(), expires_at: grace_until });
+            member.public_key = new_key.clone();
+            member.key_created_at = now;
+            log::info!("Member {name} rotated its key");
+            Ok(member.retired_key.as_ref().map_or(now, |k| k.expires_at))
+        }).await?;
+        Ok(Response::new(RotateKeyReply { grace_until }))
+    }
+
+    async fn advertise_routes(&self, req: Request<AdvertiseRoutesRequest>) -> Result<Response<AdvertiseRoutesReply>, Status> {
+        if let Some(mut leader) = self.leader.clone() {
+            return leader.advertise_routes(Request::new(req.into_inner())).await;
+        }
+        let req = req.into_inner();
+        let routes = req.routes.iter()
+            .map(|r| IpNet::from_str(r).map(|r| r.trunc()).map_err(|e| Status::invalid_argument(e.to_string())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let name = self.store.read().await.find_by_private_key(&req.key)
+            .ok_or_else(|| Status::unauthenticated("unknown member key"))?
+            .clone();
+        let message = format!("member {name} advertises routes {:?}", routes);
+        let reply = self.store.commit(&name, &message, |state| -> Result<_, Status> {
+            let member = state.members.get_mut(&name)
+                .ok_or_else(|| Status::unauthenticated("unknown member key"))?;
+            // withdrawn routes stop waiting, approved ones are left to the admins
+            member.advertised_routes.retain(|r| routes.contains(r));
+            for route in routes.iter() {
+                if member.allowed_ips.contains(route) {
+                    continue;
+                }
+                if self.auto_approve_routes.iter().any(|p| p.contains(route)) {
+                    log::info!("Route {route} of member {name} approved automatically");
+                    member.advertised_routes.retain(|r| r != route);
+                    member.allowed_ips.push(*route);
+                } else if !member.advertised_routes.contains(route) {
+                    log::info!("Route {route} of member {name} waits for approval");
+                    member.advertised_routes.push(*route);
+                }
+            }
+            Ok(AdvertiseRoutesReply {
+                approved: routes.iter().filter(|r| member.allowed_ips.contains(r)).map(|r| r.to_string()).collect(),
+                pending: member.advertised_routes.iter().map(|r| r.to_string()).collect(),
+            })
+        }).await?;
+        Ok(Response::new(reply))
+    }
+
+    async fn use_exit(&self, req: Request<UseExitRequest>) -> Result<Response<UseExitReply>, Status> {
+        if let Some(mut leader) = self.leader.clone() {
+            return leader.use_exit(Request::new(req.into_inner())).await;
+        }
+        let req = req.into_inner();
+        self.store.update(|state| -> Result<(), Status> {
+            let name = state.find_by_private_key(&req.key)
+                .ok_or_else(|| Status::unauthenticated("unknown member key"))?
+                .clone();
+            if let Some(exit) = &req.exit {
+                let node = state.members.get(exit)
+                    .ok_or_else(|| Status::not_found(format!("no member {exit}")))?;
+                if *exit == name || !node.is_exit() || node.disabled {
+                    return Err(Status::failed_precondition(format!("member {exit} is not an exit node")));
+                }
+                if !policy::may_peer(state, &name, exit) {
+                    return Err(Status::permission_denied(format!("member {name} may not reach {exit}")));
+                }
+            }
+            match &req.exit {
+                Some(exit) => log::info!("Member {name} uses exit node {exit}"),
+                None => log::info!("Member {name} stops using an exit node"),
+            }
+            state.members.get_mut(&name).unwrap().exit = req.exit.clone();
+            Ok(())
+        }).await?;
+        Ok(Response::new(UseExitReply {}))
+    }
+
+    async fn publish_services(&self, req: Request<PublishServicesRequest>) -> Result<Response<PublishServicesReply>, Status> {
+        if let Some(mut leader) = self.leader.clone() {
+            return leader.publish_services(Request::new(req.into_inner())).await;
+        }
+        let req = req.into_inner();
+        let services = req.services.iter()
+            .map(|s| Service::from_proto_service(s).map_err(Status::invalid_argument))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.store.update(|state| -> Result<(), Status> {
+            let name = state.find_by_private_key(&req.key)
+                .ok_or_else(|| Status::unauthenticated("unknown member key"))?
+                .clone();
+            let member = state.members.get_mut(&name).unwrap();
+            if member.services != services {
+                log::info!("Member {name} publishes services {:?}", services.iter().map(|s| &s.name).collect::<Vec<_>>());
+                member.services = services;
+            }
+            Ok(())
+        }).await?;
+        Ok(Response::new(PublishServicesReply {}))
+    }
+
+    async fn list_services(&self, req: Request<ListServicesRequest>) -> Result<Response<ListServicesReply>, Status> {
+        let req = req.into_inner();
+        let state = self.store.read().await;
+        let name = state.find_by_private_key(&req.key)
+            .ok_or_else(|| Status::unauthenticated("unknown member key"))?;
+        if state.members[name].disabled {
+            return Err(Status::permission_denied(format!("member {name} is disabled")));
+        }
+        Ok(Response::new(ListServicesReply { services: state.services_for(name) }))
+    }
+
+    async fn register_signing_key(&self, req: Request<RegisterSigningKeyRequest>) -> Result<Response<RegisterSigningKeyReply>, Status> {
+        if let Some(mut leader) = self.leader.clone() {
+            return leader.register_signing_key(Request::new(req.into_inner())).await;
+        }
+        let req = req.into_inner();
+        if !gossip::is_signing_key(&req.signing_key) {
+            return Err(Status::invalid_argument("invalid signing key"));
+        }
+        let public_key = wireguard_control::Key::from_base64(&req.key)
+            .map_err(|_| Status::unauthenticated("unknown member key"))?
+            .generate_public()
+            .to_base64();
+        self.store.update(|state| -> Result<(), Status> {
+            let name = state.find_by_public_key(&public_key)
+                .ok_or_else(|| Status::unauthenticated("unknown member key"))?
+                .clone();
+            let member = state.members.get_mut(&name).unwrap();
+            // the signing key speaks for the wireguard key, a retired one can't replace it
+            if member.public_key != public_key {
+                return Err(Status::unauthenticated("retired member key"));
+            }
+            if member.signing_key.as_ref() != Some(&req.signing_key) {
+                log::info!("Member {name} registered its signing key");
+                member.signing_key = Some(req.signing_key.clone());
+            }
+            Ok(())
+        }).await?;
+        Ok(Response::new(RegisterSigningKeyReply {}))
+    }
+}
+
+/// Peers of the member holding `key`, or a notice it was removed.
+fn peers_reply(state: &NetworkState, key: &str, max_key_age: Option<u64>) -> GetPeersReply {
+    match state.find_by_private_key(key) {
+        // disabled members keep their interface but lose all peers
+        Some(name) if state.members[name].disabled => GetPeersReply { removed: false, ..Default::default() },
+        Some(name) => GetPeersReply {
+            peers: state.peers_of(name).iter()
+                .map(|(k, v)| (k.clone(), v.to_proto_peer().unwrap()))
+                .collect(),
+            signing_keys: state.peers_of(name).keys()
+                .filter_map(|p| Some((p.clone(), state.members.get(p)?.signing_key.clone()?)))
+                .collect(),
+            removed: false,
+            rotate_key: state.members[name].key_too_old(max_key_age),
+            firewall: policy::firewall_for(state, name).map(|rules| proto::Firewall {
+                rules: rules.iter().map(|r| r.to_proto_rule()).collect(),
+            }),
+            services: state.services_for(name),
+            // the rpc server adds its list
+            servers: vec![],
+            member: name.clone(),
+        },
+        None => GetPeersReply { removed: true, ..Default::default() },
     }
 }
 
 impl Server {
+    /// Load the server iface and the state persisted under `data`.
+    pub async fn new(config: ServerConfig, data: &Path) -> Result<Self, io::Error> {
+        let mut iface_config = InterfaceConfig::from_wg_quick_file(Path::new(&config.iface_config_path))?;
+        // handed to the members with their interface config
+        if !config.dns.is_empty() || !config.search.is_empty() {
+            iface_config.dns = config.dns.clone();
+            iface_config.search = config.search.clone();
+        }
+        let iface = Interface::new(&iface_config, parse_backend(&config.backend));
+        let mut store = Store::open(&config.store, data)?;
+        store.set_psk_rotation(config.psk_rotation);
+        if let Some(leader) = &config.leader {
+            if config.replication_token.is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "a follower needs the replication_token of its leader"));
+            }
+            log::info!("Following leader {leader}, only reads fail over to this server");
+            store.set_leader(Some(leader.to_string()));
+        }
+        let (tls_cert, tls_key) = tls::load_or_generate(Path::new(&config.tls_cert), Path::new(&config.tls_key))?;
+        // the leader presents the certificate the servers share
+        let leader = config.leader.as_ref().map(|l| connect_lazy(l, &tls_cert)).transpose()?;
+        log::info!("Loaded {} members from {}", store.read().await.members.len(), data.display());
+        Ok(Server {
+            config,
+            iface,
+            store: Arc::new(store),
+            leader,
+            tls_cert,
+            tls_key,
+        })
+    }
+
     pub async fn run(&mut self) {
+        let admin = AdminServer {
+            iface_config: self.iface.config.clone(),
+            listen: self.config.listen,
+            servers: self.config.servers.clone(),
+            tls_cert: self.tls_cert.clone(),
+            store: self.store.clone(),
+        };
+        let rpc = RpcServer {
+            iface_config: self.iface.config.clone(),
+            key_grace: self.config.key_grace,
+            // rotations are changes only the leader takes, members wait for it
+            max_key_age: if self.leader.is_some() { None } else { self.config.max_key_age },
+            auto_approve_routes: self.config.auto_approve_routes.clone(),
+            servers: admin.servers().iter().map(|s| s.to_string()).collect(),
+            leader: self.leader.clone(),
+            store: self.store.clone(),
+        };
+        let replication = self.config.replication_token.clone().map(|token| ReplicationServer {
+            token,
+            store: self.store.clone(),
+        });
+        let guard = AdminGuard::new(self.config.admins.clone());
+        let store = self.store.clone();
+        match (&self.config.leader, &self.config.replication_token) {
+            (Some(leader), Some(token)) => {
+                tokio::spawn(follow(store, leader.clone(), token.clone(), self.tls_cert.clone()));
+            }
+            // rotate preshared keys when due, members get them through their watch
+            _ => {
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(Duration::from_secs(60));
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = store.refresh().await {
+                            log::error!("Failed to refresh the state: {e}");
+                        }
+                    }
+                });
+            }
+        }
         transport::Server::builder()
-            .add_service(RpcServer {})
+            .tls_config(tls::server_config(&self.tls_cert, &self.tls_key)).unwrap()
+            .add_service(proto::rpc_server::RpcServer::new(rpc))
+            .add_service(proto::admin_server::AdminServer::with_interceptor(admin, guard))
+            .add_optional_service(replication.map(proto::replication_server::ReplicationServer::new))
             .serve(self.config.listen).await.unwrap();
     }
 }
 
+struct ReplicationServer {
+    token: String,
+    store: Arc<Store>,
+}
+
+/// State of `store` for a follower whose history ends at revision `since`, and the revision
+/// it is at then.
+async fn replicate_reply(store: &Store, since: u64) -> Result<(ReplicateReply, u64), io::Error> {
+    let state = store.read().await;
+    let revisions = if state.revision > since {
+        store.history()?.into_iter().filter(|r| r.revision > since).collect()
+    } else {
+        vec![]
+    };
+    let reply = ReplicateReply {
+        schema_version: SCHEMA_VERSION,
+        state: serde_json::to_string(&*state)?,
+        revisions: revisions.iter().map(serde_json::to_string).collect::<Result<_, _>>()?,
+    };
+    Ok((reply, state.revision.max(since)))
+}
+
+#[tonic::async_trait]
+impl proto::replication_server::Replication for ReplicationServer {
+    type ReplicateStream = ReceiverStream<Result<ReplicateReply, Status>>;
+
+    async fn replicate(&self, req: Request<ReplicateRequest>) -> Result<Response<Self::ReplicateStream>, Status> {
+        let follower = req.remote_addr();
+        let req = req.into_inner();
+        if !token_eq(&req.token, &self.token) {
+            return Err(Status::permission_denied("invalid replication token"));
+        }
+        log::info!("Follower {:?} replicating from revision {}", follower, req.revision);
+        let store = self.store.clone();
+        let mut changes = store.subscribe();
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            let mut since = req.revision;
+            loop {
+                let reply = match replicate_reply(&store, since).await {
+                    Ok((reply, revision)) => {
+                        since = revision;
+                        Ok(reply)
+                    }
+                    Err(e) => Err(Status::internal(e.to_string())),
+                };
+                let failed = reply.is_err();
+                if tx.send(reply).await.is_err() || failed {
+                    break;
+                }
+                if changes.changed().await.is_err() {
+                    break;
+                }
+            }
+            log::info!("Follower {:?} stopped replicating", follower);
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Copy the state of the server at `leader` into `store`, reconnecting whenever it is lost.
+async fn follow(store: Arc<Store>, leader: Endpoint, token: String, tls_ca: String) {
+    loop {
+        match follow_once(&store, &leader, &token, &tls_ca).await {
+            Ok(_) => log::warn!("Leader {leader} ended the replication"),
+            Err(e) => log::warn!("Replication from leader {leader} failed: {e}"),
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+async fn follow_once(store: &Store, leader: &Endpoint, token: &str, tls_ca: &str) -> Result<(), io::Error> {
+    let mut client = proto::replication_client::ReplicationClient::new(connect_channel(leader, tls_ca)?);
+    let req = ReplicateRequest {
+        token: token.to_string(),
+        revision: store.history()?.last().map_or(0, |r| r.revision),
+    };
+    let mut stream = client.replicate(req).await.map_err(status_to_io)?.into_inner();
+    while let Some(reply) = stream.message().await.map_err(status_to_io)? {
+        let revisions = reply.revisions.iter()
+            .map(|r| serde_json::from_str::<Revision>(r))
+            .collect::<Result<Vec<_>, _>>()?;
+        store.replicate(reply.schema_version, serde_json::from_str(&reply.state)?, revisions).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use wireguard_control::Key;
+    use crate::api::proto::rpc_server::Rpc;
+
+    fn member(key: &Key) -> Member {
+        Member {
+            public_key: key.generate_public().to_base64(),
+            key_created_at: 0,
+            retired_key: None,
+            addrs: vec!["10.1.0.2/16".parse().unwrap()],
+            routes: vec![],
+            allowed_ips: vec![],
+            advertised_routes: vec![],
+            groups: vec![],
+            tags: vec![],
+            listen_port: None,
+            internal_endpoint: None,
+            external_endpoint: None,
+            persistent_keepalive: None,
+            disabled: false,
+            exit: None,
+            services: vec![],
+            signing_key: None,
+        }
+    }
+
+    async fn rotate(rpc: &RpcServer, key: &Key, new_key: &Key) -> Result<(), Status> {
+        rpc.rotate_key(Request::new(RotateKeyRequest {
+            key: key.to_base64(),
+            new_public_key: new_key.generate_public().to_base64(),
+            new_signing_key: String::new(),
+        })).await.map(|_| ())
+    }
 
+    #[tokio::test]
+    async fn test_rotate_key() {
+        let data = std::env::temp_dir().join(format!("wgnet-rotate-test-{}", std::process::id()));
+        let store = Store::open("yaml", &data).unwrap();
+        let first = Key::generate_private();
+        store.update(|state| -> Result<(), io::Error> {
+            state.members.insert("laptop".to_string(), member(&first));
+            Ok(())
+        }).await.unwrap();
+        let rpc = RpcServer {
+            iface_config: InterfaceConfig {
+                name: "wg0".to_string(),
+                private_key: Key::generate_private().to_base64(),
+                addrs: vec!["10.1.0.1/16".parse().unwrap()],
+                listen_port: Some(51820),
+                mtu: None,
+                internal_endpoint: None,
+                external_endpoint: None,
+                peers: HashMap::new(),
+                dns: vec![],
+                search: vec![],
+            },
+            key_grace: 300,
+            max_key_age: None,
+            auto_approve_routes: vec![],
+            servers: vec![],
+            leader: None,
+            store: Arc::new(store),
+        };
+
+        let second = Key::generate_private();
+        rotate(&rpc, &first, &second).await.unwrap();
+        // a retry after a lost reply comes with the retired key
+        rotate(&rpc, &first, &second).await.unwrap();
+        // but the retired key can't take the member over
+        let stolen = Key::generate_private();
+        let status = rotate(&rpc, &first, &stolen).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        assert_eq!(rpc.store.read().await.members["laptop"].public_key, second.generate_public().to_base64());
+
+        // nor replace the signing key the peers trust for it
+        let signing_key = gossip::public_signing_key(&gossip::generate_signing_key()).unwrap();
+        let register = |key: &Key| rpc.register_signing_key(Request::new(RegisterSigningKeyRequest {
+            key: key.to_base64(),
+            signing_key: signing_key.clone(),
+        }));
+        assert_eq!(register(&first).await.unwrap_err().code(), tonic::Code::Unauthenticated);
+        register(&second).await.unwrap();
+        assert_eq!(rpc.store.read().await.members["laptop"].signing_key, Some(signing_key.clone()));
+
+        let third = Key::generate_private();
+        rotate(&rpc, &second, &third).await.unwrap();
+        let state = rpc.store.read().await;
+        assert_eq!(state.members["laptop"].public_key, third.generate_public().to_base64());
+        assert_eq!(state.members["laptop"].retired_key.as_ref().unwrap().public_key, second.generate_public().to_base64());
+        std::fs::remove_dir_all(&data).unwrap();
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use ipnet::IpNet;
+use wireguard_control::Key;
+
+use crate::api::proto;
+use crate::config::wg::{Endpoint, NextPresharedKey, PeerConfig};
+use crate::policy;
+
+/// Everything the server knows about the network, keyed by member name.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NetworkState {
+    // bumped on every configuration change
+    #[serde(default)]
+    pub revision: u64,
+    pub members: BTreeMap<String, Member>,
+    // name: address kept out of allocation
+    #[serde(default)]
+    pub reservations: BTreeMap<String, IpAddr>,
+    #[serde(default)]
+    pub acls: Vec<AclRule>,
+    // id: invite
+    #[serde(default)]
+    pub invites: BTreeMap<String, Invite>,
+    // "a,b" with a < b: preshared key of the pair, kept in sync with the members by the store
+    #[serde(default)]
+    pub psks: BTreeMap<String, PairKey>,
+    // public keys of removed members, told so when they come back online
+    #[serde(default)]
+    pub removed_keys: BTreeSet<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Member {
+    pub public_key: String,
+    // unix seconds the public key was set
+    #[serde(default)]
+    pub key_created_at: u64,
+    // key replaced by a rotation, still accepted by the server until it expires
+    #[serde(default)]
+    pub retired_key: Option<RetiredKey>,
+    pub addrs: Vec<IpNet>,
+    // prefixes routed through the member's interface on its own host
+    #[serde(default)]
+    pub routes: Vec<IpNet>,
+    // prefixes behind the member, routed to it by the other members
+    #[serde(default)]
+    pub allowed_ips: Vec<IpNet>,
+    // prefixes the member offers to route, moved to allowed_ips once approved
+    #[serde(default)]
+    pub advertised_routes: Vec<IpNet>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    // labels of the machine, as opposed to groups of its users
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub listen_port: Option<u16>,
+    pub internal_endpoint: Option<SocketAddr>,
+    pub external_endpoint: Option<Endpoint>,
+    pub persistent_keepalive: Option<u16>,
+    // disabled members are kept but left out of everyone's peers
+    #[serde(default)]
+    pub disabled: bool,
+    // exit node the member sends its internet traffic through
+    #[serde(default)]
+    pub exit: Option<String>,
+    // services published by the member
+    #[serde(default)]
+    pub services: Vec<Service>,
+    // ed25519 key the member signs its gossip with, base64. wireguard keys cannot sign, so
+    // the peers trust this one as the server hands it out, the last one they got while the
+    // server is unreachable. Replaced along with the member key on rotations.
+    #[serde(default)]
+    pub signing_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Service {
+    pub name: String,
+    // "tcp" or "udp"
+    pub proto: String,
+    pub port: u16,
+}
+
+impl Service {
+    /// Parse `name:port`, or `name:port/udp` for udp services.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (name, port) = s.split_once(':').ok_or_else(|| format!("invalid service {}, expected name:port", s))?;
+        let (port, proto) = port.split_once('/').unwrap_or((port, "tcp"));
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(format!("invalid service name in {}", s));
+        }
+        if !matches!(proto, "tcp" | "udp") {
+            return Err(format!("invalid protocol in {}", s));
+        }
+        Ok(Service {
+            name: name.to_lowercase(),
+            proto: proto.to_string(),
+            port: port.parse().map_err(|_| format!("invalid port in {}", s))?,
+        })
+    }
+
+    pub fn from_proto_service(service: &proto::Service) -> Result<Self, String> {
+        Self::parse(&format!("{}:{}/{}", service.name, service.port, service.proto))
+    }
+
+    pub fn to_proto_service(&self) -> proto::Service {
+        proto::Service {
+            name: self.name.clone(),
+            proto: self.proto.clone(),
+            port: self.port as u32,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RetiredKey {
+    pub public_key: String,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PairKey {
+    pub key: String,
+    pub created_at: u64,
+    // announced to both members once the key is due, it takes over at its switch time
+    #[serde(default)]
+    pub next: Option<NextPresharedKey>,
+}
+
+// time the members get between the announcement of a preshared key and the switch to it,
+// enough for the ones polling instead of watching to catch up
+pub const PSK_SWITCH_DELAY: u64 = 600;
+
+pub fn pair_name(a: &str, b: &str) -> String {
+    if a < b {
+        format!("{},{}", a, b)
+    } else {
+        format!("{},{}", b, a)
+    }
+}
+
+/// Pending member, redeemed once by the holder of the matching private key.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Invite {
+    pub name: String,
+    pub public_key: String,
+    pub addrs: Vec<IpNet>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub created_by: String,
+}
+
+/// `from` may reach `to`. Selectors are `*`, `member:<name>`, `group:<name>` or `tag:<name>`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AclRule {
+    pub from: String,
+    pub to: String,
+    // "tcp/22", "udp/8000-8100", "icmp"... enforced by the members' firewalls, any traffic when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<String>,
+}
+
+impl Member {
+    /// How other members see this one.
+    pub fn to_peer_config(&self) -> PeerConfig {
+        PeerConfig {
+            public_key: self.public_key.clone(),
+            endpoint: self.external_endpoint.clone().or(self.internal_endpoint.map(Endpoint::from)),
+            // default routes only go to the members using this one as their exit
+            allowed_ips: self.addrs.iter()
+                .map(|a| IpNet::new(a.addr(), a.max_prefix_len()).unwrap())
+                .chain(self.allowed_ips.iter().filter(|ip| ip.prefix_len() != 0).cloned())
+                .collect(),
+            preshared_key: None,
+            persistent_keepalive: self.persistent_keepalive,
+            next_preshared_key: None,
+        }
+    }
+
+    /// Exit nodes have an approved default route.
+    pub fn is_exit(&self) -> bool {
+        self.allowed_ips.iter().any(|ip| ip.prefix_len() == 0)
+    }
+
+    /// Whether the key is older than `max_age` seconds.
+    pub fn key_too_old(&self, max_age: Option<u64>) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        max_age.is_some_and(|age| self.key_created_at + age <= now)
+    }
+
+    pub fn to_proto_member(&self, name: &str) -> proto::Member {
+        proto::Member {
+            name: name.to_string(),
+            public_key: self.public_key.clone(),
+            addrs: self.addrs.iter().map(|a| a.to_string()).collect(),
+            allowed_ips: self.allowed_ips.iter().map(|a| a.to_string()).collect(),
+            groups: self.groups.clone(),
+            tags: self.tags.clone(),
+            persistent_keepalive: self.persistent_keepalive.map(|k| k as u32),
+            internal_endpoint: self.internal_endpoint.map(|e| e.to_string()),
+            external_endpoint: self.external_endpoint.as_ref().map(|e| e.to_string()),
+            disabled: self.disabled,
+            exit_node: self.is_exit(),
+            exit: self.exit.clone(),
+        }
+    }
+}
+
+impl Invite {
+    pub fn to_proto_invite(&self, id: &str) -> proto::Invite {
+        proto::Invite {
+            id: id.to_string(),
+            name: self.name.clone(),
+            addrs: self.addrs.iter().map(|a| a.to_string()).collect(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            created_by: self.created_by.clone(),
+        }
+    }
+}
+
+impl NetworkState {
+    /// Members authenticate with their private key, look them up by the derived public key.
+    pub fn find_by_private_key(&self, private_key: &str) -> Option<&String> {
+        let public_key = Key::from_base64(private_key).ok()?.generate_public().to_base64();
+        self.find_by_public_key(&public_key)
+    }
+
+    /// Whether `private_key` belonged to a member removed from the network.
+    pub fn was_removed(&self, private_key: &str) -> bool {
+        Key::from_base64(private_key)
+            .is_ok_and(|k| self.removed_keys.contains(&k.generate_public().to_base64()))
+    }
+
+    /// Record the keys of the members gone since `before`, forgetting the ones back again.
+    pub fn sync_removed(&mut self, before: &NetworkState) {
+        for (name, member) in before.members.iter() {
+            if !self.members.contains_key(name) {
+                self.removed_keys.insert(member.public_key.clone());
+                self.removed_keys.extend(member.retired_key.iter().map(|k| k.public_key.clone()));
+            }
+        }
+        let current: HashSet<&String> = self.members.values()
+            .flat_map(|m| std::iter::once(&m.public_key).chain(m.retired_key.iter().map(|k| &k.public_key)))
+            .collect();
+        self.removed_keys.retain(|k| !current.contains(k));
+    }
+
+    /// Move member `name` to `new_name`, along with the acl selectors and exits naming it.
+    pub fn rename_member(&mut self, name: &str, new_name: &str) -> Option<()> {
+        let member = self.members.remove(name)?;
+        self.members.insert(new_name.to_string(), member);
+        let (from, to) = (format!("member:{name}"), format!("member:{new_name}"));
+        for acl in self.acls.iter_mut() {
+            for selector in [&mut acl.from, &mut acl.to] {
+                if *selector == from {
+                    *selector = to.clone();
+                }
+            }
+        }
+        for member in self.members.values_mut() {
+            if member.exit.as_deref() == Some(name) {
+                member.exit = Some(new_name.to_string());
+            }
+        }
+        Some(())
+    }
+
+    /// Remove member `name`, the members using it as exit go straight to the internet again.
+    pub fn remove_member(&mut self, name: &str) -> Option<Member> {
+        let removed = self.members.remove(name)?;
+        for member in self.members.values_mut() {
+            if member.exit.as_deref() == Some(name) {
+                member.exit = None;
+            }
+        }
+        Some(removed)
+    }
+
+    /// Retired keys are matched too until their grace period is over. They only authenticate
+    /// with the server, peers are only ever given the current key.
+    pub fn find_by_public_key(&self, public_key: &str) -> Option<&String> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.members.iter()
+            .find(|(_, m)| m.public_key == public_key
+                || m.retired_key.as_ref().is_some_and(|k| k.public_key == public_key && k.expires_at > now))
+            .map(|(name, _)| name)
+    }
+
+    /// Peers of member `name`: the other enabled members the acls let it peer with,
+    /// with the preshared key of the pair.
+    pub fn peers_of(&self, name: &str) -> HashMap<String, PeerConfig> {
+        self.members.iter()
+            .filter(|(n, m)| n.as_str() != name && !m.disabled && policy::may_peer(self, name, n))
+            .map(|(n, m)| {
+                let mut peer = m.to_peer_config();
+                if self.members.get(name).and_then(|me| me.exit.as_deref()) == Some(n.as_str()) {
+                    peer.allowed_ips.extend(m.allowed_ips.iter().filter(|ip| ip.prefix_len() == 0));
+                }
+                if let Some(psk) = self.psks.get(&pair_name(name, n)) {
+                    peer.preshared_key = Some(psk.key.clone());
+                    peer.next_preshared_key = psk.next.clone();
+                }
+                (n.clone(), peer)
+            })
+            .collect()
+    }
+
+    /// Services of the other members `name` may reach, with their addresses.
+    pub fn services_for(&self, name: &str) -> Vec<proto::ServiceRecord> {
+        self.members.iter()
+            .filter(|(n, m)| n.as_str() != name && !m.disabled)
+            .flat_map(|(n, m)| m.services.iter()
+                .filter(|s| policy::may_reach(self, name, n, &s.proto, s.port))
+                .map(|s| proto::ServiceRecord {
+                    member: n.clone(),
+                    service: Some(s.to_proto_service()),
+                    addrs: m.addrs.iter().map(|a| a.addr().to_string()).collect(),
+                }))
+            .collect()
+    }
+
+    /// Give every pair of members its own preshared key and drop the ones of members gone.
+    /// A key older than `max_age` seconds gets a successor both members switch to together
+    /// `PSK_SWITCH_DELAY` later, the current key stays in use until then.
+    pub fn sync_psks(&mut self, max_age: Option<u64>, now: u64) {
+        let names: Vec<&String> = self.members.keys().collect();
+        let mut pairs = BTreeMap::new();
+        for (i, a) in names.iter().enumerate() {
+            for b in names[i + 1..].iter() {
+                let pair = pair_name(a, b);
+                let key = match self.psks.remove(&pair) {
+                    Some(mut k) => {
+                        // the members switched on their own, the server only catches up
+                        if let Some(next) = k.next.take_if(|n| n.switch_at <= now) {
+                            k = PairKey { key: next.key, created_at: next.switch_at, next: None };
+                        }
+                        if k.next.is_none() && max_age.is_some_and(|age| k.created_at + age <= now) {
+                            k.next = Some(NextPresharedKey {
+                                key: Key::generate_preshared().to_base64(),
+                                switch_at: now + PSK_SWITCH_DELAY,
+                            });
+                        }
+                        k
+                    }
+                    None => PairKey {
+                        key: Key::generate_preshared().to_base64(),
+                        created_at: now,
+                        next: None,
+                    },
+                };
+                pairs.insert(pair, key);
+            }
+        }
+        self.psks = pairs;
+    }
+
+    /// Copy of the state kept in the history, without the preshared keys. Those are secrets,
+    /// and `restore_config` keeps the current ones anyway.
+    pub fn snapshot(&self) -> NetworkState {
+        let mut snapshot = self.clone();
+        snapshot.psks.clear();
+        snapshot
+    }
+
+    /// Take the configuration of `snapshot`, keeping runtime data such as endpoints and keys
+    /// of the members still there.
+    pub fn restore_config(&mut self, mut snapshot: NetworkState) {
+        for (name, member) in snapshot.members.iter_mut() {
+            if let Some(current) = self.members.get(name) {
+                member.internal_endpoint = current.internal_endpoint;
+                member.external_endpoint = current.external_endpoint.clone();
+                // keys are rotated by the members themselves, an old one would lock them out
+                member.public_key = current.public_key.clone();
+                member.key_created_at = current.key_created_at;
+                member.retired_key = current.retired_key.clone();
+                member.exit = current.exit.clone();
+                member.services = current.services.clone();
+                member.signing_key = current.signing_key.clone();
+            }
+        }
+        snapshot.revision = self.revision;
+        snapshot.psks = std::mem::take(&mut self.psks);
+        snapshot.removed_keys = std::mem::take(&mut self.removed_keys);
+        *self = snapshot;
+    }
+
+    /// Check the state is consistent: keys and addresses used once.
+    pub fn validate(&self) -> Result<(), io::Error> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        let mut keys: HashMap<&String, &String> = HashMap::new();
+        let mut addrs: HashMap<IpAddr, &String> = HashMap::new();
+        for (name, member) in self.members.iter() {
+            if Key::from_base64(&member.public_key).is_err() {
+                return invalid(format!("member {}: invalid public key", name));
+            }
+            if let Some(other) = keys.insert(&member.public_key, name) {
+                return invalid(format!("members {} and {} share a public key", other, name));
+            }
+            for addr in member.addrs.iter() {
+                if let Some(other) = addrs.insert(addr.addr(), name) {
+                    return invalid(format!("members {} and {} share address {}", other, name, addr.addr()));
+                }
+            }
+        }
+        for (name, addr) in self.reservations.iter() {
+            if let Some(other) = addrs.insert(*addr, name) {
+                return invalid(format!("reservation {} overlaps {} on {}", name, other, addr));
+            }
+        }
+        for (id, invite) in self.invites.iter() {
+            for addr in invite.addrs.iter() {
+                if let Some(other) = addrs.insert(addr.addr(), id) {
+                    return invalid(format!("invite {} overlaps {} on {}", id, other, addr.addr()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Addresses held by members, reservations and pending invites.
+    pub fn used_addrs(&self) -> HashSet<IpAddr> {
+        self.members.values().flat_map(|m| m.addrs.iter().map(|a| a.addr()))
+            .chain(self.reservations.values().cloned())
+            .chain(self.invites.values().flat_map(|i| i.addrs.iter().map(|a| a.addr())))
+            .collect()
+    }
+
+    /// Pick the first free host address of each network, `taken` being used outside the state
+    /// such as by the server itself. Addresses are free again once their member is gone.
+    pub fn allocate(&self, networks: &[IpNet], taken: &[IpAddr]) -> Option<Vec<IpNet>> {
+        let mut used = self.used_addrs();
+        used.extend(taken.iter().cloned());
+        networks.iter()
+            .map(|net| {
+                let net = net.trunc();
+                net.hosts()
+                    .find(|a| !used.contains(a))
+                    .map(|a| IpNet::new(a, net.prefix_len()).unwrap())
+            })
+            .collect()
+    }
+
+    /// Describes what already holds one of `addrs`, checked against everything `allocate`
+    /// avoids: members, reservations, pending invites and the `taken` addresses.
+    pub fn addr_in_use(&self, addrs: &[IpNet], taken: &[IpAddr]) -> Option<String> {
+        let wanted = |addr: &IpAddr| addrs.iter().any(|a| a.addr() == *addr);
+        if let Some(name) = self.addr_conflict(addrs) {
+            return Some(format!("member {}", name));
+        }
+        if let Some((name, _)) = self.reservations.iter().find(|(_, a)| wanted(a)) {
+            return Some(format!("reservation {}", name));
+        }
+        if let Some(invite) = self.invites.values().find(|i| i.addrs.iter().any(|a| wanted(&a.addr()))) {
+            return Some(format!("the invite of {}", invite.name));
+        }
+        if taken.iter().any(wanted) {
+            return Some("the server".to_string());
+        }
+        None
+    }
+
+    /// Returns the name of a member already using one of `addrs`.
+    pub fn addr_conflict(&self, addrs: &[IpNet]) -> Option<&String> {
+        self.members.iter()
+            .find(|(_, m)| m.addrs.iter().any(|a| addrs.iter().any(|b| a.addr() == b.addr())))
+            .map(|(name, _)| name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn member(addr: &str) -> Member {
+        Member {
+            public_key: Key::generate_private().generate_public().to_base64(),
+            key_created_at: 0,
+            retired_key: None,
+            addrs: vec![addr.parse().unwrap()],
+            routes: vec![],
+            allowed_ips: vec![],
+            advertised_routes: vec![],
+            groups: vec![],
+            tags: vec![],
+            listen_port: Some(51820),
+            internal_endpoint: None,
+            external_endpoint: Some("1.2.3.4:51820".parse().unwrap()),
+            persistent_keepalive: Some(25),
+            disabled: false,
+            exit: None,
+            services: vec![],
+            signing_key: None,
+        }
+    }
+
+    #[test]
+    fn test_peers_of() {
+        let mut state = NetworkState::default();
+        state.members.insert("a".to_string(), member("10.1.1.1/16"));
+        state.members.insert("b".to_string(), member("10.1.1.2/16"));
+        let peers = state.peers_of("a");
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers["b"].allowed_ips, vec!["10.1.1.2/32".parse::<IpNet>().unwrap()]);
+        // default routes only reach the members using b as exit
+        state.members.get_mut("b").unwrap().allowed_ips.push("0.0.0.0/0".parse().unwrap());
+        assert_eq!(state.peers_of("a")["b"].allowed_ips.len(), 1);
+        state.members.get_mut("a").unwrap().exit = Some("b".to_string());
+        assert_eq!(state.peers_of("a")["b"].allowed_ips[1], "0.0.0.0/0".parse::<IpNet>().unwrap());
+        state.members.get_mut("b").unwrap().disabled = true;
+        assert!(state.peers_of("a").is_empty());
+        assert_eq!(state.addr_conflict(&["10.1.1.2/24".parse().unwrap()]), Some(&"b".to_string()));
+    }
+
+    #[test]
+    fn test_rename_member() {
+        let mut state = NetworkState::default();
+        state.members.insert("a".to_string(), member("10.1.1.1/16"));
+        state.members.insert("b".to_string(), member("10.1.1.2/16"));
+        state.members.get_mut("a").unwrap().exit = Some("b".to_string());
+        state.rename_member("b", "c").unwrap();
+        assert_eq!(state.members["a"].exit, Some("c".to_string()));
+        assert!(state.rename_member("b", "d").is_none());
+        state.remove_member("c").unwrap();
+        assert_eq!(state.members["a"].exit, None);
+    }
+
+    #[test]
+    fn test_sync_removed() {
+        let mut state = NetworkState::default();
+        let key = Key::generate_private();
+        let mut a = member("10.1.1.1/16");
+        a.public_key = key.generate_public().to_base64();
+        state.members.insert("a".to_string(), a);
+        let before = state.clone();
+        state.members.remove("a");
+        state.sync_removed(&before);
+        assert!(state.was_removed(&key.to_base64()));
+        assert!(!state.was_removed(&Key::generate_private().to_base64()));
+        let after = state.clone();
+        state.members = before.members.clone();
+        state.sync_removed(&after);
+        assert!(!state.was_removed(&key.to_base64()));
+    }
+
+    #[test]
+    fn test_sync_psks() {
+        let mut state = NetworkState::default();
+        state.members.insert("a".to_string(), member("10.1.1.1/16"));
+        state.members.insert("b".to_string(), member("10.1.1.2/16"));
+        state.members.insert("c".to_string(), member("10.1.1.3/16"));
+        state.sync_psks(Some(100), 1000);
+        assert_eq!(state.psks.len(), 3);
+        let ab = state.peers_of("a")["b"].preshared_key.clone().unwrap();
+        assert_eq!(state.peers_of("b")["a"].preshared_key, Some(ab.clone()));
+        assert_ne!(state.peers_of("a")["c"].preshared_key, Some(ab.clone()));
+        state.sync_psks(Some(100), 1050);
+        assert_eq!(state.peers_of("a")["b"].preshared_key, Some(ab.clone()));
+        assert!(state.snapshot().psks.is_empty());
+        state.members.remove("c");
+        state.sync_psks(Some(100), 1100);
+        assert_eq!(state.psks.len(), 1);
+        assert_eq!(state.peers_of("a")["b"].preshared_key, Some(ab));
+    }
+
+    #[test]
+    fn test_psk_overlap() {
+        let mut state = NetworkState::default();
+        state.members.insert("a".to_string(), member("10.1.1.1/16"));
+        state.members.insert("b".to_string(), member("10.1.1.2/16"));
+        state.sync_psks(Some(100), 1000);
+        let old = state.peers_of("a")["b"].preshared_key.clone();
+
+        // due: the old key stays while both members get the next one ahead
+        state.sync_psks(Some(100), 1100);
+        let (mut ab, mut ba) = (state.peers_of("a")["b"].clone(), state.peers_of("b")["a"].clone());
+        assert_eq!(ab.preshared_key, old);
+        assert_eq!(ba.preshared_key, old);
+        let next = ab.next_preshared_key.clone().unwrap();
+        assert_eq!(ba.next_preshared_key, Some(next.clone()));
+        assert_eq!(next.switch_at, 1100 + PSK_SWITCH_DELAY);
+        // announced once, later syncs before the switch keep it
+        state.sync_psks(Some(100), 1200);
+        assert_eq!(state.peers_of("a")["b"].next_preshared_key, Some(next.clone()));
+
+        // both ends switch at the same time
+        assert!(!ab.switch_psk(next.switch_at - 1));
+        assert!(ab.switch_psk(next.switch_at));
+        assert!(ba.switch_psk(next.switch_at + 1));
+        assert_eq!(ab.preshared_key, Some(next.key.clone()));
+        assert_eq!(ab.preshared_key, ba.preshared_key);
+
+        // and the server catches up
+        state.sync_psks(Some(100), next.switch_at + 30);
+        let ab = &state.peers_of("a")["b"];
+        assert_eq!(ab.preshared_key, Some(next.key));
+        assert_eq!(ab.next_preshared_key, None);
+        assert_eq!(state.psks[&pair_name("a", "b")].created_at, next.switch_at);
+    }
+
+    #[test]
+    fn test_allocate() {
+        let mut state = NetworkState::default();
+        let network: Vec<IpNet> = vec!["10.1.0.1/16".parse().unwrap()];
+        state.members.insert("a".to_string(), member("10.1.0.2/16"));
+        state.reservations.insert("printer".to_string(), "10.1.0.3".parse().unwrap());
+        let addrs = state.allocate(&network, &["10.1.0.1".parse().unwrap()]).unwrap();
+        assert_eq!(addrs, vec!["10.1.0.4/16".parse::<IpNet>().unwrap()]);
+        state.members.remove("a");
+        let addrs = state.allocate(&network, &["10.1.0.1".parse().unwrap()]).unwrap();
+        assert_eq!(addrs, vec!["10.1.0.2/16".parse::<IpNet>().unwrap()]);
+        let taken: Vec<IpAddr> = vec!["10.1.0.1".parse().unwrap()];
+        assert_eq!(state.addr_in_use(&["10.1.0.3/16".parse().unwrap()], &taken), Some("reservation printer".to_string()));
+        assert_eq!(state.addr_in_use(&["10.1.0.1/16".parse().unwrap()], &taken), Some("the server".to_string()));
+        assert_eq!(state.addr_in_use(&addrs, &taken), None);
+    }
+}
@@ -0,0 +1,485 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use tokio::sync::{watch, RwLock, RwLockReadGuard};
+
+use crate::state::NetworkState;
+use crate::utils::write_atomic;
+
+/// Version of the persisted state layout, bump it and add a migration when `NetworkState` changes.
+pub const SCHEMA_VERSION: u64 = 2;
+
+/// `MIGRATIONS[i]` upgrades a state of schema version `i + 1` to `i + 2`.
+const MIGRATIONS: &[fn(&mut Value)] = &[
+    migrate_key_created_at,
+];
+
+/// Version 2 tracks key ages for rotation, keys of older states count from the upgrade.
+fn migrate_key_created_at(state: &mut Value) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    if let Some(members) = state.get_mut("members").and_then(|m| m.as_object_mut()) {
+        for member in members.values_mut().filter_map(|m| m.as_object_mut()) {
+            member.entry("key_created_at").or_insert(Value::from(now));
+        }
+    }
+}
+
+/// A configuration change, with a snapshot of the state right after it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Revision {
+    pub revision: u64,
+    pub author: String,
+    // unix seconds
+    pub timestamp: u64,
+    pub message: String,
+    pub schema_version: u64,
+    pub state: Value,
+}
+
+/// Where the server state is persisted.
+pub trait Storage: Send + Sync {
+    /// Returns the stored state and its schema version, None if nothing was stored yet.
+    fn load(&self) -> Result<Option<(u64, Value)>, io::Error>;
+    /// Persist `state`, and record `revision` in the history for configuration changes.
+    fn save(&self, state: &Value, revision: Option<&Revision>) -> Result<(), io::Error>;
+    /// All recorded revisions, oldest first.
+    fn history(&self) -> Result<Vec<Revision>, io::Error>;
+}
+
+/// Upgrade a state stored with schema `version` to the current one.
+pub fn migrate(version: u64, mut state: Value) -> Result<NetworkState, io::Error> {
+    if version == 0 || version > SCHEMA_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported state schema version {}, this wgnet supports up to {}", version, SCHEMA_VERSION),
+        ));
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        log::info!("Migrating state from schema version {} to {}", i + 1, i + 2);
+        migration(&mut state);
+    }
+    serde_json::from_value(state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The server state in memory, written through to a `Storage` on every change.
+pub struct Store {
+    state: RwLock<NetworkState>,
+    storage: Box<dyn Storage>,
+    // notified after every saved change
+    changes: watch::Sender<()>,
+    // seconds before pair preshared keys are replaced, never when None
+    psk_rotation: Option<u64>,
+    // the server this store replicates, changes are only taken from there
+    leader: Option<String>,
+}
+
+impl Store {
+    /// Open the `kind` ("yaml", "json" or "sqlite") storage under the `data` directory.
+    pub fn open(kind: &str, data: &Path) -> Result<Self, io::Error> {
+        fs::create_dir_all(data)?;
+        let storage: Box<dyn Storage> = match kind {
+            "yaml" => Box::new(FileStorage::new(data.join("state.yaml"), data.join("history"), FileFormat::Yaml)),
+            "json" => Box::new(FileStorage::new(data.join("state.json"), data.join("history"), FileFormat::Json)),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Box::new(SqliteStorage::open(&data.join("state.db"))?),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown store {}", kind))),
+        };
+        Self::with_storage(storage)
+    }
+
+    pub fn with_storage(storage: Box<dyn Storage>) -> Result<Self, io::Error> {
+        let state = match storage.load()? {
+            Some((version, value)) => {
+                let state = migrate(version, value)?;
+                if version != SCHEMA_VERSION {
+                    storage.save(&serde_json::to_value(&state).unwrap(), None)?;
+                }
+                state
+            }
+            None => NetworkState::default(),
+        };
+        Ok(Store {
+            state: RwLock::new(state),
+            storage,
+            changes: watch::channel(()).0,
+            psk_rotation: None,
+            leader: None,
+        })
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, NetworkState> {
+        self.state.read().await
+    }
+
+    pub fn set_psk_rotation(&mut self, psk_rotation: Option<u64>) {
+        self.psk_rotation = psk_rotation;
+    }
+
+    /// Make this store a copy of the one of the server at `leader`, refusing other changes.
+    pub fn set_leader(&mut self, leader: Option<String>) {
+        self.leader = leader;
+    }
+
+    /// Catch up with time based changes, such as due preshared key rotations.
+    pub async fn refresh(&self) -> Result<(), io::Error> {
+        self.update(|_| Ok(())).await
+    }
+
+    /// Wakes up after each change of the state, to push it to the members.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    /// Change runtime data such as endpoints with `f` and persist it, without a new revision.
+    /// If `f` fails or the state can't be saved, the state is left untouched.
+    pub async fn update<R, E, F>(&self, f: F) -> Result<R, E>
+        where
+            F: FnOnce(&mut NetworkState) -> Result<R, E>,
+            E: From<io::Error>,
+    {
+        self.change(None, f).await.map(|(r, _)| r)
+    }
+
+    /// Change the configuration with `f` and record it as a new revision by `author`.
+    pub async fn commit<R, E, F>(&self, author: &str, message: &str, f: F) -> Result<R, E>
+        where
+            F: FnOnce(&mut NetworkState) -> Result<R, E>,
+            E: From<io::Error>,
+    {
+        self.change(Some((author, message)), f).await.map(|(r, _)| r)
+    }
+
+    /// Like `commit`, also returning the revision the network is at once the change is saved,
+    /// the current one when `f` changed nothing.
+    pub async fn commit_revision<R, E, F>(&self, author: &str, message: &str, f: F) -> Result<(R, u64), E>
+        where
+            F: FnOnce(&mut NetworkState) -> Result<R, E>,
+            E: From<io::Error>,
+    {
+        self.change(Some((author, message)), f).await
+    }
+
+    async fn change<R, E, F>(&self, commit: Option<(&str, &str)>, f: F) -> Result<(R, u64), E>
+        where
+            F: FnOnce(&mut NetworkState) -> Result<R, E>,
+            E: From<io::Error>,
+    {
+        let mut state = self.state.write().await;
+        let mut new_state = state.clone();
+        let r = f(&mut new_state)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        new_state.sync_removed(&state);
+        new_state.sync_psks(self.psk_rotation, now);
+        if new_state == *state {
+            return Ok((r, state.revision));
+        }
+        if let Some(leader) = &self.leader {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("this server replicates {}, make changes there", leader),
+            ).into());
+        }
+        let revision = match commit {
+            Some((author, message)) => {
+                new_state.revision = state.revision + 1;
+                Some(Revision {
+                    revision: new_state.revision,
+                    author: author.to_string(),
+                    timestamp: now,
+                    message: message.to_string(),
+                    schema_version: SCHEMA_VERSION,
+                    state: serde_json::to_value(new_state.snapshot()).unwrap(),
+                })
+            }
+            None => None,
+        };
+        self.storage.save(&serde_json::to_value(&new_state).unwrap(), revision.as_ref())?;
+        if let Some(revision) = revision {
+            log::info!("Revision {}: {} by {}", revision.revision, revision.message, revision.author);
+        }
+        *state = new_state;
+        self.changes.send_replace(());
+        Ok((r, state.revision))
+    }
+
+    pub fn history(&self) -> Result<Vec<Revision>, io::Error> {
+        self.storage.history()
+    }
+
+    /// Take the state of the leader, stored with `schema_version`, and the `revisions` missing
+    /// from the history. Nothing is changed unless all of it can be saved.
+    pub async fn replicate(&self, schema_version: u64, state: Value, revisions: Vec<Revision>) -> Result<(), io::Error> {
+        let new_state = migrate(schema_version, state)?;
+        let mut state = self.state.write().await;
+        // snapshots lack the preshared keys, the current state stays until the new one is saved
+        let current = serde_json::to_value(&*state).unwrap();
+        for revision in revisions.iter() {
+            self.storage.save(&current, Some(revision))?;
+        }
+        if new_state == *state && revisions.is_empty() {
+            return Ok(());
+        }
+        self.storage.save(&serde_json::to_value(&new_state).unwrap(), None)?;
+        log::debug!("Replicated revision {} with {} new history entries", new_state.revision, revisions.len());
+        *state = new_state;
+        self.changes.send_replace(());
+        Ok(())
+    }
+
+    /// Bring the configuration back to `revision`, as a new revision. Runtime data is kept.
+    pub async fn rollback(&self, author: &str, revision: u64) -> Result<u64, io::Error> {
+        let target = self.history()?.into_iter()
+            .find(|r| r.revision == revision)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no revision {}", revision)))?;
+        let snapshot = migrate(target.schema_version, target.state)?;
+        let ((), revision) = self.commit_revision(author, &format!("rollback to revision {}", revision), |state| -> Result<(), io::Error> {
+            state.restore_config(snapshot);
+            Ok(())
+        }).await?;
+        Ok(revision)
+    }
+}
+
+pub enum FileFormat {
+    Json,
+    Yaml,
+}
+
+/// State in a single json or yaml file, replaced atomically on save.
+/// Revisions are kept as one file each in the history directory.
+pub struct FileStorage {
+    path: PathBuf,
+    history_dir: PathBuf,
+    format: FileFormat,
+}
+
+impl FileStorage {
+    pub fn new(path: PathBuf, history_dir: PathBuf, format: FileFormat) -> Self {
+        FileStorage { path, history_dir, format }
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self) -> Result<Option<(u64, Value)>, io::Error> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&self.path)?;
+        let mut doc: Value = match self.format {
+            FileFormat::Json => serde_json::from_str(&content)?,
+            FileFormat::Yaml => serde_yaml::from_str(&content)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        };
+        let version = doc.get("schema_version").and_then(|v| v.as_u64()).unwrap_or(0);
+        if version != SCHEMA_VERSION {
+            // keep the old file around until the upgrade proved fine
+            let mut backup = self.path.as_os_str().to_owned();
+            backup.push(format!(".v{}", version));
+            fs::copy(&self.path, &backup)?;
+        }
+        Ok(Some((version, doc["state"].take())))
+    }
+
+    fn save(&self, state: &Value, revision: Option<&Revision>) -> Result<(), io::Error> {
+        let doc = serde_json::json!({
+            "schema_version": SCHEMA_VERSION,
+            "state": state,
+        });
+        let content = match self.format {
+            FileFormat::Json => serde_json::to_string_pretty(&doc)?,
+            FileFormat::Yaml => serde_yaml::to_string(&doc)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+        };
+        write_atomic(&self.path, content.as_bytes(), 0o600)?;
+        // the state goes first, the history never lists a revision the state is not at yet
+        if let Some(revision) = revision {
+            fs::create_dir_all(&self.history_dir)?;
+            let path = self.history_dir.join(format!("{}.json", revision.revision));
+            write_atomic(&path, serde_json::to_string(revision)?.as_bytes(), 0o600)?;
+        }
+        Ok(())
+    }
+
+    fn history(&self) -> Result<Vec<Revision>, io::Error> {
+        if !self.history_dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut revisions = vec![];
+        for entry in fs::read_dir(&self.history_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "json") {
+                revisions.push(serde_json::from_str::<Revision>(&fs::read_to_string(&path)?)?);
+            }
+        }
+        revisions.sort_by_key(|r| r.revision);
+        Ok(revisions)
+    }
+}
+
+/// State in an embedded SQLite database.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    conn: std::sync::Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+fn sql_error(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let conn = rusqlite::Connection::open(path).map_err(sql_error)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS state (
+                 id INTEGER PRIMARY KEY CHECK (id = 0),
+                 schema_version INTEGER NOT NULL,
+                 data TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS history (
+                 revision INTEGER PRIMARY KEY,
+                 author TEXT NOT NULL,
+                 timestamp INTEGER NOT NULL,
+                 message TEXT NOT NULL,
+                 schema_version INTEGER NOT NULL,
+                 data TEXT NOT NULL
+             );",
+        ).map_err(sql_error)?;
+        Ok(SqliteStorage {
+            conn: std::sync::Mutex::new(conn),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<Option<(u64, Value)>, io::Error> {
+        use rusqlite::OptionalExtension;
+        let conn = self.conn.lock().unwrap();
+        let row: Option<(u64, String)> = conn
+            .query_row("SELECT schema_version, data FROM state WHERE id = 0", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()
+            .map_err(sql_error)?;
+        match row {
+            Some((version, data)) => Ok(Some((version, serde_json::from_str(&data)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, state: &Value, revision: Option<&Revision>) -> Result<(), io::Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        if let Some(r) = revision {
+            tx.execute(
+                "INSERT INTO history (revision, author, timestamp, message, schema_version, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![r.revision, r.author, r.timestamp, r.message, r.schema_version, r.state.to_string()],
+            ).map_err(sql_error)?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO state (id, schema_version, data) VALUES (0, ?1, ?2)",
+            rusqlite::params![SCHEMA_VERSION, state.to_string()],
+        ).map_err(sql_error)?;
+        tx.commit().map_err(sql_error)
+    }
+
+    fn history(&self) -> Result<Vec<Revision>, io::Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT revision, author, timestamp, message, schema_version, data FROM history ORDER BY revision",
+        ).map_err(sql_error)?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get::<_, String>(5)?))
+        }).map_err(sql_error)?;
+        let mut revisions = vec![];
+        for row in rows {
+            let (revision, author, timestamp, message, schema_version, data) = row.map_err(sql_error)?;
+            revisions.push(Revision {
+                revision,
+                author,
+                timestamp,
+                message,
+                schema_version,
+                state: serde_json::from_str(&data)?,
+            });
+        }
+        Ok(revisions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let data = std::env::temp_dir().join(format!("wgnet-store-test-{}", std::process::id()));
+        let store = Store::open("yaml", &data).unwrap();
+        store.commit("test", "add printer", |state| -> Result<(), io::Error> {
+            state.reservations.insert("printer".to_string(), "10.1.0.100".parse().unwrap());
+            Ok(())
+        }).await.unwrap();
+        store.commit("test", "remove printer", |state| -> Result<(), io::Error> {
+            state.reservations.clear();
+            Ok(())
+        }).await.unwrap();
+        let store2 = Store::open("yaml", &data).unwrap();
+        assert_eq!(store2.read().await.revision, 2);
+        assert_eq!(store2.history().unwrap().len(), 2);
+        assert_eq!(store2.rollback("test", 1).await.unwrap(), 3);
+        assert!(store2.read().await.reservations.contains_key("printer"));
+        fs::remove_dir_all(&data).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replicate() {
+        let data = std::env::temp_dir().join(format!("wgnet-replicate-test-{}", std::process::id()));
+        let leader = Store::open("json", &data.join("leader")).unwrap();
+        leader.commit("test", "add printer", |state| -> Result<(), io::Error> {
+            state.reservations.insert("printer".to_string(), "10.1.0.100".parse().unwrap());
+            Ok(())
+        }).await.unwrap();
+        let mut follower = Store::open("json", &data.join("follower")).unwrap();
+        follower.set_leader(Some("10.1.0.1:51821".to_string()));
+        let state = serde_json::to_value(&*leader.read().await).unwrap();
+        follower.replicate(SCHEMA_VERSION, state, leader.history().unwrap()).await.unwrap();
+        assert_eq!(*follower.read().await, *leader.read().await);
+        assert_eq!(follower.history().unwrap(), leader.history().unwrap());
+        assert!(follower.commit("test", "remove printer", |state| -> Result<(), io::Error> {
+            state.reservations.clear();
+            Ok(())
+        }).await.is_err());
+        fs::remove_dir_all(&data).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_rejects_newer_schema() {
+        assert!(migrate(SCHEMA_VERSION + 1, Value::Null).is_err());
+    }
+
+    #[test]
+    fn test_migrate_key_created_at() {
+        let key = wireguard_control::Key::generate_private().generate_public().to_base64();
+        let v1 = serde_json::json!({
+            "members": {
+                "a": {
+                    "public_key": key,
+                    "addrs": ["10.1.1.1/16"],
+                    "listen_port": null,
+                    "internal_endpoint": null,
+                    "external_endpoint": null,
+                    "persistent_keepalive": null,
+                },
+            },
+        });
+        let state = migrate(1, v1).unwrap();
+        assert!(state.members["a"].key_created_at > 0);
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+
+use crate::utils::write_atomic;
+
+/// Name the rpc certificate is issued for. Clients check it whatever address they dial,
+/// so servers stay reachable by any address or hostname.
+pub const TLS_NAME: &str = "wgnet";
+
+/// The pem certificate and key of the rpc service at `cert` and `key`, a self-signed pair
+/// for `TLS_NAME` is generated there on first use.
+pub fn load_or_generate(cert: &Path, key: &Path) -> Result<(String, String), io::Error> {
+    if !cert.exists() && !key.exists() {
+        let generated = rcgen::generate_simple_self_signed(vec![TLS_NAME.to_string()])
+            .map_err(io::Error::other)?;
+        let cert_pem = generated.serialize_pem().map_err(io::Error::other)?;
+        write_atomic(key, generated.serialize_private_key_pem().as_bytes(), 0o600)?;
+        write_atomic(cert, cert_pem.as_bytes(), 0o644)?;
+        log::info!("Generated the rpc certificate {}", cert.display());
+    }
+    Ok((fs::read_to_string(cert)?, fs::read_to_string(key)?))
+}
+
+pub fn server_config(cert: &str, key: &str) -> ServerTlsConfig {
+    ServerTlsConfig::new().identity(Identity::from_pem(cert, key))
+}
+
+/// Trust the servers presenting `ca`, a pem certificate of the network.
+pub fn client_config(ca: &str) -> ClientTlsConfig {
+    ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(ca))
+        .domain_name(TLS_NAME)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+    use tonic::transport::{Channel, Server};
+    use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+    use crate::api::proto;
+    use crate::api::proto::{ReplicateReply, ReplicateRequest};
+
+    // answers over the secured connection, then refuses
+    struct Refuse;
+
+    #[tonic::async_trait]
+    impl proto::replication_server::Replication for Refuse {
+        type ReplicateStream = ReceiverStream<Result<ReplicateReply, tonic::Status>>;
+
+        async fn replicate(&self, _req: tonic::Request<ReplicateRequest>) -> Result<tonic::Response<Self::ReplicateStream>, tonic::Status> {
+            Err(tonic::Status::permission_denied("refused"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls() {
+        let dir = std::env::temp_dir().join(format!("wgnet-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = load_or_generate(&dir.join("rpc.crt"), &dir.join("rpc.key")).unwrap();
+        assert_eq!(load_or_generate(&dir.join("rpc.crt"), &dir.join("rpc.key")).unwrap(), (cert.clone(), key.clone()));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(Server::builder()
+            .tls_config(server_config(&cert, &key)).unwrap()
+            .add_service(proto::replication_server::ReplicationServer::new(Refuse))
+            .serve_with_incoming(TcpListenerStream::new(listener)));
+
+        let channel = Channel::from_shared(format!("https://{}", addr)).unwrap()
+            .tls_config(client_config(&cert)).unwrap()
+            .connect().await.unwrap();
+        let status = proto::replication_client::ReplicationClient::new(channel)
+            .replicate(ReplicateRequest::default()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // a server with another certificate is refused
+        let (other, _) = load_or_generate(&dir.join("other.crt"), &dir.join("other.key")).unwrap();
+        let refused = Channel::from_shared(format!("https://{}", addr)).unwrap()
+            .tls_config(client_config(&other)).unwrap()
+            .connect().await;
+        assert!(refused.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
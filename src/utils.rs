@@ -1,8 +1,10 @@
 use std::process;
+use std::fs;
 use std::io;
-use std::str::FromStr;
-use log;
-use wireguard_control::InterfaceName;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use subtle::ConstantTimeEq;
+use wireguard_control::Backend;
 
 pub fn run_command(cmd: &str, args: &Vec<&str>) -> Result<process::Output, io::Error> {
     log::debug!("run command: {} {}", cmd, args.join(" "));
@@ -13,25 +15,169 @@ pub fn run_command(cmd: &str, args: &Vec<&str>) -> Result<process::Output, io::E
     if output.status.success() {
         Ok(output)
     } else {
-        Err(io::Error::new(
-            io::ErrorKind::Other,
+        Err(io::Error::other(
             format!("failed to run {} {}: {}", cmd, args.join(" "), String::from_utf8_lossy(&output.stderr)),
         ))
     }
 }
 
-pub fn resolve_tun_name(name: &String) -> Result<String, io::Error> {
+/// Like `run_command`, with `input` written to the command's stdin.
+pub fn run_command_with_input(cmd: &str, args: &Vec<&str>, input: &str) -> Result<process::Output, io::Error> {
+    log::debug!("run command: {} {} <<< {}", cmd, args.join(" "), input);
+    let mut child = std::process::Command::new(cmd)
+        .args(args)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(input.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(io::Error::other(
+            format!("failed to run {} {}: {}", cmd, args.join(" "), String::from_utf8_lossy(&output.stderr)),
+        ))
+    }
+}
+
+/// Write `content` to a temporary file next to `path`, then rename it over `path`,
+/// so readers never see a partially written file.
+pub fn write_atomic(path: &Path, content: &[u8], mode: u32) -> Result<(), io::Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // make the rename itself durable
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Compare a presented token with an expected one in time independent of where they differ.
+pub fn token_eq(token: &str, expected: &str) -> bool {
+    token.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// Format unix seconds as "YYYY-MM-DD HH:MM:SS UTC".
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Let the host forward packets between interfaces, for the families given.
+#[cfg(target_os = "linux")]
+pub fn enable_ip_forwarding(v4: bool, v6: bool) -> Result<(), io::Error> {
+    if v4 {
+        fs::write("/proc/sys/net/ipv4/ip_forward", "1")?;
+    }
+    if v6 {
+        fs::write("/proc/sys/net/ipv6/conf/all/forwarding", "1")?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+pub fn enable_ip_forwarding(v4: bool, v6: bool) -> Result<(), io::Error> {
+    if v4 {
+        run_command("sysctl", &vec!["-w", "net.inet.ip.forwarding=1"])?;
+    }
+    if v6 {
+        run_command("sysctl", &vec!["-w", "net.inet6.ip6.forwarding=1"])?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+pub fn enable_ip_forwarding(_v4: bool, _v6: bool) -> Result<(), io::Error> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "enabling ip forwarding is not supported on windows"))
+}
+
+#[cfg(target_os = "macos")]
+pub fn resolve_tun_name(name: &str) -> Result<String, io::Error> {
     let real_interface = wireguard_control::backends::userspace::resolve_tun(
-        &InterfaceName::from_str(&name)?
+        &name.parse::<wireguard_control::InterfaceName>()?
     )?;
     Ok(real_interface)
 }
 
+#[cfg(target_os = "linux")]
+pub fn parse_backend(name: &str) -> Backend {
+    match name.to_lowercase().as_str() {
+        "kernel" => Backend::Kernel,
+        "userspace" => Backend::Userspace,
+        _ => {
+            log::error!("Unknown backend \"{}\", use \"kernel\" by default", name);
+            Backend::Kernel
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn parse_backend(name: &str) -> Backend {
+    match name.to_lowercase().as_str() {
+        "userspace" => Backend::Userspace,
+        "kernel" => {
+            log::error!("\"{}\" backend is not supported in current OS, using \"userspace\" instead", name);
+            Backend::Userspace
+        }
+        _ => {
+            log::error!("Unknown backend \"{}\", use \"userspace\" by default", name);
+            Backend::Userspace
+        }
+    }
+}
+
 #[cfg(target_os = "linux")]
 pub mod linux {
     use std::fmt::Debug;
     use std::io;
+    use std::net::IpAddr;
+    use ipnet::IpNet;
     use wireguard_control::InterfaceName;
+    use netlink_packet_core::{
+        NetlinkDeserializable, NetlinkMessage, NetlinkPayload, NetlinkSerializable,
+        NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_EXCL, NLM_F_REQUEST,
+    };
+    use netlink_packet_route::{
+        address::Nla as AddressNla, link::nlas::Nla as LinkNla, route::Nla as RouteNla, AddressMessage,
+        LinkMessage, RouteMessage, RtnlMessage, IFF_UP, RTN_UNICAST, RTPROT_BOOT, RTPROT_KERNEL,
+        RT_SCOPE_LINK, RT_TABLE_MAIN,
+    };
+    use netlink_sys::{protocols::NETLINK_ROUTE, Socket};
+
+    const MAX_NETLINK_BUFFER_LENGTH: usize = 4096;
+
+    macro_rules! get_nla_value {
+        ($nlas:expr, $e:ident, $v:ident) => {
+            $nlas.iter().find_map(|attr| match attr {
+                $e::$v(value) => Some(value),
+                _ => None,
+            })
+        };
+    }
 
     pub fn if_nametoindex(interface: &InterfaceName) -> Result<u32, io::Error> {
         match unsafe { libc::if_nametoindex(interface.as_ptr()) } {
@@ -43,46 +189,6 @@ pub mod linux {
         }
     }
 
-    pub fn netlink_request_genl<F>(
-        mut message: GenlMessage<F>,
-        flags: Option<u16>,
-    ) -> Result<Vec<NetlinkMessage<GenlMessage<F>>>, io::Error>
-        where
-            F: GenlFamily + Clone + Debug + Eq,
-            GenlMessage<F>: Clone + Debug + Eq + NetlinkSerializable + NetlinkDeserializable,
-    {
-        if message.family_id() == 0 {
-            let genlmsg: GenlMessage<GenlCtrl> = GenlMessage::from_payload(GenlCtrl {
-                cmd: GenlCtrlCmd::GetFamily,
-                nlas: vec![GenlCtrlAttrs::FamilyName(F::family_name().to_string())],
-            });
-            let responses =
-                netlink_request_genl::<GenlCtrl>(genlmsg, Some(NLM_F_REQUEST | NLM_F_ACK))?;
-
-            match responses.get(0) {
-                Some(NetlinkMessage {
-                         payload:
-                         NetlinkPayload::InnerMessage(GenlMessage {
-                                                          payload: GenlCtrl { nlas, .. },
-                                                          ..
-                                                      }),
-                         ..
-                     }) => {
-                    let family_id = get_nla_value!(nlas, GenlCtrlAttrs, FamilyId)
-                        .ok_or_else(|| io::ErrorKind::NotFound)?;
-                    message.set_resolved_family_id(*family_id);
-                }
-                _ => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Unexpected netlink payload",
-                    ));
-                }
-            };
-        }
-        netlink_request(message, flags, NETLINK_GENERIC)
-    }
-
     pub fn netlink_request_rtnl(
         message: RtnlMessage,
         flags: Option<u16>,
@@ -154,6 +260,140 @@ pub mod linux {
             }
         }
     }
-}
 
+    fn parse_ip(bytes: &Vec<u8>) -> Option<IpAddr> {
+        match bytes.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes.as_slice()).unwrap())),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes.as_slice()).unwrap())),
+            _ => None,
+        }
+    }
+
+    /// All addresses currently assigned to the interface.
+    pub fn get_addrs(interface: &InterfaceName) -> Result<Vec<IpNet>, io::Error> {
+        let index = if_nametoindex(interface)?;
+        let responses = netlink_request_rtnl(
+            RtnlMessage::GetAddress(AddressMessage::default()),
+            Some(NLM_F_REQUEST | NLM_F_DUMP),
+        )?;
+        let mut addrs = vec![];
+        for response in responses {
+            if let NetlinkPayload::InnerMessage(RtnlMessage::NewAddress(msg)) = response.payload {
+                if msg.header.index != index {
+                    continue;
+                }
+                // for point-to-point links `Local` is our side, `Address` is the remote side
+                let bytes = get_nla_value!(msg.nlas, AddressNla, Local)
+                    .or(get_nla_value!(msg.nlas, AddressNla, Address));
+                if let Some(addr) = bytes.and_then(parse_ip) {
+                    addrs.push(IpNet::new(addr, msg.header.prefix_len)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+                }
+            }
+        }
+        Ok(addrs)
+    }
 
+    /// Destinations of the main table unicast routes going through the interface,
+    /// except the ones the kernel adds for the interface addresses.
+    pub fn get_routes(interface: &InterfaceName) -> Result<Vec<IpNet>, io::Error> {
+        let index = if_nametoindex(interface)?;
+        let mut routes = vec![];
+        for family in [libc::AF_INET as u8, libc::AF_INET6 as u8] {
+            let mut msg = RouteMessage::default();
+            msg.header.address_family = family;
+            let responses = netlink_request_rtnl(
+                RtnlMessage::GetRoute(msg),
+                Some(NLM_F_REQUEST | NLM_F_DUMP),
+            )?;
+            for response in responses {
+                if let NetlinkPayload::InnerMessage(RtnlMessage::NewRoute(msg)) = response.payload {
+                    if msg.header.table != RT_TABLE_MAIN
+                        || msg.header.kind != RTN_UNICAST
+                        || msg.header.protocol == RTPROT_KERNEL
+                        || get_nla_value!(msg.nlas, RouteNla, Oif) != Some(&index) {
+                        continue;
+                    }
+                    let dest = match get_nla_value!(msg.nlas, RouteNla, Destination).and_then(parse_ip) {
+                        Some(dest) => dest,
+                        None if family == libc::AF_INET as u8 => IpAddr::from([0u8; 4]),
+                        None => IpAddr::from([0u8; 16]),
+                    };
+                    routes.push(IpNet::new(dest, msg.header.destination_prefix_length)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+                }
+            }
+        }
+        Ok(routes)
+    }
+
+    /// Assign `addr` to the interface, doing nothing if it is already there.
+    pub fn add_addr(interface: &InterfaceName, addr: &IpNet) -> Result<(), io::Error> {
+        let mut msg = AddressMessage::default();
+        msg.header.index = if_nametoindex(interface)?;
+        msg.header.prefix_len = addr.prefix_len();
+        match addr {
+            IpNet::V4(addr) => {
+                let bytes = addr.addr().octets().to_vec();
+                msg.header.family = libc::AF_INET as u8;
+                msg.nlas.push(AddressNla::Local(bytes.clone()));
+                msg.nlas.push(AddressNla::Address(bytes));
+            }
+            IpNet::V6(addr) => {
+                msg.header.family = libc::AF_INET6 as u8;
+                msg.nlas.push(AddressNla::Address(addr.addr().octets().to_vec()));
+            }
+        }
+        match netlink_request_rtnl(RtnlMessage::NewAddress(msg), None) {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            r => r.map(|_| ()),
+        }
+    }
+
+    /// Set the mtu of the interface and bring it up.
+    pub fn set_up(interface: &InterfaceName, mtu: u32) -> Result<(), io::Error> {
+        let mut msg = LinkMessage::default();
+        msg.header.index = if_nametoindex(interface)?;
+        msg.header.flags = IFF_UP;
+        msg.header.change_mask = IFF_UP;
+        msg.nlas.push(LinkNla::Mtu(mtu));
+        netlink_request_rtnl(RtnlMessage::SetLink(msg), Some(NLM_F_REQUEST | NLM_F_ACK))?;
+        Ok(())
+    }
+
+    fn route_message(interface: &InterfaceName, dest: &IpNet) -> Result<RouteMessage, io::Error> {
+        let index = if_nametoindex(interface)?;
+        let mut msg = RouteMessage::default();
+        msg.header.table = RT_TABLE_MAIN;
+        msg.header.protocol = RTPROT_BOOT;
+        msg.header.scope = RT_SCOPE_LINK;
+        msg.header.kind = RTN_UNICAST;
+        msg.header.destination_prefix_length = dest.prefix_len();
+        let (family, bytes) = match dest {
+            IpNet::V4(dest) => (libc::AF_INET as u8, dest.network().octets().to_vec()),
+            IpNet::V6(dest) => (libc::AF_INET6 as u8, dest.network().octets().to_vec()),
+        };
+        msg.header.address_family = family;
+        msg.nlas.push(RouteNla::Destination(bytes));
+        msg.nlas.push(RouteNla::Oif(index));
+        Ok(msg)
+    }
+
+    /// Route `dest` through the interface, doing nothing if the route is already there.
+    pub fn add_route(interface: &InterfaceName, dest: &IpNet) -> Result<(), io::Error> {
+        let msg = route_message(interface, dest)?;
+        match netlink_request_rtnl(RtnlMessage::NewRoute(msg), None) {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            r => r.map(|_| ()),
+        }
+    }
+
+    /// Remove the route of `dest` through the interface, doing nothing if it is already gone.
+    pub fn del_route(interface: &InterfaceName, dest: &IpNet) -> Result<(), io::Error> {
+        let msg = route_message(interface, dest)?;
+        match netlink_request_rtnl(RtnlMessage::DelRoute(msg), Some(NLM_F_REQUEST | NLM_F_ACK)) {
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+            r => r.map(|_| ()),
+        }
+    }
+}
@@ -1,112 +1,614 @@
 use std::str::FromStr;
-use log;
 use std::{io, vec};
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
-use clap::builder::Str;
-use serde::ser::SerializeStruct;
+use std::time::{SystemTime, UNIX_EPOCH};
 use ipnet::IpNet;
-use log::Level::Trace;
-use toml;
-use wireguard_control::{Backend, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
+use wireguard_control::{Backend, Device, DeviceUpdate, InterfaceName, Key, PeerConfigBuilder};
 
-use crate::utils::{run_command, resolve_tun_name};
-use crate::config::wg::{InterfaceConfig, PeerConfig};
+use crate::utils::run_command;
+#[cfg(target_os = "macos")]
+use crate::utils::resolve_tun_name;
+use crate::config::wg::{Endpoint, InterfaceConfig, PeerConfig};
+use crate::firewall;
+use crate::firewall::FirewallRule;
 
 
 pub struct Interface {
     pub config: InterfaceConfig,
     pub is_up: bool,
-    pub peers: HashMap<String, Peer>,
     pub backend: Backend,
+    // rules installed in the wgnet nftables table, None when no table is installed
+    pub firewall: Option<Vec<FirewallRule>>,
+    // peer all traffic is routed through, see set_exit
+    pub exit: Option<PeerConfig>,
+    // whether traffic forwarded from the interface is masqueraded, for exit nodes
+    pub masquerade: bool,
+    // block traffic outside the tunnel while an exit is used
+    pub kill_switch: bool,
+    // control plane connections the kill switch lets through
+    pub servers: Vec<SocketAddr>,
+    // resolvers of the host the kill switch lets lookups through to, outside the tunnel
+    pub host_resolvers: Vec<IpAddr>,
+    // public key: address the endpoint of the peer resolved to
+    pub resolved: HashMap<String, SocketAddr>,
+    // the device was built outside wgnet, down only undoes what wgnet added to it
+    pub adopted: bool,
+    // where the device, addresses and routes are changed
+    link: Box<dyn Link>,
 }
 
-pub struct Peer {
-    pub config: PeerConfig,
+/// Endpoint a peer talks from as the device sees it, and its last handshake.
+pub type PeerEndpoint = (Option<SocketAddr>, Option<SystemTime>);
+
+/// Routing table of the exit routes, also the fwmark of the tunnel's own packets.
+const EXIT_TABLE: u32 = 51820;
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
 impl Interface {
     pub fn new(config: &InterfaceConfig, backend: Backend) -> Self {
+        Self::with_link(config, backend, Box::new(SystemLink { backend }))
+    }
+
+    pub fn with_link(config: &InterfaceConfig, backend: Backend, link: Box<dyn Link>) -> Self {
         Interface {
             config: config.clone(),
             is_up: false,
-            peers: HashMap::new(),
             backend,
+            firewall: None,
+            exit: None,
+            masquerade: false,
+            kill_switch: false,
+            servers: vec![],
+            host_resolvers: vec![],
+            resolved: HashMap::new(),
+            adopted: false,
+            link,
+        }
+    }
+
+    /// Read a running device configured outside wgnet, without changing it.
+    /// Peers are named by their public keys.
+    pub fn from_device(name: &str, backend: Backend) -> Result<Self, io::Error> {
+        let iface_name = InterfaceName::from_str(name)?;
+        let device = Device::get(&iface_name, backend)?;
+        let private_key = device.private_key.ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("interface {} has no private key", name),
+        ))?;
+        let mut peers = HashMap::new();
+        for peer in device.peers {
+            let c = peer.config;
+            peers.insert(c.public_key.to_base64(), PeerConfig {
+                public_key: c.public_key.to_base64(),
+                endpoint: c.endpoint.map(Endpoint::from),
+                allowed_ips: c.allowed_ips.iter()
+                    .map(|ip| IpNet::new(ip.address, ip.cidr).unwrap())
+                    .collect(),
+                preshared_key: c.preshared_key.map(|k| k.to_base64()),
+                persistent_keepalive: c.persistent_keepalive_interval,
+                next_preshared_key: None,
+            });
         }
+        let config = InterfaceConfig {
+            name: name.to_string(),
+            private_key: private_key.to_base64(),
+            addrs: Self::read_addrs(&iface_name)?,
+            listen_port: device.listen_port,
+            // kept, or the next `up` would reset it to the default
+            mtu: Self::read_mtu(name)?,
+            internal_endpoint: None,
+            external_endpoint: None,
+            peers,
+            dns: vec![],
+            search: vec![],
+        };
+        Ok(Interface {
+            config,
+            is_up: true,
+            backend,
+            firewall: None,
+            exit: None,
+            masquerade: false,
+            kill_switch: false,
+            servers: vec![],
+            host_resolvers: vec![],
+            resolved: HashMap::new(),
+            adopted: true,
+            link: Box::new(SystemLink { backend }),
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_addrs(name: &InterfaceName) -> Result<Vec<IpNet>, io::Error> {
+        crate::utils::linux::get_addrs(name)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_mtu(name: &str) -> Result<Option<u32>, io::Error> {
+        let mtu = std::fs::read_to_string(format!("/sys/class/net/{}/mtu", name))?;
+        Ok(mtu.trim().parse().ok())
     }
 
-    pub fn up(&mut self) -> Result<(), io::Error> {
+    #[cfg(not(target_os = "linux"))]
+    fn read_mtu(_name: &str) -> Result<Option<u32>, io::Error> {
+        Ok(None)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn read_addrs(_name: &InterfaceName) -> Result<Vec<IpNet>, io::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "reading interface addresses is only supported on linux"))
+    }
+
+    /// Routes through the interface besides the ones of its own addresses.
+    #[cfg(target_os = "linux")]
+    pub fn read_routes(&self) -> Result<Vec<IpNet>, io::Error> {
+        let routes = crate::utils::linux::get_routes(&InterfaceName::from_str(&self.config.name)?)?;
+        Ok(routes.into_iter()
+            .filter(|r| !self.config.addrs.iter().any(|a| a.trunc() == *r))
+            .collect())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn read_routes(&self) -> Result<Vec<IpNet>, io::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "reading interface routes is only supported on linux"))
+    }
+
+    pub async fn up(&mut self) -> Result<(), io::Error> {
+        let now = unix_now();
+        for peer in self.config.peers.values_mut() {
+            peer.switch_psk(now);
+        }
+        self.resolved = Self::resolve_endpoints(&self.config.peers).await;
         let config = &self.config;
         let mut update = DeviceUpdate::new();
         update = update.set_private_key(Key::from_base64(&config.private_key).unwrap());
         if let Some(port) = config.listen_port {
             update = update.set_listen_port(port);
         }
+        if cfg!(target_os = "linux") {
+            update = update.set_fwmark(EXIT_TABLE);
+        }
         for peer in config.peers.values() {
-            let mut p = PeerConfigBuilder::new(
-                &Key::from_base64(&peer.public_key).unwrap());
+            update = update.add_peer(Self::peer_builder(peer, self.resolved.get(&peer.public_key)));
+        }
+        self.link.apply(&config.name, update)?;
+        self.link.set_addrs(&config.name, &config.addrs)?;
+        self.link.set_up(&config.name, config.mtu.unwrap_or(1420))?;
+        self.is_up = true;
+        // the kernel only routes the prefixes of the addresses when they are connected
+        let addr_routes: Vec<IpNet> = self.config.addrs.iter().map(|a| a.trunc()).collect();
+        for route in addr_routes.into_iter().chain(self.peer_routes()) {
+            self.route_add(&route)?;
+        }
+        self.set_exit(self.exit_peer())?;
+        Ok(())
+    }
+
+    /// Addresses of the peer endpoints by public key, the ones failing to resolve are left out
+    /// until the next refresh_endpoints.
+    async fn resolve_endpoints(peers: &HashMap<String, PeerConfig>) -> HashMap<String, SocketAddr> {
+        let mut resolved = HashMap::new();
+        for (name, peer) in peers.iter() {
             if let Some(endpoint) = &peer.endpoint {
-                p = p.set_endpoint(endpoint.clone());
+                match endpoint.resolve().await {
+                    Ok(addr) => {
+                        resolved.insert(peer.public_key.clone(), addr);
+                    }
+                    Err(e) => log::warn!("Peer {name}: failed to resolve endpoint {endpoint}: {e}"),
+                }
+            }
+        }
+        resolved
+    }
+
+    fn peer_builder(peer: &PeerConfig, endpoint: Option<&SocketAddr>) -> PeerConfigBuilder {
+        let mut p = PeerConfigBuilder::new(
+            &Key::from_base64(&peer.public_key).unwrap());
+        if let Some(endpoint) = endpoint {
+            p = p.set_endpoint(*endpoint);
+        }
+        if let Some(preshared_key) = &peer.preshared_key {
+            p = p.set_preshared_key(Key::from_base64(preshared_key).unwrap());
+        }
+        if let Some(persistent_keepalive) = peer.persistent_keepalive {
+            p = p.set_persistent_keepalive_interval(persistent_keepalive);
+        }
+        for ips in &peer.allowed_ips {
+            p = p.add_allowed_ip(ips.addr(), ips.prefix_len());
+        }
+        p
+    }
+
+    /// Allowed ips of the peers outside the networks of the interface addresses,
+    /// they need their own routes. Default routes are left to set_exit.
+    fn peer_routes(&self) -> Vec<IpNet> {
+        let mut routes: Vec<IpNet> = self.config.peers.values()
+            .flat_map(|p| p.allowed_ips.iter())
+            .filter(|ip| ip.prefix_len() != 0)
+            .map(|ip| ip.trunc())
+            .filter(|ip| !self.config.addrs.iter().any(|a| a.trunc().contains(ip)))
+            .collect();
+        routes.sort();
+        routes.dedup();
+        routes
+    }
+
+    /// Bring the device to `peers`: peers no longer there are removed together with their routes,
+    /// the others are added or updated in place.
+    pub async fn update_peers(&mut self, mut peers: HashMap<String, PeerConfig>) -> Result<(), io::Error> {
+        // a preshared key may be due already when the server has not caught up yet
+        let now = unix_now();
+        for peer in peers.values_mut() {
+            peer.switch_psk(now);
+        }
+        let gone: Vec<(String, PeerConfig)> = self.config.peers.iter()
+            .filter(|(_, p)| !peers.values().any(|q| q.public_key == p.public_key))
+            .map(|(n, p)| (n.clone(), p.clone()))
+            .collect();
+        let old_routes = self.peer_routes();
+        let mut update = DeviceUpdate::new();
+        for (_, peer) in gone.iter() {
+            update = update.remove_peer_by_key(&Key::from_base64(&peer.public_key).unwrap());
+        }
+        let resolved = Self::resolve_endpoints(&peers).await;
+        for peer in peers.values() {
+            update = update.add_peer(Self::peer_builder(peer, resolved.get(&peer.public_key)).replace_allowed_ips());
+        }
+        self.link.apply(&self.config.name, update)?;
+        self.config.peers = peers;
+        self.set_resolved(resolved)?;
+
+        let new_routes = self.peer_routes();
+        for route in old_routes.iter().filter(|r| !new_routes.contains(r)) {
+            self.route_del(route)?;
+        }
+        for route in new_routes.iter().filter(|r| !old_routes.contains(r)) {
+            self.route_add(route)?;
+        }
+        self.set_exit(self.exit_peer())?;
+        for (name, _) in gone {
+            log::info!("Interface {}: peer {} removed", self.config.name, name);
+        }
+        Ok(())
+    }
+
+    /// When the next announced preshared key is due, unix seconds.
+    pub fn next_psk_switch(&self) -> Option<u64> {
+        self.config.peers.values()
+            .filter_map(|p| p.next_preshared_key.as_ref())
+            .map(|k| k.switch_at)
+            .min()
+    }
+
+    /// Move the peers whose next preshared key is due at `now` over to it, the other member
+    /// of each pair does the same at that time.
+    pub fn switch_psks(&mut self, now: u64) -> Result<(), io::Error> {
+        let mut update = DeviceUpdate::new();
+        let mut switched = vec![];
+        for (name, peer) in self.config.peers.iter_mut() {
+            if peer.switch_psk(now) {
+                update = update.add_peer(PeerConfigBuilder::new(&Key::from_base64(&peer.public_key).unwrap())
+                    .set_preshared_key(Key::from_base64(peer.preshared_key.as_ref().unwrap()).unwrap()));
+                switched.push(name.clone());
             }
-            if let Some(preshared_key) = &peer.preshared_key {
-                p = p.set_preshared_key(Key::from_base64(&preshared_key).unwrap());
+        }
+        if switched.is_empty() {
+            return Ok(());
+        }
+        self.link.apply(&self.config.name, update)?;
+        for name in switched {
+            log::info!("Interface {}: peer {} switched to its next preshared key", self.config.name, name);
+        }
+        Ok(())
+    }
+
+    /// Look the hostname endpoints up again and point the device at the addresses that changed.
+    pub async fn refresh_endpoints(&mut self) -> Result<(), io::Error> {
+        let mut resolved = self.resolved.clone();
+        let mut update = DeviceUpdate::new();
+        let mut changed = false;
+        for (name, peer) in self.config.peers.iter() {
+            let endpoint = match &peer.endpoint {
+                Some(e) if e.is_hostname() => e,
+                _ => continue,
+            };
+            match endpoint.resolve().await {
+                Ok(addr) if resolved.get(&peer.public_key) != Some(&addr) => {
+                    log::info!("Interface {}: endpoint {endpoint} of peer {name} moved to {addr}", self.config.name);
+                    resolved.insert(peer.public_key.clone(), addr);
+                    update = update.add_peer(
+                        PeerConfigBuilder::new(&Key::from_base64(&peer.public_key).unwrap()).set_endpoint(addr));
+                    changed = true;
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Interface {}: failed to resolve endpoint {endpoint} of peer {name}: {e}", self.config.name),
             }
-            if let Some(persistent_keepalive) = peer.persistent_keepalive {
-                p = p.set_persistent_keepalive_interval(persistent_keepalive);
+        }
+        if changed {
+            self.link.apply(&self.config.name, update)?;
+            self.set_resolved(resolved)?;
+        }
+        Ok(())
+    }
+
+    /// Take the new endpoint addresses, moving the exit rules along when the exit's one changed.
+    fn set_resolved(&mut self, resolved: HashMap<String, SocketAddr>) -> Result<(), io::Error> {
+        let exit = self.exit.clone();
+        let moved = exit.as_ref().is_some_and(|e| self.resolved.get(&e.public_key) != resolved.get(&e.public_key));
+        if moved {
+            self.set_exit(None)?;
+        }
+        self.resolved = resolved;
+        if moved {
+            self.set_exit(exit)?;
+        }
+        Ok(())
+    }
+
+    /// Endpoint each peer talks from as the device sees it, with its last handshake, by public key.
+    pub fn peer_endpoints(&self) -> Result<HashMap<String, PeerEndpoint>, io::Error> {
+        let device = Device::get(&InterfaceName::from_str(&self.config.name)?, self.backend)?;
+        Ok(device.peers.into_iter()
+            .map(|p| (p.config.public_key.to_base64(), (p.config.endpoint, p.stats.last_handshake_time)))
+            .collect())
+    }
+
+    /// Send to peer `public_key` at `addr` from now on, until the server or the peer itself
+    /// tells otherwise.
+    pub fn set_peer_endpoint(&mut self, public_key: &str, addr: SocketAddr) -> Result<(), io::Error> {
+        let key = Key::from_base64(public_key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid public key"))?;
+        let update = DeviceUpdate::new().add_peer(PeerConfigBuilder::new(&key).set_endpoint(addr));
+        self.link.apply(&self.config.name, update)?;
+        let mut resolved = self.resolved.clone();
+        resolved.insert(public_key.to_string(), addr);
+        self.set_resolved(resolved)
+    }
+
+    /// Switch the device to `private_key`, peers stay as they are.
+    pub fn set_private_key(&mut self, private_key: &str) -> Result<(), io::Error> {
+        let key = Key::from_base64(private_key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid private key"))?;
+        self.link.apply(&self.config.name, DeviceUpdate::new().set_private_key(key))?;
+        self.config.private_key = private_key.to_string();
+        Ok(())
+    }
+
+    /// The peer with a default route, the server only sends one to the members using it as exit.
+    fn exit_peer(&self) -> Option<PeerConfig> {
+        self.config.peers.values()
+            .find(|p| p.allowed_ips.iter().any(|ip| ip.prefix_len() == 0))
+            .cloned()
+    }
+
+    /// Route all traffic through `exit`, or restore normal routing with None.
+    pub fn set_exit(&mut self, exit: Option<PeerConfig>) -> Result<(), io::Error> {
+        // a new preshared key or keepalive of the same exit leaves the routing alone
+        let unchanged = match (&exit, &self.exit) {
+            (Some(new), Some(old)) => new.public_key == old.public_key && new.endpoint == old.endpoint,
+            (new, old) => new.is_none() && old.is_none(),
+        };
+        if unchanged {
+            self.exit = exit;
+            return Ok(());
+        }
+        if let Some(old) = self.exit.take() {
+            if self.kill_switch {
+                self.block_leaks(None)?;
             }
-            for ips in &peer.allowed_ips {
-                p = p.add_allowed_ip(ips.addr(), ips.prefix_len());
+            self.route_exit(&old, false)?;
+            log::info!("Interface {}: exit node off", self.config.name);
+        }
+        if let Some(new) = exit {
+            self.route_exit(&new, true)?;
+            if self.kill_switch {
+                self.block_leaks(Some(&new))?;
             }
-            update = update.add_peer(p);
+            log::info!("Interface {}: all traffic goes through {}", self.config.name, new.public_key);
+            self.exit = Some(new);
+        }
+        Ok(())
+    }
+
+    /// Let the kill switch through to `servers`, the control plane of the network.
+    pub fn set_servers(&mut self, servers: Vec<SocketAddr>) -> Result<(), io::Error> {
+        if servers == self.servers {
+            return Ok(());
+        }
+        self.servers = servers;
+        if self.kill_switch && self.exit.is_some() {
+            self.block_leaks(self.exit.as_ref())?;
         }
-        update.apply(&InterfaceName::from_str(&config.name).unwrap(), self.backend.clone())?;
-        self.set_addr()?;
-        self.real_up()?;
-        self.is_up = true;
-        self.add_route()?;
         Ok(())
     }
 
-    pub fn down(&self) -> Result<(), io::Error> {
-        panic!("TODO");
+    /// Delete the device, its addresses and routes go with it, and the firewall table of wgnet.
+    /// An adopted device is left up with its addresses, only the peer routes, exit, masquerading
+    /// and firewall are removed.
+    pub fn down(&mut self) -> Result<(), io::Error> {
+        if self.adopted {
+            self.clear_host_changes()?;
+            for route in self.peer_routes() {
+                self.route_del(&route)?;
+            }
+            self.is_up = false;
+            return Ok(());
+        }
+        self.teardown()
     }
 
+    /// Delete the device even if it was adopted, once the member is gone from the network.
+    pub fn teardown(&mut self) -> Result<(), io::Error> {
+        self.clear_host_changes()?;
+        let name = InterfaceName::from_str(&self.config.name)?;
+        Device::get(&name, self.backend)?.delete()?;
+        self.is_up = false;
+        Ok(())
+    }
+
+    /// Remove what wgnet set up outside the device: the exit routing, masquerading and firewall.
+    fn clear_host_changes(&mut self) -> Result<(), io::Error> {
+        self.set_exit(None)?;
+        self.set_masquerade(false)?;
+        self.set_firewall(None)
+    }
+
+    /// Install the firewall rules pushed by the server, or remove the table with None.
     #[cfg(target_os = "linux")]
-    fn set_addr(&self) -> Result<(), io::Error> {
-        use crate::utils::linux;
-        use netlink_packet_route::address;
+    pub fn set_firewall(&mut self, rules: Option<Vec<FirewallRule>>) -> Result<(), io::Error> {
+        if rules == self.firewall {
+            return Ok(());
+        }
+        match &rules {
+            Some(rules) => firewall::apply(&self.config.name, rules)?,
+            None => firewall::clear(&self.config.name)?,
+        }
+        self.firewall = rules;
+        Ok(())
+    }
 
-        for addr_cidr in &self.config.addrs {
-            let iface_idx = linux::if_nametoindex(&self.name)?;
-            let (family, nlas) = match addr_cidr {
-                IpNet::V4(addr4_cidr) => {
-                    let addr_bytes = addr4_cidr.addr().octets().to_vec();
-                    (
-                        libc::AF_INET as u8,
-                        vec![
-                            address::Nla::Local(addr_bytes.clone()),
-                            address::Nla::Address(addr_bytes),
-                        ],
-                    )
-                }
-                IpNet::V6(addr6_cidr) => {
-                    let addr_bytes = addr6_cidr.addr().octets().to_vec();
-                    (
-                        libc::AF_INET6 as u8,
-                        vec![
-                            address::Nla::Address(addr_bytes),
-                        ],
-                    )
+    #[cfg(not(target_os = "linux"))]
+    pub fn set_firewall(&mut self, rules: Option<Vec<FirewallRule>>) -> Result<(), io::Error> {
+        if rules.is_some() {
+            log::warn!("Interface {}: firewall rules are only enforced on linux", self.config.name);
+        }
+        Ok(())
+    }
+
+    /// Masquerade the traffic of the members leaving through another interface, for exit nodes.
+    #[cfg(target_os = "linux")]
+    pub fn set_masquerade(&mut self, on: bool) -> Result<(), io::Error> {
+        if on == self.masquerade {
+            return Ok(());
+        }
+        if on {
+            firewall::apply_masquerade(&self.config.name)?;
+        } else {
+            firewall::clear_masquerade(&self.config.name)?;
+        }
+        self.masquerade = on;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn set_masquerade(&mut self, on: bool) -> Result<(), io::Error> {
+        if on {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "exit nodes are only supported on linux"));
+        }
+        Ok(())
+    }
+
+    /// Kill switch while `exit` is used: only the tunnel may leave the host. None removes it.
+    #[cfg(target_os = "linux")]
+    fn block_leaks(&self, exit: Option<&PeerConfig>) -> Result<(), io::Error> {
+        match exit {
+            Some(exit) => firewall::apply_kill_switch(
+                &self.config.name, EXIT_TABLE, self.resolved.get(&exit.public_key).copied(), &self.servers, &self.host_resolvers),
+            None => firewall::clear_kill_switch(&self.config.name),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn block_leaks(&self, exit: Option<&PeerConfig>) -> Result<(), io::Error> {
+        if exit.is_some() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "the kill switch is only supported on linux"));
+        }
+        Ok(())
+    }
+
+    /// Policy routing of wg-quick: everything not marked as the tunnel's own packets goes to
+    /// a table with a default route through the interface, while the main table keeps its more
+    /// specific routes. The endpoint of the exit always keeps the route of the main table.
+    #[cfg(target_os = "linux")]
+    fn route_exit(&self, exit: &PeerConfig, on: bool) -> Result<(), io::Error> {
+        let action = if on { "add" } else { "del" };
+        let table = EXIT_TABLE.to_string();
+        let ip = |args: Vec<&str>| -> Result<(), io::Error> {
+            match run_command("ip", &args) {
+                // deleting what is already gone
+                Err(e) if !on => {
+                    log::debug!("ip {}: {e}", args.join(" "));
+                    Ok(())
                 }
-            };
+                r => r.map(|_| ()),
+            }
+        };
+        for (family, default, v4) in [("-4", "0.0.0.0/0", true), ("-6", "::/0", false)] {
+            if !exit.allowed_ips.iter().any(|r| r.prefix_len() == 0 && matches!(r, IpNet::V4(_)) == v4) {
+                continue;
+            }
+            if let Some(endpoint) = self.resolved.get(&exit.public_key).filter(|e| e.is_ipv4() == v4) {
+                ip(vec![family, "rule", action, "to", &endpoint.ip().to_string(), "table", "main", "priority", "5200"])?;
+            }
+            ip(vec![family, "rule", action, "table", "main", "suppress_prefixlength", "0", "priority", "5210"])?;
+            ip(vec![family, "rule", action, "not", "fwmark", &table, "table", &table, "priority", "5220"])?;
+            if on {
+                ip(vec![family, "route", "replace", default, "dev", &self.config.name, "table", &table])?;
+            } else {
+                ip(vec![family, "route", "flush", "table", &table])?;
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn route_exit(&self, exit: &PeerConfig, on: bool) -> Result<(), io::Error> {
+        if on {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "exit nodes are only supported on linux"));
+        }
+        Ok(())
+    }
+
+    fn route_add(&self, dest: &IpNet) -> Result<(), io::Error> {
+        self.link.route_add(&self.config.name, dest)
+    }
+
+    fn route_del(&self, dest: &IpNet) -> Result<(), io::Error> {
+        self.link.route_del(&self.config.name, dest)
+    }
+}
+
+/// How the device, its addresses and its routes are changed in the system.
+pub trait Link: Send + Sync {
+    /// Apply a wireguard configuration change to the device `name`, creating it if needed.
+    fn apply(&self, name: &str, update: DeviceUpdate) -> Result<(), io::Error>;
+    /// Assign `addrs` to the interface, the ones already there are left alone.
+    fn set_addrs(&self, name: &str, addrs: &[IpNet]) -> Result<(), io::Error>;
+    /// Set the mtu of the interface and bring it up.
+    fn set_up(&self, name: &str, mtu: u32) -> Result<(), io::Error>;
+    /// Route `dest` through the interface, doing nothing if the route is already there.
+    fn route_add(&self, name: &str, dest: &IpNet) -> Result<(), io::Error>;
+    /// Remove the route of `dest` through the interface, doing nothing if it is already gone.
+    fn route_del(&self, name: &str, dest: &IpNet) -> Result<(), io::Error>;
+}
+
+/// The wireguard `backend` and the network configuration of the host.
+pub struct SystemLink {
+    pub backend: Backend,
+}
+
+impl Link for SystemLink {
+    fn apply(&self, name: &str, update: DeviceUpdate) -> Result<(), io::Error> {
+        update.apply(&InterfaceName::from_str(name)?, self.backend)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn set_addrs(&self, name: &str, addrs: &[IpNet]) -> Result<(), io::Error> {
+        let iface_name = InterfaceName::from_str(name)?;
+        for addr in addrs {
+            crate::utils::linux::add_addr(&iface_name, addr)?;
         }
         Ok(())
     }
 
     #[cfg(target_os = "macos")]
-    fn set_addr(&self) -> Result<(), io::Error> {
-        let tun_name = resolve_tun_name(&self.config.name)?;
-        for addr_cidr in &self.config.addrs {
+    fn set_addrs(&self, name: &str, addrs: &[IpNet]) -> Result<(), io::Error> {
+        let tun_name = resolve_tun_name(name)?;
+        for addr_cidr in addrs {
             match addr_cidr {
                 IpNet::V4(addr4_cidr) => {
                     run_command(
@@ -137,73 +639,213 @@ impl Interface {
     }
 
     #[cfg(target_os = "windows")]
-    fn set_addr(&self) -> Result<(), io::Error> {
-        panic!("not implemented");
+    fn set_addrs(&self, _name: &str, _addrs: &[IpNet]) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "assigning addresses is not supported on windows"))
     }
 
     #[cfg(target_os = "linux")]
-    fn add_route(&self) -> Result<(), io::Error> {
-        panic!("not implemented");
+    fn set_up(&self, name: &str, mtu: u32) -> Result<(), io::Error> {
+        crate::utils::linux::set_up(&InterfaceName::from_str(name)?, mtu)
     }
 
     #[cfg(target_os = "macos")]
-    fn add_route(&self) -> Result<(), io::Error> {
-        let tun_name = resolve_tun_name(&self.config.name)?;
-        for addr_cidr in &self.config.addrs {
-            run_command(
-                "route",
-                &vec![
-                    "-n",
-                    "add",
-                    match addr_cidr {
-                        IpNet::V4(_) => "-inet",
-                        IpNet::V6(_) => "-inet6",
-                    },
-                    addr_cidr.to_string().as_str(),
-                    "-interface",
-                    tun_name.as_str(),
-                ],
-            )?;
-        }
+    fn set_up(&self, name: &str, mtu: u32) -> Result<(), io::Error> {
+        let tun_name = resolve_tun_name(name)?;
+        run_command(
+            "ifconfig",
+            &vec![
+                tun_name.as_str(),
+                "mtu",
+                mtu.to_string().as_str(),
+            ],
+        )?;
         Ok(())
     }
 
     #[cfg(target_os = "windows")]
-    fn add_route(&self) -> Result<(), io::Error> {
-        panic!("not implemented");
+    fn set_up(&self, _name: &str, _mtu: u32) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "bringing interfaces up is not supported on windows"))
     }
 
     #[cfg(target_os = "linux")]
-    fn real_up(&self) -> Result<(), io::Error> {
-        panic!("not implemented");
+    fn route_add(&self, name: &str, dest: &IpNet) -> Result<(), io::Error> {
+        crate::utils::linux::add_route(&InterfaceName::from_str(name)?, dest)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn route_del(&self, name: &str, dest: &IpNet) -> Result<(), io::Error> {
+        crate::utils::linux::del_route(&InterfaceName::from_str(name)?, dest)
     }
 
     #[cfg(target_os = "macos")]
-    fn real_up(&self) -> Result<(), io::Error> {
-        let mtu = self.config.mtu.unwrap_or(1420);
-        let tun_name = resolve_tun_name(&self.config.name)?;
+    fn route_add(&self, name: &str, dest: &IpNet) -> Result<(), io::Error> {
+        let tun_name = resolve_tun_name(name)?;
         run_command(
-            "ifconfig",
+            "route",
             &vec![
+                "-n",
+                "add",
+                match dest {
+                    IpNet::V4(_) => "-inet",
+                    IpNet::V6(_) => "-inet6",
+                },
+                dest.to_string().as_str(),
+                "-interface",
                 tun_name.as_str(),
-                "mtu",
-                mtu.to_string().as_str(),
             ],
         )?;
         Ok(())
     }
 
+    #[cfg(target_os = "macos")]
+    fn route_del(&self, _name: &str, dest: &IpNet) -> Result<(), io::Error> {
+        run_command(
+            "route",
+            &vec![
+                "-n",
+                "delete",
+                match dest {
+                    IpNet::V4(_) => "-inet",
+                    IpNet::V6(_) => "-inet6",
+                },
+                dest.to_string().as_str(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    fn route_add(&self, _name: &str, _dest: &IpNet) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "adding routes is not supported on windows"))
+    }
+
     #[cfg(target_os = "windows")]
-    fn real_up(&self) -> Result<(), io::Error> {
-        panic!("not implemented");
+    fn route_del(&self, _name: &str, _dest: &IpNet) -> Result<(), io::Error> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "removing routes is not supported on windows"))
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::{Arc, Mutex};
     use super::*;
+    use crate::config::wg::NextPresharedKey;
+
+    // records the changes instead of making them
+    #[derive(Clone, Default)]
+    struct FakeLink {
+        changes: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Link for FakeLink {
+        fn apply(&self, name: &str, _update: DeviceUpdate) -> Result<(), io::Error> {
+            self.changes.lock().unwrap().push(format!("apply {name}"));
+            Ok(())
+        }
+
+        fn set_addrs(&self, name: &str, addrs: &[IpNet]) -> Result<(), io::Error> {
+            for addr in addrs {
+                self.changes.lock().unwrap().push(format!("addr {name} {addr}"));
+            }
+            Ok(())
+        }
+
+        fn set_up(&self, name: &str, mtu: u32) -> Result<(), io::Error> {
+            self.changes.lock().unwrap().push(format!("up {name} mtu {mtu}"));
+            Ok(())
+        }
+
+        fn route_add(&self, name: &str, dest: &IpNet) -> Result<(), io::Error> {
+            self.changes.lock().unwrap().push(format!("route add {name} {dest}"));
+            Ok(())
+        }
+
+        fn route_del(&self, name: &str, dest: &IpNet) -> Result<(), io::Error> {
+            self.changes.lock().unwrap().push(format!("route del {name} {dest}"));
+            Ok(())
+        }
+    }
+
+    fn test_config(allowed_ips: Vec<&str>) -> InterfaceConfig {
+        let peer = PeerConfig {
+            public_key: Key::generate_private().generate_public().to_base64(),
+            endpoint: None,
+            allowed_ips: allowed_ips.iter().map(|ip| ip.parse().unwrap()).collect(),
+            preshared_key: None,
+            persistent_keepalive: None,
+            next_preshared_key: None,
+        };
+        InterfaceConfig {
+            name: "wg0".to_string(),
+            private_key: Key::generate_private().to_base64(),
+            addrs: vec!["10.1.0.1/16".parse().unwrap()],
+            listen_port: None,
+            mtu: None,
+            internal_endpoint: None,
+            external_endpoint: None,
+            peers: HashMap::from([("peer1".to_string(), peer)]),
+            dns: vec![],
+            search: vec![],
+        }
+    }
 
     #[test]
-    fn test() {}
-}
+    fn test_peer_routes() {
+        let config = test_config(vec!["10.1.0.2/32", "192.168.1.1/24", "0.0.0.0/0"]);
+        let iface = Interface::new(&config, Backend::Userspace);
+        assert_eq!(iface.peer_routes(), vec!["192.168.1.0/24".parse::<IpNet>().unwrap()]);
+        assert_eq!(iface.exit_peer(), iface.config.peers.get("peer1").cloned());
+    }
 
+    #[test]
+    fn test_switch_psks() {
+        let mut config = test_config(vec!["10.1.0.2/32"]);
+        let next = Key::generate_preshared().to_base64();
+        config.peers.get_mut("peer1").unwrap().next_preshared_key = Some(NextPresharedKey { key: next.clone(), switch_at: 1000 });
+        let link = FakeLink::default();
+        let mut iface = Interface::with_link(&config, Backend::Userspace, Box::new(link.clone()));
+        assert_eq!(iface.next_psk_switch(), Some(1000));
+        iface.switch_psks(999).unwrap();
+        assert!(link.changes.lock().unwrap().is_empty());
+        iface.switch_psks(1000).unwrap();
+        assert_eq!(*link.changes.lock().unwrap(), vec!["apply wg0"]);
+        assert_eq!(iface.config.peers["peer1"].preshared_key, Some(next));
+        assert_eq!(iface.next_psk_switch(), None);
+    }
+
+    #[tokio::test]
+    async fn test_down_adopted() {
+        let config = test_config(vec!["10.1.0.2/32", "192.168.1.0/24"]);
+        let link = FakeLink::default();
+        let mut iface = Interface::with_link(&config, Backend::Userspace, Box::new(link.clone()));
+        iface.adopted = true;
+        iface.up().await.unwrap();
+        let changes = link.changes.lock().unwrap().len();
+        iface.down().unwrap();
+        assert!(!iface.is_up);
+        assert_eq!(link.changes.lock().unwrap()[changes..], ["route del wg0 192.168.1.0/24"]);
+    }
+
+    #[tokio::test]
+    async fn test_up() {
+        let config = test_config(vec!["10.1.0.2/32", "192.168.1.0/24"]);
+        let link = FakeLink::default();
+        let mut iface = Interface::with_link(&config, Backend::Userspace, Box::new(link.clone()));
+        iface.up().await.unwrap();
+        assert!(iface.is_up);
+        assert_eq!(*link.changes.lock().unwrap(), vec![
+            "apply wg0",
+            "addr wg0 10.1.0.1/16",
+            "up wg0 mtu 1420",
+            "route add wg0 10.1.0.0/16",
+            "route add wg0 192.168.1.0/24",
+        ]);
+
+        iface.update_peers(test_config(vec!["10.1.0.3/32", "192.168.2.0/24"]).peers).await.unwrap();
+        assert_eq!(link.changes.lock().unwrap()[5..], [
+            "apply wg0",
+            "route del wg0 192.168.1.0/24",
+            "route add wg0 192.168.2.0/24",
+        ]);
+    }
+}